@@ -1,10 +1,12 @@
 use tokio::net::UdpSocket;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::time::{timeout, Duration, sleep};
+use tokio::time::{timeout, Duration};
 use std::error::Error;
 use steganography::util::file_as_dynamic_image;
 use steganography::decoder::Decoder;
+use steganography::encoder::Encoder;
+use steganography::util::save_image_buffer;
 use image::open;
 use reqwest::Client;
 use tokio_tungstenite::connect_async;
@@ -34,16 +36,417 @@ use tokio::net::TcpStream;
 use tokio::net::TcpListener;
 use png;
 use std::process::Command;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use base64::{engine::general_purpose, Engine};
+use x25519_dalek::{PublicKey, StaticSecret};
+use sha2::{Digest, Sha256};
+use hmac::{Hmac, Mac};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::Path;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand::Rng;
 const SERVER_ADDRS: [&str; 3] = ["10.7.17.88:8080", "10.7.17.50:8081", "10.7.17.155:8082"];
-const CHUNK_SIZE: usize = 1024;
 const TIMEOUT_DURATION: Duration = Duration::from_secs(10);
-const ACK: &[u8] = b"ACK";
-
-#[derive(Deserialize)]
+const INBOX_DIR: &str = "inbox";
+const INBOX_INDEX_PATH: &str = "inbox/index.json";
+static INBOX_INDEX_LOCK: Mutex<()> = Mutex::const_new(()); // Reply listeners update the index concurrently
+const REPLY_WAIT_TIMEOUT: Duration = Duration::from_secs(30 * 60); // Later replies are left on the DOS
+const OUTBOX_DIR: &str = "outbox"; // Per-request work files of the owner while encoding a delivery
+const COVER_IMAGE_PATH: &str = "mask.jpg"; // Fallback cover when the DOS library is empty
+const SHARE_COVERS_PATH: &str = "share_covers.json";
+const WATERMARK_SETTINGS_PATH: &str = "watermark_settings.json";
+const FONT_PATH: &str = "Roboto-Bold.ttf";
+const WATERMARK_OPACITY: f32 = 0.3;
+const FORENSIC_WATERMARKS_PATH: &str = "watermarks.json"; // Which delivery each forensic ID was embedded in
+static FORENSIC_WATERMARKS_LOCK: Mutex<()> = Mutex::const_new(()); // Concurrent deliveries record their IDs here
+const FORENSIC_GRID: u32 = 128; // The image is split into GRID x GRID cells whatever its size
+const FORENSIC_BITS: usize = 32;
+const FORENSIC_STRENGTH: f32 = 3.0; // Luminance change per cell, out of 255
+const FORENSIC_SEED: u64 = 0x4450_494d_574d_4b31; // Fixes the chip pattern so any copy can be checked
+const FORENSIC_JPEG_QUALITY: u8 = 95; // JPEG sources are marked and saved as JPEG again at this quality
+const MAX_COVER_DIMENSION: u32 = 4096; // Covers are never upscaled beyond this width or height
+const STEGO_HEADER_LEN: usize = 5; // Codec version byte followed by the body length
+const FRAME_MAGIC: &[u8; 4] = b"DPIM";
+const FRAME_VERSION: u8 = 1;
+const MAX_FRAME_HEADER_LEN: usize = 64 * 1024;
+const MAX_FRAME_BODY_LEN: u64 = 256 * 1024 * 1024;
+const REQUEST_MAX_AGE_SECS: u64 = 300; // Signed requests older than this are refused as replays
+const MAX_VIEW_SHARES: u32 = 1000; // Single-use key shares registered per delivery, the DOS refuses more
+
+#[derive(Deserialize, Clone)]
 struct Message {
     image_name: String,
     views: i32,
     viewer: String,
+    reply_port: u16,    // Port the requester bound to receive this delivery
+    request_id: String, // Identifies the delivery on both ends
+    timestamp: u64,     // Seconds since the epoch when the viewer signed the request
+    signature: String,  // Base64 HMAC of the request under the key shared by viewer and owner
+}
+
+impl Message {
+    /// The fields covered by the signature, in a fixed order.
+    fn signed_content(&self, owner: &str) -> String {
+        format!(
+            "{}|{}|{}|{}|{}|{}|{}",
+            self.viewer, owner, self.image_name, self.views, self.reply_port, self.request_id, self.timestamp
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct InboxEntry {
+    owner: String, // Needed to look up the public key the image was encrypted against
+    image_name: String,
+    path: String,
+    received: bool,
+}
+
+/// Images this client requested, keyed by request ID. Each delivery gets its own file in the inbox.
+#[derive(Serialize, Deserialize, Default)]
+struct InboxIndex {
+    entries: HashMap<String, InboxEntry>,
+}
+
+impl InboxIndex {
+    fn load_from_file(file_path: &str) -> Self {
+        match std::fs::File::open(file_path) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file)).unwrap_or_default(),
+            Err(_) => InboxIndex::default(),
+        }
+    }
+
+    fn save_to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &self)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CoverChoice {
+    owner: String, // Client ID, or "shared" for the admin library
+    name: String,
+}
+
+/// Covers the owner picked for sharing specific images, keyed by image name.
+#[derive(Serialize, Deserialize, Default)]
+struct ShareCovers {
+    covers: HashMap<String, CoverChoice>,
+}
+
+impl ShareCovers {
+    fn load_from_file(file_path: &str) -> Self {
+        match std::fs::File::open(file_path) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file)).unwrap_or_default(),
+            Err(_) => ShareCovers::default(),
+        }
+    }
+
+    fn save_to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &self)?;
+        Ok(())
+    }
+}
+
+/// Images the owner wants visibly watermarked with the viewer's ID before each delivery.
+#[derive(Serialize, Deserialize, Default)]
+struct WatermarkSettings {
+    images: HashMap<String, bool>,
+}
+
+impl WatermarkSettings {
+    fn load_from_file(file_path: &str) -> Self {
+        match std::fs::File::open(file_path) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file)).unwrap_or_default(),
+            Err(_) => WatermarkSettings::default(),
+        }
+    }
+
+    fn save_to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &self)?;
+        Ok(())
+    }
+
+    fn is_enabled(&self, image_name: &str) -> bool {
+        self.images.get(image_name).copied().unwrap_or(false)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ForensicWatermark {
+    viewer: String,
+    image_name: String,
+    request_id: String,
+    created_at: u64,
+}
+
+/// Forensic watermark IDs embedded by this owner, keyed by the ID in hex.
+#[derive(Serialize, Deserialize, Default)]
+struct ForensicWatermarks {
+    watermarks: HashMap<String, ForensicWatermark>,
+}
+
+impl ForensicWatermarks {
+    fn load_from_file(file_path: &str) -> Self {
+        match std::fs::File::open(file_path) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file)).unwrap_or_default(),
+            Err(_) => ForensicWatermarks::default(),
+        }
+    }
+
+    fn save_to_file(&self, file_path: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(file_path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), &self)?;
+        Ok(())
+    }
+}
+
+/// X25519 and Ed25519 identity of a user on this machine. Only the public halves are ever sent to the DOS.
+#[derive(Serialize, Deserialize)]
+struct ClientIdentity {
+    secret_key: String, // Base64 X25519 static secret
+    #[serde(default)]
+    signing_key: Option<String>, // Base64 Ed25519 seed, signs the access metadata of deliveries
+}
+
+impl ClientIdentity {
+    fn file_path(client_id: &str) -> String {
+        format!("identity_{}.json", client_id)
+    }
+
+    fn load(client_id: &str) -> Result<Option<ClientIdentity>, Box<dyn Error>> {
+        match std::fs::File::open(Self::file_path(client_id)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(std::io::BufReader::new(file))?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn save(&self, client_id: &str) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(Self::file_path(client_id))?;
+        serde_json::to_writer(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Loads the user's secret key, generating and saving a fresh one on first use.
+    fn load_or_create(client_id: &str) -> Result<StaticSecret, Box<dyn Error>> {
+        if let Some(identity) = Self::load(client_id)? {
+            let secret_bytes: [u8; 32] = general_purpose::STANDARD
+                .decode(&identity.secret_key)?
+                .try_into()
+                .map_err(|_| "Stored secret key must be 32 bytes")?;
+            return Ok(StaticSecret::from(secret_bytes));
+        }
+
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let identity = ClientIdentity {
+            secret_key: general_purpose::STANDARD.encode(secret.to_bytes()),
+            signing_key: None,
+        };
+        identity.save(client_id)?;
+        println!("Generated a new keypair for {} and saved it to {}", client_id, Self::file_path(client_id));
+        Ok(secret)
+    }
+
+    /// Loads the user's signing key, adding one to the identity file on first use.
+    fn load_or_create_signing_key(client_id: &str) -> Result<SigningKey, Box<dyn Error>> {
+        Self::load_or_create(client_id)?;
+        let mut identity = Self::load(client_id)?.ok_or("Identity file disappeared")?;
+        if let Some(signing_key) = &identity.signing_key {
+            let seed: [u8; 32] = general_purpose::STANDARD
+                .decode(signing_key)?
+                .try_into()
+                .map_err(|_| "Stored signing key must be 32 bytes")?;
+            return Ok(SigningKey::from_bytes(&seed));
+        }
+
+        let signing_key = SigningKey::from_bytes(&rand::random::<[u8; 32]>());
+        identity.signing_key = Some(general_purpose::STANDARD.encode(signing_key.to_bytes()));
+        identity.save(client_id)?;
+        println!("Generated a new signing key for {}", client_id);
+        Ok(signing_key)
+    }
+
+    fn public_key(client_id: &str) -> Result<String, Box<dyn Error>> {
+        let secret = Self::load_or_create(client_id)?;
+        Ok(general_purpose::STANDARD.encode(PublicKey::from(&secret).as_bytes()))
+    }
+
+    fn verifying_key(client_id: &str) -> Result<String, Box<dyn Error>> {
+        let signing_key = Self::load_or_create_signing_key(client_id)?;
+        Ok(general_purpose::STANDARD.encode(signing_key.verifying_key().as_bytes()))
+    }
+}
+
+/// Access terms the owner signs into each delivery, so they can't be edited after the fact.
+#[derive(Serialize, Deserialize, Clone)]
+struct AccessMetadata {
+    owner: String,
+    viewer: String,
+    image_name: String,
+    request_id: String,
+    views: u32,
+    #[serde(default)]
+    not_before: Option<u64>, // Seconds since the epoch, none if the grant is valid right away
+    expires_at: Option<u64>, // Seconds since the epoch, none if the grant doesn't expire
+    nonce: String,
+}
+
+/// Optional time limits of a grant, as stored by the DOS under `access_windows`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AccessWindow {
+    #[serde(default)]
+    pub not_before: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// Derives the key shared between this user and a peer from X25519 Diffie-Hellman.
+/// Owner and viewer compute the same key, so no key material crosses the network.
+fn derive_shared_key(own_secret: &StaticSecret, peer_public_key: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let peer_bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(peer_public_key)?
+        .try_into()
+        .map_err(|_| "Peer public key must be 32 bytes")?;
+    let shared_secret = own_secret.diffie_hellman(&PublicKey::from(peer_bytes));
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"Distributed_Project image key");
+    hasher.update(shared_secret.as_bytes());
+    Ok(hasher.finalize().into())
+}
+
+/// Wraps the per-delivery share for view number `view_index`, or unwraps it again.
+/// The DOS only holds wrapped shares and hands each one out once, without the pairwise key
+/// none of them is of any use.
+fn wrap_view_share(shared_key: &[u8; 32], request_id: &str, view_index: u64, share: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"Distributed_Project view share");
+    hasher.update(shared_key);
+    hasher.update(request_id.as_bytes());
+    hasher.update(view_index.to_be_bytes());
+    let pad: [u8; 32] = hasher.finalize().into();
+
+    let mut wrapped = [0u8; 32];
+    for (out, (share_byte, pad_byte)) in wrapped.iter_mut().zip(share.iter().zip(pad)) {
+        *out = share_byte ^ pad_byte;
+    }
+    wrapped
+}
+
+/// Key an image is actually encrypted with: the pairwise key mixed with a per-delivery share
+/// that only the DOS hands out, one view at a time.
+fn derive_view_key(shared_key: &[u8; 32], view_share: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"Distributed_Project view key");
+    hasher.update(shared_key);
+    hasher.update(view_share);
+    hasher.finalize().into()
+}
+
+/// Fetches the public key a client published to the DOS.
+async fn fetch_public_key(dos_address: &str, client_id: &str) -> Result<String, Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .get(format!("{}/get_public_key", dos_address))
+        .query(&[("client_id", client_id)])
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    match response_body.get("public_key").and_then(|v| v.as_str()) {
+        Some(public_key) => Ok(public_key.to_string()),
+        None => Err(format!("No public key published for client {}: {}", client_id, response_body).into()),
+    }
+}
+
+/// Fetches the Ed25519 key a client published to the DOS for verifying its signatures.
+async fn fetch_verifying_key(dos_address: &str, client_id: &str) -> Result<VerifyingKey, Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .get(format!("{}/get_public_key", dos_address))
+        .query(&[("client_id", client_id)])
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    let signing_key = response_body
+        .get("signing_key")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("No signing key published for client {}", client_id))?;
+    let key_bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(signing_key)?
+        .try_into()
+        .map_err(|_| "Signing key must be 32 bytes")?;
+    Ok(VerifyingKey::from_bytes(&key_bytes)?)
+}
+
+/// Derives the key shared with `peer_id` using the public key the peer published to the DOS.
+async fn shared_key_with(dos_address: &str, client_id: &str, peer_id: &str) -> Result<[u8; 32], String> {
+    let peer_public_key = fetch_public_key(dos_address, peer_id).await.map_err(|e| e.to_string())?;
+    let own_secret = ClientIdentity::load_or_create(client_id).map_err(|e| e.to_string())?;
+    derive_shared_key(&own_secret, &peer_public_key).map_err(|e| e.to_string())
+}
+
+fn request_mac(shared_key: &[u8; 32]) -> Hmac<Sha256> {
+    // Keep the request key separate from the image key even though both come from the same exchange
+    let mut hasher = Sha256::new();
+    hasher.update(b"Distributed_Project request key");
+    hasher.update(shared_key);
+    let request_key: [u8; 32] = hasher.finalize().into();
+    <Hmac<Sha256> as Mac>::new_from_slice(&request_key).expect("HMAC accepts keys of any length")
+}
+
+/// Signs a P2P request with the key shared between the viewer and the owner.
+/// Only the viewer holding the secret behind their registered public key can produce it.
+fn sign_request(shared_key: &[u8; 32], signed_content: &str) -> String {
+    let mut mac = request_mac(shared_key);
+    mac.update(signed_content.as_bytes());
+    general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+fn verify_request_signature(shared_key: &[u8; 32], signed_content: &str, signature: &str) -> bool {
+    let Ok(signature) = general_purpose::STANDARD.decode(signature) else {
+        return false;
+    };
+    let mut mac = request_mac(shared_key);
+    mac.update(signed_content.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Publishes this user's public key to the DOS, replacing any previously published key.
+async fn publish_public_key(dos_address: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/update_public_key", dos_address))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password,
+            "public_key": ClientIdentity::public_key(client_id)?,
+            "signing_key": ClientIdentity::verifying_key(client_id)?
+        }))
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Failed to publish public key: {}",
+            response.text().await.unwrap_or_else(|_| "No response body".to_string())
+        )
+        .into())
+    }
 }
 
 // #[tokio::main]
@@ -161,38 +564,108 @@ struct Message {
 //     Ok(())
 // }
 
-async fn add_notification(
+/// Records an access request on the DOS, which the owner sees live or in their notifications.
+/// Returns the request ID to use, and whether an identical request was already pending.
+async fn create_access_request(
     api_base_url: &str,
+    client_id: &str,
+    password: &str,
     image_owner: &str,
     image_name: &str,
-    requester: &str,
     access_rights: i32,
-) -> Result<(), Box<dyn Error>> {
+    request_id: &str,
+) -> Result<(String, bool), Box<dyn Error>> {
     let client = Client::new();
-    let response = client
-        .post(format!("{}/add_notification", api_base_url))
+    let response_body: Value = client
+        .post(format!("{}/access_requests", api_base_url))
         .json(&json!({
+            "client_id": client_id,
+            "password": password,
             "image_owner": image_owner,
             "image_name": image_name,
-            "requester": requester,
             "access_rights": access_rights,
+            "request_id": request_id,
         }))
         .send()
+        .await?
+        .json()
         .await?;
 
-    if response.status().is_success() {
-        println!("Notification added successfully.");
-    } else {
-        eprintln!(
-            "Failed to add notification: {:?}",
-            response.text().await?
-        );
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("Access request refused: {}", error).into());
     }
+    let request_id = response_body["request_id"].as_str().unwrap_or(request_id).to_string();
+    let duplicate = response_body["duplicate"].as_bool().unwrap_or(false);
+    Ok((request_id, duplicate))
+}
 
-    Ok(())
+/// The ID of the owner's pending notification for a request that arrived from the viewer's client,
+/// if the request was recorded on the DOS and nobody decided it yet.
+async fn pending_access_request(dos_address: &str, client_id: &str, password: &str, request: &Message) -> Result<Option<u64>, String> {
+    let response_body: Value = reqwest::Client::new()
+        .get(format!("{}/get_notifications", dos_address))
+        .query(&[("client_id", client_id), ("password", password)])
+        .send()
+        .await
+        .map_err(|err| format!("Error communicating with notifications endpoint: {}", err))?
+        .json()
+        .await
+        .map_err(|err| format!("Invalid notifications response: {}", err))?;
+
+    Ok(response_body["notifications"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|n| {
+            n["kind"] == "AccessRequest"
+                && n["status"] == "Pending"
+                && n["request_id"] == request.request_id.as_str()
+                && n["requester"] == request.viewer.as_str()
+        })
+        .and_then(|n| n["id"].as_u64()))
+}
+
+/// Approves or rejects an access request on the DOS. Approving grants the views.
+async fn decide_access_request(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    notification_id: u64,
+    action: &str,
+    views: u32,
+    access_window: AccessWindow,
+) -> Result<(), String> {
+    let response_body: Value = reqwest::Client::new()
+        .post(format!("{}/notifications/{}/{}", dos_address, notification_id, action))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password,
+            "access_rights": views,
+            "access_window": access_window
+        }))
+        .send()
+        .await
+        .map_err(|err| format!("Error communicating with notifications endpoint: {}", err))?
+        .json()
+        .await
+        .map_err(|err| format!("Invalid notifications response: {}", err))?;
+
+    match response_body.get("error") {
+        Some(error) => Err(format!("Failed to {} request: {}", action, error)),
+        None => Ok(()),
+    }
 }
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    // Offline tools that don't need the DOS or the P2P server
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("watermark-detect") {
+        return match args.get(2) {
+            Some(image_path) => run_watermark_detect(image_path),
+            None => Err("Usage: client watermark-detect <image path>".into()),
+        };
+    }
+
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000)); // Listen on all interfaces
 
     // Shared state for dos_address, client_id, and password
@@ -206,23 +679,27 @@ async fn main() -> Result<(), Box<dyn Error>> {
         request_leader(&*socket_guard).await?
     };
 
+    // Incoming P2P requests wait here until the owner approves them
+    let pending_requests: Arc<Mutex<Vec<Message>>> = Arc::new(Mutex::new(Vec::new()));
+    // Kept apart from shared_state so verifying a request never waits on the menu
+    let owner_identity: Arc<Mutex<Option<(String, String)>>> = Arc::new(Mutex::new(None));
+    let seen_requests: Arc<Mutex<HashMap<String, u64>>> = Arc::new(Mutex::new(HashMap::new()));
+
     // Spawn the HTTP server as a separate task
-    let server_state = Arc::clone(&shared_state);
-    let server_socket = Arc::clone(&socket); // Clone the socket Mutex
-    let server_leader_address = leader_address.clone(); // Clone the leader address
+    let server_pending_requests = Arc::clone(&pending_requests);
+    let server_owner_identity = Arc::clone(&owner_identity);
     tokio::spawn(async move {
         let server = Server::bind(&addr).serve(make_service_fn(move |_conn| {
-            let server_state = Arc::clone(&server_state); // Clone shared state for each request
-            let server_socket = Arc::clone(&server_socket); // Clone socket for each request
-            let server_leader_address = server_leader_address.clone(); // Clone leader address into the closure
+            let server_pending_requests = Arc::clone(&server_pending_requests); // Clone the queue for each request
+            let server_owner_identity = Arc::clone(&server_owner_identity);
+            let seen_requests = Arc::clone(&seen_requests);
             async move {
                 Ok::<_, Infallible>(service_fn(move |req| {
-                    let cloned_leader_address = server_leader_address.clone(); // Clone again for async block
                     handle_request(
                         req,
-                        Arc::clone(&server_state),
-                        Arc::clone(&server_socket),
-                        cloned_leader_address, // Pass cloned leader address
+                        Arc::clone(&server_pending_requests),
+                        Arc::clone(&server_owner_identity),
+                        Arc::clone(&seen_requests),
                     )
                 }))
             }
@@ -236,9 +713,6 @@ async fn main() -> Result<(), Box<dyn Error>> {
     });
     
     
-    // middleware_encrypt(&socket, &leader_address).await?;
-    // middleware_decrypt(&socket).await?;
-
     println!("Welcome! Choose an option:");
     println!("1: Register");
     println!("2: Login");
@@ -273,14 +747,38 @@ async fn main() -> Result<(), Box<dyn Error>> {
         _ => println!("Invalid option! Please try again."), // Catch-all pattern
     }
 
+    {
+        let state = shared_state.lock().await;
+        *owner_identity.lock().await = Some((state.0.clone(), state.1.clone()));
+
+        // Pick up anything owners approved or revoked while we were offline
+        if let Err(e) = fetch_deliveries(&state.0, &state.1, &state.2).await {
+            eprintln!("Failed to fetch pending deliveries: {}", e);
+        }
+        if let Err(e) = apply_revocations(&state.0, &state.1, &state.2).await {
+            eprintln!("{}", e);
+        }
+
+        tokio::spawn(subscribe_to_events(state.0.clone(), state.1.clone(), state.2.clone()));
+    }
+
     loop {
         println!("Choose an option:");
         println!("1: Add an image");
         println!("2: Delete an image");
         println!("3: View the gallery");
-        println!("4: View the latest image");
+        println!("4: View a received image");
         println!("5: View Past notifications");
-        println!("6: Exit");
+        println!("6: Add a cover image");
+        println!("7: Choose the cover for an image");
+        println!("8: Review pending requests");
+        println!("9: View the audit log of an image");
+        println!("10: Toggle the visible watermark for an image");
+        println!("11: Manage groups");
+        println!("12: Manage albums");
+        println!("13: Re-share access");
+        println!("14: Change a viewer's access");
+        println!("15: Exit");
 
         option.clear(); // Clear the previous input
         std::io::stdin().read_line(&mut option).expect("Failed to read line");
@@ -310,17 +808,95 @@ async fn main() -> Result<(), Box<dyn Error>> {
             }
             "3" => {
                 let output_path = "composite_image.png";
+                println!("Enter an owner and album to only show that album (owner/album), or leave empty for all images:");
+                let mut album_filter = String::new();
+                std::io::stdin().read_line(&mut album_filter).expect("Failed to read line");
+                let album = album_filter.trim().split_once('/');
                 let state = shared_state.lock().await;
-                match fetch_composite_image(&state.0, output_path).await {
-                    Ok(_) => println!("Composite image fetched and saved to {}", output_path),
-                    Err(e) => eprintln!("Error fetching composite image: {}", e),
+                if let Err(e) = list_gallery(&state.0, &state.1, &state.2, album).await {
+                    eprintln!("Error fetching the gallery: {}", e);
+                }
+
+                println!("Render the gallery as an image too? (y/n)");
+                let mut render = String::new();
+                std::io::stdin().read_line(&mut render).expect("Failed to read line");
+                if render.trim() == "y" {
+                    println!("Which page? (leave empty for the first)");
+                    let mut page = String::new();
+                    std::io::stdin().read_line(&mut page).expect("Failed to read line");
+                    let page = page.trim().parse::<u32>().unwrap_or(1);
+                    match fetch_composite_image(&state.0, output_path, album, page).await {
+                        Ok(_) => println!("Composite image fetched and saved to {}", output_path),
+                        Err(e) => eprintln!("Error fetching composite image: {}", e),
+                    }
                 }
-                view_gallery(&leader_address).await?;
+                view_gallery(&leader_address, &state.1, &state.2).await?;
             }
-            "4" =>process_image_metadata_and_decode("reply_image.png","final_requested_image.png").await?,
+            "4" => { let state = shared_state.lock().await;
+                if let Some(inbox_path) = choose_inbox_image()? {
+                    process_image_metadata_and_decode(&state.0, &state.1, &state.2, &inbox_path,"final_requested_image.png").await?
+                }},
             "5" => { let state = shared_state.lock().await;
                 list_notifications_with_choice_and_execute(&state.0, &state.1, &state.2, &state.0).await?},
-            "6" => break,
+            "6" => {
+                println!("Enter cover image path:");
+                let mut cover_path = String::new();
+                std::io::stdin().read_line(&mut cover_path).expect("Failed to read line");
+                let cover_path = cover_path.trim();
+                println!("Add to the shared library? (y/n, admins only):");
+                let mut shared = String::new();
+                std::io::stdin().read_line(&mut shared).expect("Failed to read line");
+                let state = shared_state.lock().await;
+                match add_cover_to_dos(&state.0, &state.1, &state.2, cover_path, shared.trim() == "y").await {
+                    Ok(msg) => println!("Cover added successfully: {}", msg),
+                    Err(e) => println!("Failed to add cover: {}", e),
+                }
+            }
+            "7" => { let state = shared_state.lock().await;
+                if let Err(e) = choose_cover_for_image(&state.0, &state.1, &state.2).await {
+                    println!("Failed to choose cover: {}", e);
+                }},
+            "8" => { let state = shared_state.lock().await;
+                review_pending_requests(&state.0, &state.1, &state.2, &leader_address, &pending_requests).await?},
+            "9" => {
+                println!("Enter image name:");
+                let mut image_name = String::new();
+                std::io::stdin().read_line(&mut image_name).expect("Failed to read line");
+                let state = shared_state.lock().await;
+                if let Err(e) = view_audit_log(&state.0, &state.1, &state.2, image_name.trim()).await {
+                    println!("{}", e);
+                }
+            }
+            "10" => {
+                if let Err(e) = toggle_watermark_for_image() {
+                    println!("Failed to update the watermark setting: {}", e);
+                }
+            }
+            "11" => {
+                let state = shared_state.lock().await;
+                if let Err(e) = manage_groups(&state.0, &state.1, &state.2).await {
+                    println!("{}", e);
+                }
+            }
+            "12" => {
+                let state = shared_state.lock().await;
+                if let Err(e) = manage_albums(&state.0, &state.1, &state.2).await {
+                    println!("{}", e);
+                }
+            }
+            "13" => {
+                let state = shared_state.lock().await;
+                if let Err(e) = manage_resharing(&state.0, &state.1, &state.2).await {
+                    println!("{}", e);
+                }
+            }
+            "14" => {
+                let state = shared_state.lock().await;
+                if let Err(e) = change_viewer_access(&state.0, &state.1, &state.2).await {
+                    println!("Failed to change access: {}", e);
+                }
+            }
+            "15" => break,
             _ => println!("Invalid choice! Please select again."),
         }
     }
@@ -350,32 +926,85 @@ async fn main() -> Result<(), Box<dyn Error>> {
 //                          .await?;
 
 //     if response.status().is_success() {
-//         println!("Message sent successfully!");
+//         println!("Request sent, waiting for the owner's approval.");
 //     } else {
 //         eprintln!("Failed to send message: {}", response.status());
 //     }
 //     Ok(())
 // }
-pub async fn send_message_to_client(client_ip: &str, image_name: &str, client_to_add: &str, views: i32,client_name:&str,dos_address: &str) -> Result<(), Box<dyn Error>> {
+pub async fn send_message_to_client(client_ip: &str, image_name: &str, client_to_add: &str, password: &str, views: i32,client_name:&str,dos_address: &str) -> Result<(), Box<dyn Error>> {
     let client = Client::new();
     let send_msg_url = format!("http://{}:3000/receive_message", client_ip);
-    
+
+    // The DOS records the request first, the owner only acts on requests it knows about
+    let request_id = format!("{:016x}", rand::random::<u64>());
+    let (request_id, duplicate) =
+        create_access_request(dos_address, client_to_add, password, client_name, image_name, views, &request_id).await?;
+    // The owner may have missed the first attempt, so ask again under the request ID the DOS has
+    if duplicate {
+        println!("You already have the same request pending with {}, sending it again.", client_name);
+    }
+
+    // Every request gets its own ephemeral port and inbox file so deliveries never collide
+    let listener = TcpListener::bind(("0.0.0.0", 0)).await?;
+    let reply_port = listener.local_addr()?.port();
+    std::fs::create_dir_all(INBOX_DIR)?;
+    let reply_output_path = format!("{}/{}.png", INBOX_DIR, request_id);
+
+    // Remember who owns the reply so its key can be derived when decoding
+    {
+        let _guard = INBOX_INDEX_LOCK.lock().await;
+        let mut inbox_index = InboxIndex::load_from_file(INBOX_INDEX_PATH);
+        inbox_index.entries.insert(
+            request_id.clone(),
+            InboxEntry {
+                owner: client_name.to_string(),
+                image_name: image_name.to_string(),
+                path: reply_output_path.clone(),
+                received: false,
+            },
+        );
+        inbox_index.save_to_file(INBOX_INDEX_PATH)?;
+    }
+
+    // Sign the request so the owner can check it really comes from this viewer
+    let shared_key = shared_key_with(dos_address, client_to_add, client_name).await?;
+    let mut request = Message {
+        image_name: image_name.to_string(),
+        views,
+        viewer: client_to_add.to_string(),
+        reply_port,
+        request_id: request_id.clone(),
+        timestamp: unix_timestamp(),
+        signature: String::new(),
+    };
+    request.signature = sign_request(&shared_key, &request.signed_content(client_name));
+
     // Prepare the JSON payload
     let json_payload = json!({
-        "image_name": image_name,
-        "views": views,
-        "viewer": client_to_add
+        "image_name": request.image_name,
+        "views": request.views,
+        "viewer": request.viewer,
+        "reply_port": request.reply_port,
+        "request_id": request.request_id,
+        "timestamp": request.timestamp,
+        "signature": request.signature
     });
 
-    // Start a listener task for receiving images
-    let reply_port = 6000; // Hardcoded port for receiving the reply
-    let reply_output_path = "reply_image.png"; // Hardcoded output path for the received image
-
+    let reply_request_id = request_id.clone();
     tokio::spawn(async move {
-        if let Err(err) = receive_image_save(reply_port, reply_output_path).await {
+        if let Err(err) = receive_image_save(listener, &reply_output_path).await {
             eprintln!("Failed to receive reply image: {}", err);
         } else {
             println!("Reply image received successfully and saved to {}", reply_output_path);
+            let _guard = INBOX_INDEX_LOCK.lock().await;
+            let mut inbox_index = InboxIndex::load_from_file(INBOX_INDEX_PATH);
+            if let Some(entry) = inbox_index.entries.get_mut(&reply_request_id) {
+                entry.received = true;
+            }
+            if let Err(err) = inbox_index.save_to_file(INBOX_INDEX_PATH) {
+                eprintln!("Failed to update inbox index: {}", err);
+            }
             // let output_file_path = "first_decryption.png"; // Path to save the restored image
             // if let Err(err) = strip_metadata(reply_output_path, output_file_path).await {
             //     eprintln!("Failed to strip metadata: {}", err);
@@ -392,8 +1021,6 @@ pub async fn send_message_to_client(client_ip: &str, image_name: &str, client_to
         }
     });
 
-    add_notification(dos_address,client_name,image_name,client_to_add,views).await?;
-    
 
     // Sending the JSON payload as part of the request
     let response = client.post(send_msg_url)
@@ -404,7 +1031,7 @@ pub async fn send_message_to_client(client_ip: &str, image_name: &str, client_to
     
 
     if response.status().is_success() {
-        println!("Message sent successfully!");
+        println!("Request sent, waiting for the owner's approval.");
     } else {
         eprintln!("Failed to send message: {}", response.status());
         
@@ -513,7 +1140,7 @@ async fn list_notifications_with_choice_and_execute(
     let client = Client::new();
     let response = client
         .get(format!("{}/get_notifications", api_base_url))
-        .query(&[("client_id", client_id)])
+        .query(&[("client_id", client_id), ("password", password)])
         .send()
         .await?;
 
@@ -522,26 +1149,21 @@ async fn list_notifications_with_choice_and_execute(
         let notifications: Value = response.json().await?;
 
         // Check if notifications exist
-        if let Some(notifications_map) = notifications.as_object() {
-            let mut notification_list = Vec::new();
+        if let Some(notifications) = notifications.get("notifications").and_then(|v| v.as_array()) {
             println!("Notifications for client {}:", client_id);
-
-            let mut counter = 1;
-            for (requester, notifications_list) in notifications_map {
-                if let Some(list) = notifications_list.as_array() {
-                    for notification in list {
-                        if let (Some(image), Some(access_rights)) = (
-                            notification.get("image").and_then(|v| v.as_str()),
-                            notification.get("access_rights").and_then(|v| v.as_u64()),
-                        ) {
-                            println!(
-                                "{}. Requester: {}, Image: {}, Access Rights: {}",
-                                counter, requester, image, access_rights
-                            );
-                            notification_list.push((requester.clone(), image.to_string(), access_rights as u32));
-                            counter += 1;
-                        }
-                    }
+            for (index, notification) in notifications.iter().enumerate() {
+                let field = |name: &str| notification.get(name).and_then(|v| v.as_str()).unwrap_or_default();
+                let access_rights = notification.get("access_rights").and_then(|v| v.as_u64()).unwrap_or(0);
+                if field("kind") == "Outcome" {
+                    println!(
+                        "{}. {} {} your request for {} views of {}",
+                        index + 1, field("image_owner"), field("status").to_lowercase(), access_rights, field("image_name")
+                    );
+                } else {
+                    println!(
+                        "{}. Requester: {}, Image: {}, Access Rights: {}, Status: {}",
+                        index + 1, field("requester"), field("image_name"), access_rights, field("status")
+                    );
                 }
             }
 
@@ -553,35 +1175,72 @@ async fn list_notifications_with_choice_and_execute(
             let choice: usize = choice.trim().parse().unwrap_or(0);
 
             // Check if choice is valid
-            if choice > 0 && choice <= notification_list.len() {
-                let (requester, image_name, access_rights) = &notification_list[choice - 1];
-
-                // Perform logic for the selected notification
-                let mut access_rights_map = HashMap::new();
-                access_rights_map.insert(requester.clone(), *access_rights);
-
-                let payload = json!({
-                    "client_id": client_id,
-                    "password": password,
-                    "image_name": image_name,
-                    "access_rights": access_rights_map
-                });
+            if choice > 0 && choice <= notifications.len() {
+                let notification = &notifications[choice - 1];
+                let id = notification.get("id").and_then(|v| v.as_u64()).unwrap_or(0);
+                let requester = notification.get("requester").and_then(|v| v.as_str()).unwrap_or_default();
+                let image_name = notification.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
+                let is_pending_request = notification.get("kind").and_then(|v| v.as_str()) == Some("AccessRequest")
+                    && notification.get("status").and_then(|v| v.as_str()) == Some("Pending");
+
+                if is_pending_request {
+                    println!("a: Approve, r: Reject, d: Dismiss");
+                } else {
+                    println!("d: Dismiss");
+                }
+                let mut action = String::new();
+                io::stdout().flush()?;
+                io::stdin().read_line(&mut action)?;
+                let action = match action.trim() {
+                    "a" if is_pending_request => "approve",
+                    "r" if is_pending_request => "reject",
+                    "d" => "dismiss",
+                    _ => {
+                        println!("Invalid choice!");
+                        return Ok(());
+                    }
+                };
 
-                let modify_access_response = client
-                    .post(format!("{}/modify_access", dos_address))
-                    .json(&payload)
+                let decide_response = client
+                    .post(format!("{}/notifications/{}/{}", dos_address, id, action))
+                    .json(&json!({
+                        "client_id": client_id,
+                        "password": password
+                    }))
                     .send()
                     .await;
 
-                match modify_access_response {
+                match decide_response {
                     Ok(res) if res.status().is_success() => {
-                        println!("Access rights updated successfully for image: {}", image_name);
+                        let response_body: Value = res.json().await?;
+                        if let Some(error) = response_body.get("error") {
+                            eprintln!("Failed to {} notification: {}", action, error);
+                            return Ok(());
+                        }
+                        println!("{}", response_body.get("message").and_then(|v| v.as_str()).unwrap_or_default());
+
+                        if action == "approve" {
+                            // The requester may be offline, so leave the image on the DOS for them
+                            let request_id = format!("{:016x}", rand::random::<u64>());
+                            let upload_result = match encode_for_viewer(dos_address, client_id, password, requester, image_name, &request_id).await {
+                                Ok(delivery_path) => {
+                                    let result = upload_delivery(dos_address, client_id, password, requester, image_name, &request_id, &delivery_path).await;
+                                    let _ = std::fs::remove_file(&delivery_path);
+                                    result
+                                }
+                                Err(err) => Err(err),
+                            };
+                            match upload_result {
+                                Ok(()) => println!("Delivery stored on the DOS for {}.", requester),
+                                Err(err) => eprintln!("{}", err),
+                            }
+                        }
                     }
                     Ok(res) => {
-                        eprintln!("Failed to modify access rights: {:?}", res.text().await);
+                        eprintln!("Failed to {} notification: {:?}", action, res.text().await);
                     }
                     Err(err) => {
-                        eprintln!("Error communicating with modify_access endpoint: {}", err);
+                        eprintln!("Error communicating with notifications endpoint: {}", err);
                     }
                 }
             } else {
@@ -600,111 +1259,799 @@ async fn list_notifications_with_choice_and_execute(
     Ok(())
 }
 
-async fn handle_request(
-    req: Request<Body>,
-    shared_state: Arc<Mutex<(String, String, String)>>, // Shared state
-    socket: Arc<Mutex<UdpSocket>>,                     // Mutex for socket
-    leader_address: String,                            // Owned leader address
-) -> Result<Response<Body>, Infallible>{
-    match (req.method(), req.uri().path()) {
-        (&hyper::Method::POST, "/receive_message") => {
-            let full_body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 64
+        && request_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
 
-            match serde_json::from_slice::<Message>(&full_body) {
-                Ok(parsed_message) => {
-                    let state = shared_state.lock().await;
-                    let client_id = &state.1; // Access client_id
-                    let password = &state.2;  // Access password
-                    let dos_address = &state.0; // Access dos_address
+/// Lists the delivered images in the inbox and returns the path of the one the user picks.
+fn choose_inbox_image() -> Result<Option<String>, Box<dyn Error>> {
+    let inbox_index = InboxIndex::load_from_file(INBOX_INDEX_PATH);
+    let mut received: Vec<(&String, &InboxEntry)> = inbox_index
+        .entries
+        .iter()
+        .filter(|(_, entry)| entry.received)
+        .collect();
+    received.sort_by(|a, b| a.0.cmp(b.0));
+
+    if received.is_empty() {
+        println!("No images received yet.");
+        return Ok(None);
+    }
 
-                    println!(
-                        "Received image name: {}, views: {}, using client ID: {}, DOS address: {}",
-                        parsed_message.image_name, parsed_message.views, client_id, dos_address
-                    );
+    for (index, (request_id, entry)) in received.iter().enumerate() {
+        println!("{}. {} from {} (request {})", index + 1, entry.image_name, entry.owner, request_id);
+    }
 
-                    let mut access_rights = HashMap::new();
-                    access_rights.insert(parsed_message.viewer.to_string(), parsed_message.views as u32); // Example access
-                    let client_ip = match resolve_client_ip(&leader_address, &parsed_message.viewer).await {
-                        Ok(ip) => ip,
-                        Err(err) => {
-                            eprintln!("Failed to resolve client IP: {}", err);
-                            return Ok(Response::builder()
-                                .status(StatusCode::BAD_REQUEST)
-                                .body(Body::from("Failed to resolve client IP"))
-                                .unwrap());
-                        }
-                    };
-                    let client = reqwest::Client::new();
-                    let payload = json!({
-                        "client_id": client_id,
-                        "password": password,
-                        "image_name": parsed_message.image_name,
-                        "access_rights": access_rights
-                    });
+    let mut choice = String::new();
+    print!("Enter the number of the image: ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut choice)?;
+    let choice: usize = choice.trim().parse().unwrap_or(0);
 
-                    let response = client
-                        .post(format!("{}/modify_access", dos_address))
-                        .json(&payload)
-                        .send()
-                        .await;
+    if choice == 0 || choice > received.len() {
+        println!("Invalid choice!");
+        return Ok(None);
+    }
+    Ok(Some(received[choice - 1].1.path.clone()))
+}
 
-                    match response {
-                        Ok(res) if res.status().is_success() => {
-                            println!("Access rights updated successfully!");
+/// Encrypts and hides `image_name` for `viewer` in the outbox and returns the path of the
+/// delivery file. The caller removes it once it has been sent or uploaded.
+async fn encode_for_viewer(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    viewer: &str,
+    image_name: &str,
+    request_id: &str,
+) -> Result<String, String> {
+    // Derive the key shared with the viewer from their published public key
+    let shared_key = shared_key_with(dos_address, client_id, viewer)
+        .await
+        .map_err(|err| format!("Failed to derive key for viewer {}: {}", viewer, err))?;
+
+    // Without this share, which the DOS releases wrapped once per view, the viewer can't decrypt the delivery
+    let view_share: [u8; 32] = rand::random();
+    let view_key = derive_view_key(&shared_key, &view_share);
+
+    // Work files for this request only, so concurrent deliveries don't overwrite each other
+    std::fs::create_dir_all(OUTBOX_DIR).map_err(|err| format!("Failed to create outbox: {}", err))?;
+    let watermarked_file_path = format!("{}/{}_watermarked.png", OUTBOX_DIR, request_id);
+    let cover_file_path = format!("{}/{}_cover.png", OUTBOX_DIR, request_id);
+    let input_file_path = format!("{}/{}_encoded.png", OUTBOX_DIR, request_id);
+    let output_file_path = format!("{}/{}_delivery.png", OUTBOX_DIR, request_id);
+
+    // Mark the viewer's copy if the owner asked for it, so a leak can be traced back to them
+    let source_path = if WatermarkSettings::load_from_file(WATERMARK_SETTINGS_PATH).is_enabled(image_name) {
+        apply_visible_watermark(image_name, &watermarked_file_path, viewer)
+            .map_err(|err| format!("Failed to watermark image for viewer: {}", err))?;
+        watermarked_file_path.clone()
+    } else {
+        image_name.to_string()
+    };
 
-                            {
-                                let mut socket = socket.lock().await; // Lock the socket for encryption
-                                middleware_encrypt(&*socket, &leader_address,&parsed_message.image_name).await.unwrap();
-                            }
+    // Every copy also carries an invisible ID that survives re-compression and resizing. It keeps
+    // the source's format, a photo re-encoded as PNG would no longer fit in any cover.
+    let forensic_format = forensic_output_format(&source_path);
+    let forensic_file_path = format!("{}/{}_forensic.{}", OUTBOX_DIR, request_id, forensic_format.extensions_str()[0]);
+    let (width, height) = image::image_dimensions(&source_path)
+        .map_err(|err| format!("Failed to read {}: {}", image_name, err))?;
+    let source_path = if let Err(err) = check_forensic_size(width, height) {
+        println!("Delivering {} without a forensic watermark: {}", image_name, err);
+        source_path
+    } else {
+        // Reserve an unused ID, one already handed out would make a leak point at the wrong delivery
+        let (watermark_id, watermark_key) = {
+            let _guard = FORENSIC_WATERMARKS_LOCK.lock().await;
+            let mut watermarks = ForensicWatermarks::load_from_file(FORENSIC_WATERMARKS_PATH);
+            let (watermark_id, watermark_key) = loop {
+                let watermark_id: u32 = rand::random();
+                let watermark_key = format!("{:08x}", watermark_id);
+                if !watermarks.watermarks.contains_key(&watermark_key) {
+                    break (watermark_id, watermark_key);
+                }
+            };
+            watermarks.watermarks.insert(
+                watermark_key.clone(),
+                ForensicWatermark {
+                    viewer: viewer.to_string(),
+                    image_name: image_name.to_string(),
+                    request_id: request_id.to_string(),
+                    created_at: unix_timestamp(),
+                },
+            );
+            watermarks.save_to_file(FORENSIC_WATERMARKS_PATH).map_err(|err| err.to_string())?;
+            (watermark_id, watermark_key)
+        };
+
+        if let Err(err) = apply_forensic_watermark(&source_path, &forensic_file_path, forensic_format, watermark_id) {
+            let _guard = FORENSIC_WATERMARKS_LOCK.lock().await;
+            let mut watermarks = ForensicWatermarks::load_from_file(FORENSIC_WATERMARKS_PATH);
+            watermarks.watermarks.remove(&watermark_key);
+            let _ = watermarks.save_to_file(FORENSIC_WATERMARKS_PATH);
+            let _ = std::fs::remove_file(&watermarked_file_path);
+            return Err(format!("Failed to add the forensic watermark: {}", err));
+        }
+        forensic_file_path.clone()
+    };
 
-                            {
-                                let mut socket = socket.lock().await; // Lock the socket for decryption
-                                middleware_decrypt(&*socket).await.unwrap();
-                            }
-                            // Path to the input and output images
-                            let input_file_path = "encoded_image_received.png";
-                            let output_file_path = "re_encrypted_image.png";
-
-                            if let Err(err) = fetch_and_encrypt_image(
-                                input_file_path,
-                                output_file_path,
-                                dos_address,
-                                &parsed_message.image_name,
-                                client_id,
-                                password,
-                                &parsed_message.viewer,
-                            ).await {
-                                eprintln!("Failed to fetch views or re-encrypt the image: {}", err);
-                            } else {
-                                println!(
-                                    "Image successfully fetched and re-encrypted with the number of views. Saved to {}.",
-                                    output_file_path
-                                );
-                            }
-                            // Send the image to the client
-                            
-                            let client_port = 6000;      // Replace with the client's port
-
-                            if let Err(err) = send_image(&client_ip, client_port, output_file_path).await {
-                                eprintln!("Failed to send image to client: {}", err);
-                                return Ok(Response::new(Body::from("Error during image sending")));
-                            } else {
-                                println!("Image sent successfully to the client.");
-                            }
+    // Pick a cover big enough for the encrypted image
+    let payload_len = std::fs::metadata(&source_path)
+        .map(|metadata| hidden_stream_len(metadata.len() as usize))
+        .unwrap_or(0);
+    let cover_path = prepare_cover(dos_address, client_id, password, image_name, payload_len, &cover_file_path)
+        .await
+        .map_err(|err| format!("Failed to prepare a cover image: {}", err))?;
+
+    // Encrypt for the viewer and hide the image locally, it never leaves this machine in clear
+    let encode_result = encode_image(&source_path, &cover_path, &input_file_path, &view_key)
+        .map_err(|err| format!("Failed to encode image for viewer: {}", err));
+    // Only the work files go, the source may be the owner's original
+    for work_file_path in [&cover_file_path, &watermarked_file_path, &forensic_file_path] {
+        let _ = std::fs::remove_file(work_file_path);
+    }
+    encode_result?;
+
+    let target = DeliveryTarget { viewer, image_name, request_id };
+    let embed_result = fetch_and_encrypt_image(
+        &input_file_path,
+        &output_file_path,
+        dos_address,
+        client_id,
+        password,
+        &target,
+    )
+    .await
+    .map_err(|err| format!("Failed to fetch views or re-encrypt the image: {}", err));
+    let _ = std::fs::remove_file(&input_file_path);
+    let views = embed_result?;
+    println!(
+        "Image successfully fetched and re-encrypted with the number of views. Saved to {}.",
+        output_file_path
+    );
+
+    // One single-use share per view the viewer has now
+    let shares: Vec<[u8; 32]> = (0..views.min(MAX_VIEW_SHARES))
+        .map(|view_index| wrap_view_share(&shared_key, request_id, u64::from(view_index), &view_share))
+        .collect();
+    if let Err(err) = register_view_share(dos_address, client_id, password, image_name, viewer, request_id, &shares).await {
+        let _ = std::fs::remove_file(&output_file_path);
+        return Err(err);
+    }
+    Ok(output_file_path)
+}
+
+async fn register_view_share(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    image_name: &str,
+    viewer: &str,
+    request_id: &str,
+    shares: &[[u8; 32]],
+) -> Result<(), String> {
+    let shares: Vec<String> = shares.iter().map(|share| general_purpose::STANDARD.encode(share)).collect();
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/register_view_share", dos_address))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password,
+            "image_name": image_name,
+            "viewer": viewer,
+            "request_id": request_id,
+            "shares": shares
+        }))
+        .send()
+        .await
+        .map_err(|err| format!("Error communicating with register_view_share endpoint: {}", err))?;
 
+    let response_body: Value = response.json().await.map_err(|err| err.to_string())?;
+    match response_body.get("error") {
+        Some(error) => Err(format!("Failed to register view share: {}", error)),
+        None => Ok(()),
+    }
+}
 
+/// One view of a delivery granted by the DOS. The share is still wrapped for `view_index`
+/// and must be used before `expires_at`.
+struct ViewGrant {
+    share: [u8; 32],
+    view_index: u64,
+    remaining_views: u64,
+    expires_at: u64,
+}
 
-                            Ok(Response::new(Body::from("Message received and access modified")))
-                        }
-                        Ok(res) => {
-                            eprintln!("Failed to modify access rights: {:?}", res.text().await);
-                            Ok(Response::new(Body::from("Failed to modify access rights")))
-                        }
-                        Err(e) => {
-                            eprintln!("Error communicating with modify_access endpoint: {}", e);
-                            Ok(Response::new(Body::from("Error communicating with server")))
+/// Asks the DOS for one view of a delivery. The DOS takes the view off the viewer's count
+/// and hands out the next single-use key share together with the views left.
+async fn request_view(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    owner: &str,
+    image_name: &str,
+    request_id: &str,
+) -> Result<ViewGrant, Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/request_view", dos_address))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password,
+            "owner": owner,
+            "image_name": image_name,
+            "request_id": request_id
+        }))
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("View refused: {}", error).into());
+    }
+    let share = response_body
+        .get("share")
+        .and_then(|v| v.as_str())
+        .ok_or("No key share in the view grant")?;
+    let share: [u8; 32] = general_purpose::STANDARD
+        .decode(share)?
+        .try_into()
+        .map_err(|_| "Key share must be 32 bytes")?;
+    let view_index = response_body
+        .get("view_index")
+        .and_then(|v| v.as_u64())
+        .ok_or("No view index in the view grant")?;
+    let expires_at = response_body
+        .get("expires_at")
+        .and_then(|v| v.as_u64())
+        .ok_or("No expiry in the view grant")?;
+    let remaining_views = response_body.get("remaining_views").and_then(|v| v.as_u64()).unwrap_or(0);
+    Ok(ViewGrant { share, view_index, remaining_views, expires_at })
+}
+
+/// Leaves an encoded delivery on the DOS for a viewer who can't be reached directly.
+async fn upload_delivery(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    viewer: &str,
+    image_name: &str,
+    request_id: &str,
+    delivery_path: &str,
+) -> Result<(), String> {
+    let data = std::fs::read(delivery_path).map_err(|err| format!("Failed to read delivery: {}", err))?;
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/upload_delivery", dos_address))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password,
+            "viewer": viewer,
+            "image_name": image_name,
+            "request_id": request_id,
+            "data": general_purpose::STANDARD.encode(data)
+        }))
+        .send()
+        .await
+        .map_err(|err| format!("Error communicating with upload_delivery endpoint: {}", err))?;
+
+    let response_body: Value = response.json().await.map_err(|err| err.to_string())?;
+    match response_body.get("error") {
+        Some(error) => Err(format!("Failed to upload delivery: {}", error)),
+        None => Ok(()),
+    }
+}
+
+fn event_cursor_path(client_id: &str) -> String {
+    format!("event_cursor_{}.json", client_id)
+}
+
+/// Epoch and ID of the last event this user has seen, so the stream resumes where it left off.
+/// IDs only mean something within the epoch of the DOS event log that issued them.
+fn load_event_cursor(client_id: &str) -> (String, u64) {
+    let cursor = std::fs::read_to_string(event_cursor_path(client_id))
+        .ok()
+        .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+        .unwrap_or_default();
+    (
+        cursor.get("epoch").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+        cursor.get("last_event_id").and_then(|v| v.as_u64()).unwrap_or(0),
+    )
+}
+
+fn save_event_cursor(client_id: &str, epoch: &str, last_event_id: u64) {
+    let cursor = json!({ "epoch": epoch, "last_event_id": last_event_id });
+    if let Err(e) = std::fs::write(event_cursor_path(client_id), cursor.to_string()) {
+        eprintln!("Failed to save event cursor: {}", e);
+    }
+}
+
+/// Prints the events the DOS streams for this client (requests, grants, revocations, deliveries)
+/// as they happen, reconnecting if the connection drops. Events missed while disconnected are
+/// replayed by the DOS on reconnect.
+async fn subscribe_to_events(dos_address: String, client_id: String, password: String) {
+    let ws_base = dos_address.replacen("http://", "ws://", 1);
+    let (mut epoch, mut last_event_id) = load_event_cursor(&client_id);
+
+    loop {
+        let ws_url = match reqwest::Url::parse_with_params(
+            &format!("{}/ws", ws_base),
+            &[
+                ("client_id", client_id.as_str()),
+                ("password", password.as_str()),
+                ("epoch", epoch.as_str()),
+                ("since", last_event_id.to_string().as_str()),
+            ],
+        ) {
+            Ok(url) => url.to_string(),
+            Err(e) => {
+                eprintln!("Invalid event stream address: {}", e);
+                return;
+            }
+        };
+
+        match connect_async(ws_url.as_str()).await {
+            Ok((mut ws_stream, _)) => {
+                while let Some(Ok(message)) = ws_stream.next().await {
+                    let Ok(text) = message.into_text() else {
+                        continue;
+                    };
+                    let Ok(event) = serde_json::from_str::<Value>(&text) else {
+                        continue;
+                    };
+                    // After a failover or a reset log the IDs start over, so the old cursor is dropped
+                    let event_epoch = event.get("epoch").and_then(|v| v.as_str()).unwrap_or_default();
+                    if event_epoch != epoch {
+                        epoch = event_epoch.to_string();
+                        last_event_id = 0;
+                    }
+                    let id = event.get("id").and_then(|v| v.as_u64()).unwrap_or(0);
+                    if id <= last_event_id {
+                        continue;
+                    }
+                    last_event_id = id;
+                    save_event_cursor(&client_id, &epoch, last_event_id);
+                    if event.get("event").and_then(|v| v.as_str()) == Some("access_revoked") {
+                        if let Err(e) = apply_revocations(&dos_address, &client_id, &password).await {
+                            eprintln!("{}", e);
                         }
                     }
+                    println!(
+                        "\n[{}] {}",
+                        event.get("event").and_then(|v| v.as_str()).unwrap_or("event"),
+                        event.get("message").and_then(|v| v.as_str()).unwrap_or(&text)
+                    );
+                }
+                eprintln!("Event stream closed, reconnecting...");
+            }
+            Err(e) => eprintln!("Failed to connect to the event stream: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Overwrites a file before removing it, so the image can't be read back from the freed blocks
+/// on simple filesystems. Not a guarantee on SSDs or copy-on-write filesystems.
+fn secure_delete(path: &str) -> io::Result<()> {
+    let len = std::fs::metadata(path)?.len() as usize;
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    let mut noise = vec![0u8; len];
+    rand::Rng::fill(&mut rand::thread_rng(), &mut noise[..]);
+    file.write_all(&noise)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::remove_file(path)
+}
+
+/// Deletes local copies of images whose access was revoked and reports each wipe to the DOS.
+async fn apply_revocations(dos_address: &str, client_id: &str, password: &str) -> Result<(), String> {
+    let client = Client::new();
+    let response = client
+        .get(format!("{}/revocations", dos_address))
+        .query(&[("client_id", client_id), ("password", password)])
+        .send()
+        .await
+        .map_err(|err| format!("Error communicating with revocations endpoint: {}", err))?;
+    let response_body: Value = response.json().await.map_err(|err| err.to_string())?;
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("Failed to fetch revocations: {}", error));
+    }
+
+    let revocations = response_body
+        .get("revocations")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    for revocation in revocations {
+        let owner = revocation.get("owner").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_name = revocation.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
+
+        let guard = INBOX_INDEX_LOCK.lock().await;
+        let mut inbox_index = InboxIndex::load_from_file(INBOX_INDEX_PATH);
+        let revoked: Vec<String> = inbox_index
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.owner == owner && entry.image_name == image_name)
+            .map(|(request_id, _)| request_id.clone())
+            .collect();
+        let mut wiped_copies = 0;
+        for request_id in revoked {
+            if let Some(entry) = inbox_index.entries.remove(&request_id) {
+                match secure_delete(&entry.path) {
+                    Ok(()) => wiped_copies += 1,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => eprintln!("Failed to delete {}: {}", entry.path, err),
+                }
+            }
+        }
+        inbox_index.save_to_file(INBOX_INDEX_PATH).map_err(|err| err.to_string())?;
+        drop(guard);
+        println!("Access to {}'s image {} was revoked, deleted {} local copies.", owner, image_name, wiped_copies);
+
+        let ack_response = client
+            .post(format!("{}/revocations/ack", dos_address))
+            .json(&json!({
+                "client_id": client_id,
+                "password": password,
+                "owner": owner,
+                "image_name": image_name,
+                "wiped_copies": wiped_copies
+            }))
+            .send()
+            .await
+            .map_err(|err| format!("Error communicating with revocations endpoint: {}", err))?;
+        if !ack_response.status().is_success() {
+            eprintln!("Failed to report the wipe of {}: {}", image_name, ack_response.status());
+        }
+    }
+    Ok(())
+}
+
+/// Collects the deliveries owners left on the DOS while this client was offline into the inbox.
+async fn fetch_deliveries(dos_address: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/fetch_deliveries", dos_address))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password
+        }))
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("Failed to fetch deliveries: {}", error).into());
+    }
+    let deliveries = response_body
+        .get("deliveries")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    std::fs::create_dir_all(INBOX_DIR)?;
+    let guard = INBOX_INDEX_LOCK.lock().await;
+    let mut inbox_index = InboxIndex::load_from_file(INBOX_INDEX_PATH);
+    let mut stored = Vec::new();
+    for delivery in deliveries {
+        let field = |name: &str| delivery.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        let request_id = field("request_id");
+        // The request ID names the inbox file, so it must not contain path separators
+        if !is_valid_request_id(&request_id) {
+            eprintln!("Skipping delivery with an invalid request ID");
+            continue;
+        }
+
+        // A bad delivery is left on the DOS and must not stop the others from being stored
+        let path = format!("{}/{}.png", INBOX_DIR, request_id);
+        let data = match general_purpose::STANDARD.decode(field("data")) {
+            Ok(data) => data,
+            Err(err) => {
+                eprintln!("Skipping delivery {}: invalid image data: {}", request_id, err);
+                continue;
+            }
+        };
+        if let Err(err) = std::fs::write(&path, data) {
+            eprintln!("Skipping delivery {}: failed to save {}: {}", request_id, path, err);
+            continue;
+        }
+        println!("Received {} from {} while offline, saved to {}", field("image_name"), field("owner"), path);
+        inbox_index.entries.insert(
+            request_id.clone(),
+            InboxEntry {
+                owner: field("owner"),
+                image_name: field("image_name"),
+                path,
+                received: true,
+            },
+        );
+        stored.push(request_id);
+    }
+    inbox_index.save_to_file(INBOX_INDEX_PATH)?;
+    drop(guard);
+    if stored.is_empty() {
+        return Ok(());
+    }
+
+    // Only now that they are in the inbox index can the DOS drop them
+    let response_body: Value = client
+        .post(format!("{}/deliveries/ack", dos_address))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password,
+            "request_ids": stored
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("Failed to acknowledge deliveries: {}", error).into());
+    }
+    Ok(())
+}
+
+/// Delivers the encrypted image for an approved request to the port the viewer advertised,
+/// falling back to the DOS if the viewer can't be reached.
+async fn deliver_image(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    leader_address: &str,
+    request: &Message,
+) -> Result<(), String> {
+    let output_file_path = encode_for_viewer(
+        dos_address,
+        client_id,
+        password,
+        &request.viewer,
+        &request.image_name,
+        &request.request_id,
+    ).await?;
+
+    // Send the image to the port the requester advertised for this request
+    let metadata = json!({
+        "image_name": request.image_name,
+        "owner": client_id,
+        "viewer": request.viewer,
+        "request_id": request.request_id
+    });
+    let send_result = match resolve_client_ip(leader_address, &request.viewer).await {
+        Ok(client_ip) => send_image(&client_ip, request.reply_port, &output_file_path, &metadata)
+            .await
+            .map_err(|err| format!("Failed to send image to client: {}", err)),
+        Err(err) => Err(format!("Failed to resolve client IP: {}", err)),
+    };
+    let result = match send_result {
+        Ok(()) => {
+            println!("Image sent successfully to the client.");
+            Ok(())
+        }
+        Err(err) => {
+            eprintln!("{}, leaving the delivery on the DOS instead.", err);
+            upload_delivery(
+                dos_address,
+                client_id,
+                password,
+                &request.viewer,
+                &request.image_name,
+                &request.request_id,
+                &output_file_path,
+            ).await.map(|_| println!("Delivery stored on the DOS for {}.", request.viewer))
+        }
+    };
+    let _ = std::fs::remove_file(&output_file_path);
+    result
+}
+
+/// Lets the owner approve, reject or counter-offer each queued request.
+/// Only approved requests are encoded and delivered.
+async fn review_pending_requests(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    leader_address: &str,
+    pending_requests: &Arc<Mutex<Vec<Message>>>,
+) -> Result<(), Box<dyn Error>> {
+    let requests: Vec<Message> = pending_requests.lock().await.drain(..).collect();
+    if requests.is_empty() {
+        println!("No pending requests.");
+        return Ok(());
+    }
+
+    let mut undecided = Vec::new();
+    for request in requests {
+        // Only requests recorded on the DOS count; anything else was decided offline, expired or forged
+        let notification_id = match pending_access_request(dos_address, client_id, password, &request).await {
+            Ok(Some(notification_id)) => notification_id,
+            Ok(None) => {
+                println!("Request {} from {} is no longer pending, dropping it.", request.request_id, request.viewer);
+                continue;
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                undecided.push(request);
+                continue;
+            }
+        };
+
+        println!(
+            "{} requests {} views of {} (request {})",
+            request.viewer, request.views, request.image_name, request.request_id
+        );
+        println!("a: Approve, r: Reject, c: Counter-offer fewer views, s: Skip for now");
+
+        let mut decision = String::new();
+        io::stdout().flush()?;
+        io::stdin().read_line(&mut decision)?;
+
+        let views = match decision.trim() {
+            "a" => request.views.max(0) as u32,
+            "c" => {
+                let mut views = String::new();
+                print!("Enter the number of views to grant (less than {}): ", request.views);
+                io::stdout().flush()?;
+                io::stdin().read_line(&mut views)?;
+                match views.trim().parse::<u32>() {
+                    Ok(views) if views > 0 && (views as i32) < request.views => views,
+                    _ => {
+                        println!("Invalid number of views, keeping the request pending.");
+                        undecided.push(request);
+                        continue;
+                    }
+                }
+            }
+            "r" => {
+                match decide_access_request(dos_address, client_id, password, notification_id, "reject", 0, AccessWindow::default()).await {
+                    Ok(()) => println!("Rejected request {} from {}", request.request_id, request.viewer),
+                    Err(err) => eprintln!("{}", err),
+                }
+                continue;
+            }
+            _ => {
+                undecided.push(request);
+                continue;
+            }
+        };
+
+        // Optionally delay the start of the grant and limit how long it lasts
+        let Some(access_window) = read_access_window()? else {
+            println!("Invalid number of hours, keeping the request pending.");
+            undecided.push(request);
+            continue;
+        };
+
+        // The DOS grants the views on approval, only then is the image worth encoding
+        if let Err(err) = decide_access_request(dos_address, client_id, password, notification_id, "approve", views, access_window).await {
+            eprintln!("{}", err);
+            continue;
+        }
+        println!("Access rights updated successfully!");
+        if let Err(err) = deliver_image(dos_address, client_id, password, leader_address, &request).await {
+            eprintln!("{}", err);
+        }
+    }
+
+    // Requests the owner skipped stay queued alongside any that arrived meanwhile
+    pending_requests.lock().await.extend(undecided);
+    Ok(())
+}
+
+/// Asks when a grant starts and when it expires, in hours from now. Empty answers leave that end
+/// open; None if either answer is not a usable number of hours.
+fn read_access_window() -> io::Result<Option<AccessWindow>> {
+    let read_hours = |prompt: &str| -> io::Result<Option<Option<u64>>> {
+        let mut hours = String::new();
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        io::stdin().read_line(&mut hours)?;
+        if hours.trim().is_empty() {
+            return Ok(Some(None));
+        }
+        Ok(hours
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .and_then(|hours| hours.checked_mul(3600))
+            .and_then(|secs| unix_timestamp().checked_add(secs))
+            .map(Some))
+    };
+    let Some(not_before) = read_hours("Access starts after how many hours? (leave empty to start now): ")? else {
+        return Ok(None);
+    };
+    let Some(expires_at) = read_hours("Access expires after how many hours? (leave empty for no expiry): ")? else {
+        return Ok(None);
+    };
+    Ok(Some(AccessWindow { not_before, expires_at }))
+}
+
+/// Checks that a request was signed by the viewer it names, is recent and has not been seen before.
+async fn authenticate_request(
+    request: &Message,
+    owner_identity: &Arc<Mutex<Option<(String, String)>>>,
+    seen_requests: &Arc<Mutex<HashMap<String, u64>>>,
+) -> Result<(), (StatusCode, String)> {
+    let (dos_address, client_id) = owner_identity
+        .lock()
+        .await
+        .clone()
+        .ok_or((StatusCode::SERVICE_UNAVAILABLE, "Owner is not logged in yet".to_string()))?;
+
+    let now = unix_timestamp();
+    if request.timestamp < now.saturating_sub(REQUEST_MAX_AGE_SECS) || request.timestamp > now.saturating_add(REQUEST_MAX_AGE_SECS) {
+        return Err((StatusCode::UNAUTHORIZED, "Request timestamp is too old or in the future".to_string()));
+    }
+
+    // The viewer's registered public key is the only way to get the key the request was signed with
+    let shared_key = shared_key_with(&dos_address, &client_id, &request.viewer)
+        .await
+        .map_err(|err| (StatusCode::UNAUTHORIZED, format!("Unknown viewer {}: {}", request.viewer, err)))?;
+    if !verify_request_signature(&shared_key, &request.signed_content(&client_id), &request.signature) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid request signature".to_string()));
+    }
+
+    // Signed requests are only replayable within their age window, so that is all we need to remember.
+    // A viewer re-sending a pending request signs it with a new timestamp, only exact copies are replays.
+    let mut seen_requests = seen_requests.lock().await;
+    seen_requests.retain(|_, timestamp| *timestamp >= now.saturating_sub(REQUEST_MAX_AGE_SECS));
+    if seen_requests.contains_key(&request.signature) {
+        return Err((StatusCode::CONFLICT, "Request was already received".to_string()));
+    }
+    seen_requests.insert(request.signature.clone(), request.timestamp);
+    Ok(())
+}
+
+async fn handle_request(
+    req: Request<Body>,
+    pending_requests: Arc<Mutex<Vec<Message>>>, // Requests waiting for the owner's decision
+    owner_identity: Arc<Mutex<Option<(String, String)>>>, // DOS address and client ID once logged in
+    seen_requests: Arc<Mutex<HashMap<String, u64>>>, // Signatures of requests accepted recently, to refuse replays
+) -> Result<Response<Body>, Infallible>{
+    match (req.method(), req.uri().path()) {
+        (&hyper::Method::POST, "/receive_message") => {
+            let full_body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+
+            match serde_json::from_slice::<Message>(&full_body) {
+                Ok(parsed_message) => {
+                    println!(
+                        "Received image name: {}, views: {}, from viewer: {}",
+                        parsed_message.image_name, parsed_message.views, parsed_message.viewer
+                    );
+
+                    // The request ID names files in the outbox, so it must not contain path separators
+                    if !is_valid_request_id(&parsed_message.request_id) {
+                        return Ok(Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from("Invalid request ID"))
+                            .unwrap());
+                    }
+
+                    if let Err((status, reason)) = authenticate_request(&parsed_message, &owner_identity, &seen_requests).await {
+                        eprintln!("Refused request {} claiming to be from {}: {}", parsed_message.request_id, parsed_message.viewer, reason);
+                        return Ok(Response::builder()
+                            .status(status)
+                            .body(Body::from(reason))
+                            .unwrap());
+                    }
+
+                    // Nothing is granted or sent until the owner approves the request
+                    println!(
+                        "New request from {} for {}. Choose \"Review pending requests\" to approve or reject it.",
+                        parsed_message.viewer, parsed_message.image_name
+                    );
+                    // A re-sent request replaces the earlier one, it carries the port the viewer listens on now
+                    let mut pending_requests = pending_requests.lock().await;
+                    pending_requests.retain(|pending| {
+                        pending.request_id != parsed_message.request_id || pending.viewer != parsed_message.viewer
+                    });
+                    pending_requests.push(parsed_message);
+
+                    Ok(Response::builder()
+                        .status(StatusCode::ACCEPTED)
+                        .body(Body::from("Request queued for the owner's approval"))
+                        .unwrap())
                 }
                 Err(e) => {
                     eprintln!("Failed to parse JSON: {}", e);
@@ -822,18 +2169,26 @@ async fn resolve_client_ip(leader_address: &str, client_name: &str) -> Result<St
 }
 
 
+/// The viewer, image and request one delivered copy is made for.
+struct DeliveryTarget<'a> {
+    viewer: &'a str,
+    image_name: &'a str,
+    request_id: &'a str,
+}
+
+/// Embeds the viewer's current access terms, signed, into the encoded image. Returns the views granted.
 async fn fetch_and_encrypt_image(
     input_file_path: &str,
     output_file_path: &str,
     server_url: &str,
-    image_name: &str,
     client_id: &str,
     password: &str,
-    viewer: &str,
-) -> Result<(), Box<dyn Error>> {
+    target: &DeliveryTarget<'_>,
+) -> Result<u32, Box<dyn Error>> {
+    let DeliveryTarget { viewer, image_name, request_id } = *target;
     // Fetch the number of views from the server
-    let fetched_views = match get_access_for_viewer(server_url, client_id, password, image_name, viewer).await {
-        Ok(Some(views)) => views,
+    let (fetched_views, access_window) = match get_access_for_viewer(server_url, client_id, password, image_name, viewer).await {
+        Ok(Some(access)) => access,
         Ok(None) => {
             eprintln!("Viewer does not have access to the image.");
             return Err("No access found for the viewer".into());
@@ -846,11 +2201,24 @@ async fn fetch_and_encrypt_image(
 
     println!("Fetched views: {}", fetched_views);
 
+    // Sign the access terms so the viewer's client can tell if they were edited
+    let metadata = AccessMetadata {
+        owner: client_id.to_string(),
+        viewer: viewer.to_string(),
+        image_name: image_name.to_string(),
+        request_id: request_id.to_string(),
+        views: fetched_views,
+        not_before: access_window.not_before,
+        expires_at: access_window.expires_at,
+        nonce: format!("{:032x}", rand::random::<u128>()),
+    };
+    let signing_key = ClientIdentity::load_or_create_signing_key(client_id)?;
+
     // Encrypt the image with the fetched views
     //encrypt_image_with_views_and_overlay(input_file_path, output_file_path, fetched_views).await?;
-    embed_views_metadata(input_file_path, output_file_path, fetched_views).await?;
+    embed_views_metadata(input_file_path, output_file_path, &metadata, &signing_key).await?;
     // call the new function
-    Ok(())
+    Ok(fetched_views)
 }
 
 
@@ -860,7 +2228,7 @@ pub async fn get_access_for_viewer(
     password: &str,
     image_name: &str,
     viewer: &str,
-) -> Result<Option<u32>, Box<dyn Error>> {
+) -> Result<Option<(u32, AccessWindow)>, Box<dyn Error>> {
     // Create the HTTP client
     let client = Client::new();
 
@@ -885,7 +2253,12 @@ pub async fn get_access_for_viewer(
         if let Some(access_rights) = response_body.get("access_rights") {
             if let Some(viewer_access) = access_rights.get(viewer) {
                 if let Some(views) = viewer_access.as_u64() {
-                    return Ok(Some(views as u32));
+                    let window = response_body
+                        .get("access_windows")
+                        .and_then(|windows| windows.get(viewer))
+                        .and_then(|window| serde_json::from_value(window.clone()).ok())
+                        .unwrap_or_default();
+                    return Ok(Some((views as u32, window)));
                 }
             }
         }
@@ -901,44 +2274,52 @@ pub async fn get_access_for_viewer(
         .into())
     }
 }
+/// Fixed-size start of an image frame: magic, version, header and body lengths, and the body's SHA-256.
+fn frame_prefix(header: &[u8], body: &[u8]) -> Vec<u8> {
+    let digest = Sha256::digest(body);
+
+    let mut prefix = Vec::with_capacity(FRAME_MAGIC.len() + 1 + 4 + 8 + digest.len());
+    prefix.extend_from_slice(FRAME_MAGIC);
+    prefix.push(FRAME_VERSION);
+    prefix.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    prefix.extend_from_slice(&digest);
+    prefix
+}
+
+/// Sends an image to a peer as a single frame:
+/// `magic "DPIM" | version (u8) | header length (u32 BE) | body length (u64 BE) | SHA-256 of body | header JSON | body`.
+/// The header carries metadata about the image, the body is the file as is.
 pub async fn send_image(
     server_ip: &str,
     server_port: u16,
     file_path: &str,
+    metadata: &Value,
 ) -> Result<(), Box<dyn Error>> {
     // Connect to the server
     let target_address = format!("{}:{}", server_ip, server_port);
     let mut stream = TcpStream::connect(&target_address).await?;
     println!("Connected to server at {}", target_address);
 
-    // Open the file
-    let mut file = File::open(file_path).await?;
-    let mut buffer = [0u8; 1024];
-
-    println!("Starting to send the image...");
-
-    while let Ok(bytes_read) = file.read(&mut buffer).await {
-        if bytes_read == 0 {
-            break; // End of file reached
-        }
-
-        // Send the chunk of data
-        stream.write_all(&buffer[..bytes_read]).await?;
-        println!("Sent {} bytes", bytes_read);
-    }
+    let body = tokio::fs::read(file_path).await?;
+    let header = serde_json::to_vec(metadata)?;
+    let prefix = frame_prefix(&header, &body);
 
-    // Send end-of-transmission signal
-    stream.write_all(b"END").await?;
+    println!("Starting to send the image ({} bytes)...", body.len());
+    stream.write_all(&prefix).await?;
+    stream.write_all(&header).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
     println!("Image transfer complete");
 
     Ok(())
 }
 
-async fn get_notifications(api_base_url: &str, client_id: &str) -> Result<(), Box<dyn Error>> {
+async fn get_notifications(api_base_url: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
     let client = Client::new();
     let response = client
         .get(format!("{}/get_notifications", api_base_url))
-        .query(&[("client_id", client_id)])
+        .query(&[("client_id", client_id), ("password", password)])
         .send()
         .await?;
 
@@ -955,36 +2336,50 @@ async fn get_notifications(api_base_url: &str, client_id: &str) -> Result<(), Bo
     Ok(())
 }
 
-pub async fn receive_image_save(server_port: u16, output_file_path: &str) ->Result<(), Box<dyn Error + Send + Sync>> {
-    let listener = TcpListener::bind(("0.0.0.0", server_port)).await?;
-    println!("Listening on port {}", server_port);
+/// Receives one frame written by `send_image` on an already bound listener, verifies its SHA-256 and saves the body.
+/// Returns the metadata header. Nothing is left at `output_file_path` if the digest does not match.
+pub async fn receive_image_save(listener: TcpListener, output_file_path: &str) ->Result<Value, Box<dyn Error + Send + Sync>> {
+    println!("Listening on port {}", listener.local_addr()?.port());
 
-    // Accept a connection
-    let (mut socket, _) = listener.accept().await?;
+    // Accept a connection, an owner who never replies must not keep the listener around forever
+    let (mut socket, _) = timeout(REPLY_WAIT_TIMEOUT, listener.accept())
+        .await
+        .map_err(|_| "Timed out waiting for the owner's reply, it will be left on the DOS")??;
     println!("Client connected");
 
-    let mut file = File::create(output_file_path).await?;
-    let mut buffer = [0u8; 1024];
+    let mut prefix = [0u8; 4 + 1 + 4 + 8 + 32];
+    socket.read_exact(&mut prefix).await?;
+    if &prefix[..4] != FRAME_MAGIC {
+        return Err("Not an image frame: bad magic".into());
+    }
+    if prefix[4] != FRAME_VERSION {
+        return Err(format!("Unsupported image frame version {}", prefix[4]).into());
+    }
+    let header_len = u32::from_be_bytes(prefix[5..9].try_into()?) as usize;
+    let body_len = u64::from_be_bytes(prefix[9..17].try_into()?);
+    let expected_digest = &prefix[17..];
+    if header_len > MAX_FRAME_HEADER_LEN || body_len > MAX_FRAME_BODY_LEN {
+        return Err(format!("Image frame too large: header {} bytes, body {} bytes", header_len, body_len).into());
+    }
 
-    loop {
-        let bytes_read = socket.read(&mut buffer).await?;
-        if bytes_read == 0 {
-            break; // Connection closed
-        }
+    let mut header = vec![0u8; header_len];
+    socket.read_exact(&mut header).await?;
+    let metadata: Value = serde_json::from_slice(&header)?;
 
-        // Check for the end-of-transmission signal
-        if &buffer[..bytes_read] == b"END" {
-            println!("End of transmission received");
-            break;
-        }
+    let mut body = vec![0u8; body_len as usize];
+    socket.read_exact(&mut body).await?;
+    println!("Received {} bytes", body_len);
 
-        file.write_all(&buffer[..bytes_read]).await?;
-        println!("Received and wrote {} bytes", bytes_read);
+    if Sha256::digest(&body).as_slice() != expected_digest {
+        return Err("Image frame digest mismatch: the image was corrupted or tampered with in transit".into());
     }
 
+    let mut file = File::create(output_file_path).await?;
+    file.write_all(&body).await?;
+    file.flush().await?;
     println!("Image received and saved to {}", output_file_path);
 
-    Ok(())
+    Ok(metadata)
 }
 
 async fn login(leader_address: &str) -> Result<(String, String, String), Box<dyn std::error::Error>> {
@@ -1062,6 +2457,10 @@ async fn login(leader_address: &str) -> Result<(String, String, String), Box<dyn
                         match update_response {
                             Ok(response) => {
                                 println!("IP updated successfully: {}", response);
+                                // Make sure the DOS has the public key of this machine's keypair
+                                if let Err(err) = publish_public_key(&dos_address, &client_id, &password).await {
+                                    eprintln!("Failed to publish public key: {}", err);
+                                }
                                 // Return the details
                                 return Ok((dos_address, client_id, password));
                             }
@@ -1106,7 +2505,7 @@ async fn login(leader_address: &str) -> Result<(String, String, String), Box<dyn
 
 
 
-async fn view_gallery(leader_address: &str) -> Result<(), Box<dyn Error>> {
+async fn view_gallery(leader_address: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
     let client = Client::new();
     let parts: Vec<&str> = leader_address.split(':').collect();
     let leader_host = parts.get(0).unwrap_or(&"");
@@ -1159,13 +2558,8 @@ async fn view_gallery(leader_address: &str) -> Result<(), Box<dyn Error>> {
                 io::stdout().flush()?;
                 io::stdin().read_line(&mut views)?;
                 let views = views.trim().parse::<i32>().unwrap();
-                let mut id = String::new();
-                print!("Enter your client id for confirmation: ");
-                io::stdout().flush()?;
-                io::stdin().read_line(&mut id)?;
-                let id = id.trim().to_string();
 
-                send_message_to_client(&client_ip, &image_name,&id,views,&client_name,&dos_address).await?;
+                send_message_to_client(&client_ip, &image_name, client_id, password, views, &client_name, &dos_address).await?;
             },
             "2" => break,
             _ => println!("Invalid choice! Please select again."),
@@ -1315,6 +2709,37 @@ async fn get_local_ip() -> Result<IpAddr, Box<dyn Error>> {
     Ok(local_addr.ip())
 }
 
+/// Sets one viewer's views on an image and, optionally, when their access starts and ends.
+async fn change_viewer_access(dos_address: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    let mut image_name = String::new();
+    print!("Image name: ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut image_name)?;
+    let mut viewer = String::new();
+    print!("Viewer: ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut viewer)?;
+    let mut views = String::new();
+    print!("Number of views: ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut views)?;
+    let views: u32 = views.trim().parse().map_err(|_| "Invalid number of views")?;
+    let access_window = read_access_window()?.ok_or("Invalid number of hours")?;
+
+    let viewer = viewer.trim().to_string();
+    modify_access(
+        &Client::new(),
+        dos_address,
+        client_id,
+        password,
+        image_name.trim(),
+        HashMap::from([(viewer.clone(), views)]),
+        HashMap::from([(viewer, access_window)]),
+    )
+    .await?;
+    Ok(())
+}
+
 pub async fn modify_access(
     client: &Client,
     server_url: &str,
@@ -1322,12 +2747,14 @@ pub async fn modify_access(
     password: &str,
     image_name: &str,
     access_rights: HashMap<String, u32>,
+    access_windows: HashMap<String, AccessWindow>,
 ) -> Result<(), reqwest::Error> {
     let body = json!({
         "client_id": client_id,
         "password": password,
         "image_name": image_name,
-        "access_rights": access_rights
+        "access_rights": access_rights,
+        "access_windows": access_windows
     });
 
     let response = client
@@ -1465,97 +2892,624 @@ async fn request_leader(socket: &UdpSocket) -> Result<String, Box<dyn Error>> {
     }
 }
 
-async fn middleware_encrypt(socket: &UdpSocket, leader_addr: &str,image_path: &str) -> tokio::io::Result<()> {
-    
-    let mut file = File::open(image_path).await?;
-    let mut buffer = [0u8; CHUNK_SIZE];
-    let mut chunk_number: u32 = 0;
+/// A scheme for hiding bytes in the pixels of a cover image.
+/// Every hidden stream starts with `STEGO_HEADER_LEN` bytes: the codec's version byte and
+/// the body length (u32 BE), so the decoder can tell which codec wrote an image.
+trait StegoCodec {
+    fn version(&self) -> u8;
+    fn name(&self) -> &'static str;
+    /// Number of bytes, header included, the codec can hide in a `width` x `height` cover.
+    fn capacity(&self, width: u32, height: u32) -> usize;
+    /// Hides `stream` in the cover at `cover_path` and saves the result as a PNG at `output_path`.
+    fn embed(&self, cover_path: &str, output_path: &str, stream: &[u8], key: &[u8]) -> Result<(), Box<dyn Error>>;
+    /// Reads back the first `len` bytes hidden in the image at `image_path`.
+    fn extract(&self, image_path: &str, key: &[u8], len: usize) -> Result<Vec<u8>, Box<dyn Error>>;
+}
 
-    // Notify the server about the image transfer
-    socket.send_to(b"IMAGE_TRANSFER", leader_addr).await?;
-    println!("Client: Starting image transfer to {}", leader_addr);
+/// The original scheme of the `steganography` crate: one byte per pixel in the alpha channel.
+struct AlphaCodec;
 
-    // Send the image in chunks
-    while let Ok(bytes_read) = file.read(&mut buffer).await {
-        if bytes_read == 0 { break; }
+impl StegoCodec for AlphaCodec {
+    fn version(&self) -> u8 {
+        1
+    }
 
-        loop {
-            let packet = [&chunk_number.to_be_bytes(), &buffer[..bytes_read]].concat();
-            socket.send_to(&packet, leader_addr).await?;
-            if receive_ack(socket).await { break; } // Wait for ACK before proceeding
-            sleep(Duration::from_millis(100)).await; // Retry if no ACK
-        }
-        chunk_number += 1;
+    fn name(&self) -> &'static str {
+        "alpha"
     }
 
-    // Signal end of transfer
-    socket.send_to(b"END", leader_addr).await?;
-    println!("Client: Image transfer complete!");
+    fn capacity(&self, width: u32, height: u32) -> usize {
+        width as usize * height as usize
+    }
 
-    Ok(())
-}
+    fn embed(&self, cover_path: &str, output_path: &str, stream: &[u8], _key: &[u8]) -> Result<(), Box<dyn Error>> {
+        let (width, height) = image::image_dimensions(cover_path)?;
+        if stream.len() > self.capacity(width, height) {
+            return Err("Stream does not fit in the cover".into());
+        }
+        let cover_image = file_as_dynamic_image(cover_path.to_string());
+        let encoder = Encoder::new(stream, cover_image);
+        save_image_buffer(encoder.encode_alpha(), output_path.to_string());
+        Ok(())
+    }
 
-async fn receive_ack(socket: &UdpSocket) -> bool {
-    let mut buffer = [0; 1024];
+    fn extract(&self, image_path: &str, _key: &[u8], len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let encoded_image = file_as_dynamic_image(image_path.to_string()).to_rgba();
+        let decoder = Decoder::new(encoded_image);
+        let mut stream = decoder.decode_alpha();
+        if stream.len() < len {
+            return Err("Hidden stream is truncated".into());
+        }
+        stream.truncate(len);
+        Ok(stream)
+    }
+}
 
-    match timeout(Duration::from_secs(2), socket.recv_from(&mut buffer)).await {
-        Ok(Ok((len, _))) => {
-            let response = String::from_utf8_lossy(&buffer[..len]);
-            return response.trim() == "ACK";
-        },
-        _ => false, // Timeout or error
+/// Hides bits in the least significant bit of the R, G and B channels.
+/// Pixels are visited in an order derived from the key, so the bits are spread over the
+/// whole cover and cannot be read back in order without the key.
+struct LsbRgbCodec;
+
+impl LsbRgbCodec {
+    fn pixel_order(key: &[u8], pixel_count: u32) -> PixelOrder {
+        let mut hasher = Sha256::new();
+        hasher.update(b"Distributed_Project pixel permutation");
+        hasher.update(key);
+        PixelOrder {
+            rng: StdRng::from_seed(hasher.finalize().into()),
+            moved: HashMap::new(),
+            next: 0,
+            pixel_count,
+        }
     }
 }
 
-/// Receive and decode an image from the server.
-async fn middleware_decrypt(socket: &UdpSocket) -> Result<(), Box<dyn Error>> {
-    receive_image(socket).await?;
-    decode_image("encoded_image_received.png","original.png").await?;
-    Ok(())
+/// A random permutation of `0..pixel_count`, produced one index at a time by a front-to-back
+/// Fisher-Yates shuffle. Only the positions the swaps touched are stored, so the memory used
+/// grows with the pixels visited rather than with the size of the cover.
+struct PixelOrder {
+    rng: StdRng,
+    moved: HashMap<u32, u32>, // Position -> pixel now there, for positions a swap changed
+    next: u32,
+    pixel_count: u32,
 }
 
-/// Decode the received image and save the decoded output.
-async fn decode_image(file_path: &str, output_path: &str) -> Result<(), Box<dyn Error>> {
-    let encoded_image = file_as_dynamic_image(file_path.to_string()).to_rgba();
-    let decoder = Decoder::new(encoded_image);
+impl Iterator for PixelOrder {
+    type Item = u32;
 
-    let decoded_data = decoder.decode_alpha();
-    //let output_path = "decoded_output.png";
-    std::fs::write(output_path, &decoded_data)?;
-    println!("Image decoded and saved to {}", output_path);
-    Ok(())
+    fn next(&mut self) -> Option<u32> {
+        if self.next >= self.pixel_count {
+            return None;
+        }
+        let position = self.next;
+        let swap_with = self.rng.gen_range(position..self.pixel_count);
+        let pixel = self.moved.remove(&swap_with).unwrap_or(swap_with);
+        if swap_with != position {
+            let displaced = self.moved.remove(&position).unwrap_or(position);
+            self.moved.insert(swap_with, displaced);
+        }
+        self.next += 1;
+        Some(pixel)
+    }
 }
 
-/// Receive image data from the server in chunks and save it as a PNG file.
-async fn receive_image(socket: &UdpSocket) -> tokio::io::Result<()> {
-    let mut file = File::create("encoded_image_received.png").await?;
-    let mut buffer = [0u8; CHUNK_SIZE + 4]; // 4 extra bytes for chunk number
-    let mut expected_chunk_number: u32 = 0;
+impl StegoCodec for LsbRgbCodec {
+    fn version(&self) -> u8 {
+        2
+    }
 
-    println!("Client: Waiting to receive encrypted image...");
+    fn name(&self) -> &'static str {
+        "lsb-rgb"
+    }
 
-    loop {
-        let (bytes_received, addr) = socket.recv_from(&mut buffer).await?;
+    fn capacity(&self, width: u32, height: u32) -> usize {
+        width as usize * height as usize * 3 / 8
+    }
 
-        if &buffer[..bytes_received] == b"END" {
-            println!("Client: Received end of transmission signal.");
-            break;
+    fn embed(&self, cover_path: &str, output_path: &str, stream: &[u8], key: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut cover = image::open(cover_path)?.to_rgba8();
+        if stream.len() > self.capacity(cover.width(), cover.height()) {
+            return Err("Stream does not fit in the cover".into());
+        }
+        let order = Self::pixel_order(key, cover.width() * cover.height());
+        let width = cover.width();
+
+        let bits: Vec<u8> = stream
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |shift| (byte >> shift) & 1))
+            .collect();
+        for (pixel_index, pixel_bits) in order.zip(bits.chunks(3)) {
+            let pixel = cover.get_pixel_mut(pixel_index % width, pixel_index / width);
+            for (channel, bit) in pixel_bits.iter().enumerate() {
+                pixel.0[channel] = (pixel.0[channel] & !1) | bit;
+            }
+        }
+
+        cover.save_with_format(output_path, image::ImageFormat::Png)?;
+        Ok(())
+    }
+
+    fn extract(&self, image_path: &str, key: &[u8], len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let image = image::open(image_path)?.to_rgba8();
+        if len > self.capacity(image.width(), image.height()) {
+            return Err("Hidden stream is truncated".into());
+        }
+        let order = Self::pixel_order(key, image.width() * image.height());
+        let width = image.width();
+
+        let mut stream = vec![0u8; len];
+        let bit_count = len * 8;
+        for (visited, pixel_index) in order.take(bit_count.div_ceil(3)).enumerate() {
+            let pixel = image.get_pixel(pixel_index % width, pixel_index / width);
+            for channel in 0..3 {
+                let bit_index = visited * 3 + channel;
+                if bit_index >= bit_count {
+                    break;
+                }
+                stream[bit_index / 8] |= (pixel.0[channel] & 1) << (7 - bit_index % 8);
+            }
         }
+        Ok(stream)
+    }
+}
 
-        let chunk_number = u32::from_be_bytes(buffer[..4].try_into().unwrap());
+/// Codec used for new shares.
+fn default_codec() -> Box<dyn StegoCodec> {
+    Box::new(LsbRgbCodec)
+}
 
-        if chunk_number == expected_chunk_number {
-            file.write_all(&buffer[4..bytes_received]).await?;
-            expected_chunk_number += 1;
+/// Every codec the decoder understands, tried in turn against the version byte.
+fn all_codecs() -> Vec<Box<dyn StegoCodec>> {
+    vec![Box::new(AlphaCodec), Box::new(LsbRgbCodec)]
+}
 
-            socket.send_to(ACK, addr).await?;
-            //println!("Client: Acknowledged chunk {}", chunk_number);
-        } else {
-            println!("Client: Unexpected chunk number. Expected {}, but received {}.", expected_chunk_number, chunk_number);
+/// Hides `body` behind the codec's header, refusing payloads the cover cannot hold.
+fn hide_payload(codec: &dyn StegoCodec, cover_path: &str, output_path: &str, body: &[u8], key: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut stream = Vec::with_capacity(STEGO_HEADER_LEN + body.len());
+    stream.push(codec.version());
+    stream.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    stream.extend_from_slice(body);
+
+    let (width, height) = image::image_dimensions(cover_path)?;
+    let capacity = codec.capacity(width, height);
+    if stream.len() > capacity {
+        return Err(format!(
+            "Payload of {} bytes exceeds the {} byte {} capacity of cover {} ({}x{})",
+            stream.len(),
+            capacity,
+            codec.name(),
+            cover_path,
+            width,
+            height
+        )
+        .into());
+    }
+
+    codec.embed(cover_path, output_path, &stream, key)
+}
+
+/// Finds the codec whose version byte heads the hidden stream and returns the body.
+fn reveal_payload(image_path: &str, key: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    for codec in all_codecs() {
+        let header = match codec.extract(image_path, key, STEGO_HEADER_LEN) {
+            Ok(header) => header,
+            Err(_) => continue,
+        };
+        if header[0] != codec.version() {
+            continue;
+        }
+
+        let body_len = u32::from_be_bytes(header[1..STEGO_HEADER_LEN].try_into()?) as usize;
+        if let Ok(stream) = codec.extract(image_path, key, STEGO_HEADER_LEN + body_len) {
+            println!("Hidden payload found using the {} codec", codec.name());
+            return Ok(stream[STEGO_HEADER_LEN..].to_vec());
+        }
+    }
+
+    Err("No hidden payload found: unknown codec or wrong key".into())
+}
+
+/// Encrypts `plaintext` with ChaCha20-Poly1305, laid out as `nonce (12 bytes) | ciphertext`.
+fn encrypt_payload(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let cipher = ChaCha20Poly1305::new_from_slice(key).map_err(|_| "Shared key must be 32 bytes")?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| "Failed to encrypt payload")?;
+
+    let mut payload = Vec::with_capacity(nonce.len() + ciphertext.len());
+    payload.extend_from_slice(&nonce);
+    payload.extend_from_slice(&ciphertext);
+    Ok(payload)
+}
+
+/// Size of the stream hidden for `plaintext_len` bytes of image:
+/// stego header, nonce and the Poly1305 tag on top of the ciphertext.
+fn hidden_stream_len(plaintext_len: usize) -> usize {
+    STEGO_HEADER_LEN + 12 + plaintext_len + 16
+}
+
+/// Upscales `cover` just enough for `codec` to hold `stream_len` bytes.
+/// Fails when that would exceed `MAX_COVER_DIMENSION`.
+fn fit_cover(codec: &dyn StegoCodec, cover: DynamicImage, stream_len: usize) -> Result<DynamicImage, String> {
+    let (width, height) = (cover.width(), cover.height());
+    if codec.capacity(width, height) >= stream_len {
+        return Ok(cover);
+    }
+
+    let scale = (stream_len as f64 / codec.capacity(width, height).max(1) as f64).sqrt();
+    let new_width = (width as f64 * scale).ceil() as u32 + 1;
+    let new_height = (height as f64 * scale).ceil() as u32 + 1;
+    if new_width > MAX_COVER_DIMENSION || new_height > MAX_COVER_DIMENSION {
+        return Err(format!(
+            "Payload of {} bytes exceeds the {} capacity of any cover up to {}x{} pixels",
+            stream_len, codec.name(), MAX_COVER_DIMENSION, MAX_COVER_DIMENSION
+        ));
+    }
+
+    println!("Upscaling cover from {}x{} to {}x{} to fit the payload", width, height, new_width, new_height);
+    Ok(cover.resize_exact(new_width, new_height, image::imageops::FilterType::Triangle))
+}
+
+/// Lists the covers the DOS offers to this client: the shared library plus its own uploads.
+async fn list_covers(dos_address: &str, client_id: &str, password: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .get(format!("{}/list_covers", dos_address))
+        .query(&[("client_id", client_id), ("password", password)])
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    match response_body {
+        Value::Array(covers) => Ok(covers),
+        _ => Err(format!("Failed to list covers: {}", response_body).into()),
+    }
+}
+
+async fn fetch_cover(dos_address: &str, client_id: &str, password: &str, owner: &str, cover_name: &str) -> Result<DynamicImage, Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .get(format!("{}/get_cover", dos_address))
+        .query(&[("client_id", client_id), ("password", password), ("owner", owner), ("cover_name", cover_name)])
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    let cover_data = response_body
+        .get("data")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| format!("Failed to fetch cover: {}", response_body))?;
+    Ok(image::load_from_memory(&general_purpose::STANDARD.decode(cover_data)?)?)
+}
+
+/// Capacity of a cover listed by the DOS under the codec used for new shares.
+fn listed_cover_capacity(cover: &Value) -> usize {
+    let width = cover.get("width").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    let height = cover.get("height").and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    default_codec().capacity(width, height)
+}
+
+/// Picks the cover for sharing `image_name` and saves it to `output_path`.
+/// The owner's choice for the image wins, otherwise the smallest library cover that fits is used,
+/// otherwise the largest one is upscaled. `mask.jpg` is only used when the library is empty.
+async fn prepare_cover(dos_address: &str, client_id: &str, password: &str, image_name: &str, stream_len: usize, output_path: &str) -> Result<String, String> {
+    let chosen = ShareCovers::load_from_file(SHARE_COVERS_PATH).covers.get(image_name).cloned();
+
+    let cover = if let Some(choice) = chosen {
+        fetch_cover(dos_address, client_id, password, &choice.owner, &choice.name).await.map_err(|e| e.to_string())?
+    } else {
+        let covers = list_covers(dos_address, client_id, password).await.map_err(|e| e.to_string())?;
+        let best = covers
+            .iter()
+            .filter(|cover| listed_cover_capacity(cover) >= stream_len)
+            .min_by_key(|cover| listed_cover_capacity(cover))
+            .or_else(|| covers.iter().max_by_key(|cover| listed_cover_capacity(cover)));
+
+        match best {
+            Some(cover) => {
+                let owner = cover.get("owner").and_then(|v| v.as_str()).unwrap_or_default();
+                let name = cover.get("name").and_then(|v| v.as_str()).unwrap_or_default();
+                fetch_cover(dos_address, client_id, password, owner, name).await.map_err(|e| e.to_string())?
+            }
+            None => image::open(COVER_IMAGE_PATH).map_err(|e| e.to_string())?,
         }
+    };
+
+    fit_cover(default_codec().as_ref(), cover, stream_len)?
+        .save_with_format(output_path, image::ImageFormat::Png)
+        .map_err(|e| e.to_string())?;
+    Ok(output_path.to_string())
+}
+
+/// Lets the owner pick which cover will be used when sharing one of their images.
+async fn choose_cover_for_image(dos_address: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    let covers = list_covers(dos_address, client_id, password).await?;
+    if covers.is_empty() {
+        println!("No covers available. Add one first.");
+        return Ok(());
+    }
+
+    for (index, cover) in covers.iter().enumerate() {
+        println!(
+            "{}. {} ({}), {}x{}, holds {} bytes",
+            index + 1,
+            cover.get("name").and_then(|v| v.as_str()).unwrap_or_default(),
+            cover.get("owner").and_then(|v| v.as_str()).unwrap_or_default(),
+            cover.get("width").and_then(|v| v.as_u64()).unwrap_or(0),
+            cover.get("height").and_then(|v| v.as_u64()).unwrap_or(0),
+            listed_cover_capacity(cover)
+        );
     }
 
-    println!("Client: Image successfully received and saved as PNG.");
+    let mut image_name = String::new();
+    print!("Enter image name: ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut image_name)?;
+    let image_name = image_name.trim().to_string();
+
+    let mut choice = String::new();
+    print!("Enter the number of the cover: ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut choice)?;
+    let choice: usize = choice.trim().parse().unwrap_or(0);
+
+    if choice == 0 || choice > covers.len() {
+        println!("Invalid choice!");
+        return Ok(());
+    }
+
+    let cover = &covers[choice - 1];
+    let mut share_covers = ShareCovers::load_from_file(SHARE_COVERS_PATH);
+    share_covers.covers.insert(
+        image_name.clone(),
+        CoverChoice {
+            owner: cover.get("owner").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+            name: cover.get("name").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+        },
+    );
+    share_covers.save_to_file(SHARE_COVERS_PATH)?;
+    println!("Cover selected for {}", image_name);
+    Ok(())
+}
+
+/// Tiles "viewer · time" across the image in semi-transparent text and saves it as PNG.
+fn apply_visible_watermark(image_path: &str, output_path: &str, viewer: &str) -> Result<(), Box<dyn Error>> {
+    let original = open(image_path)?.to_rgba8();
+    let font = Font::try_from_vec(std::fs::read(FONT_PATH)?).ok_or("Failed to load font")?;
+
+    let (width, height) = original.dimensions();
+    let text = format!("{} · {}", viewer, chrono::Utc::now().format("%Y-%m-%d %H:%M UTC"));
+    let font_size = (width.min(height) as f32 / 20.0).max(12.0);
+    let scale = Scale::uniform(font_size);
+    let step_x = (font_size * text.chars().count() as f32 * 0.6) as u32 + font_size as u32 * 2;
+    let step_y = font_size as u32 * 4;
+
+    // Draw the text opaque on a copy, then blend the copy over the original
+    let mut marked = original.clone();
+    for (row, y) in (0..height).step_by(step_y.max(1) as usize).enumerate() {
+        // Shift every other row so the tiles don't line up in columns
+        let offset = if row % 2 == 0 { 0 } else { step_x / 2 };
+        for x in (0..width + step_x).step_by(step_x.max(1) as usize) {
+            draw_text_mut(
+                &mut marked,
+                Rgba([255, 255, 255, 255]),
+                x as i32 - offset as i32,
+                y as i32,
+                scale,
+                &font,
+                &text,
+            );
+        }
+    }
+
+    let mut watermarked = original.clone();
+    for (x, y, pixel) in watermarked.enumerate_pixels_mut() {
+        let mark = marked.get_pixel(x, y);
+        for channel in 0..3 {
+            pixel[channel] = (pixel[channel] as f32 * (1.0 - WATERMARK_OPACITY) + mark[channel] as f32 * WATERMARK_OPACITY) as u8;
+        }
+    }
+    watermarked.save_with_format(output_path, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+/// Assigns every grid cell to one bit of the forensic ID with a +1 or -1 chip.
+/// Cells of one bit are spread over the whole image, so local damage only weakens it.
+fn forensic_chips() -> Vec<(usize, f32)> {
+    let cell_count = (FORENSIC_GRID * FORENSIC_GRID) as usize;
+    let mut rng = StdRng::seed_from_u64(FORENSIC_SEED);
+    let mut order: Vec<usize> = (0..cell_count).collect();
+    order.shuffle(&mut rng);
+
+    let mut chips = vec![(0, 0.0); cell_count];
+    for (index, cell) in order.into_iter().enumerate() {
+        chips[cell] = (index % FORENSIC_BITS, if rng.gen::<bool>() { 1.0 } else { -1.0 });
+    }
+    chips
+}
+
+/// Below one pixel per cell some cells would be empty and their bits unreadable.
+fn check_forensic_size(width: u32, height: u32) -> Result<(), Box<dyn Error>> {
+    if width < FORENSIC_GRID || height < FORENSIC_GRID {
+        return Err(format!(
+            "Image is {}x{}, the forensic watermark needs at least {}x{} pixels",
+            width, height, FORENSIC_GRID, FORENSIC_GRID
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn forensic_cell(x: u32, y: u32, width: u32, height: u32) -> usize {
+    let column = (x as u64 * FORENSIC_GRID as u64 / width as u64) as usize;
+    let row = (y as u64 * FORENSIC_GRID as u64 / height as u64) as usize;
+    row * FORENSIC_GRID as usize + column
+}
+
+/// Embeds `watermark_id` spread-spectrum style: each cell's luminance moves slightly up or down
+/// depending on its chip and the bit it carries. The grid is relative to the image size, so the
+/// ID is still there after resizing, and cell averages survive JPEG re-compression.
+fn apply_forensic_watermark(
+    image_path: &str,
+    output_path: &str,
+    format: image::ImageFormat,
+    watermark_id: u32,
+) -> Result<(), Box<dyn Error>> {
+    let mut image = open(image_path)?.to_rgba8();
+    let (width, height) = image.dimensions();
+    check_forensic_size(width, height)?;
+    let chips = forensic_chips();
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (bit, chip) = chips[forensic_cell(x, y, width, height)];
+        let bit_sign = if watermark_id >> bit & 1 == 1 { 1.0 } else { -1.0 };
+        let delta = FORENSIC_STRENGTH * bit_sign * chip;
+        for channel in 0..3 {
+            pixel[channel] = (pixel[channel] as f32 + delta).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    if format == image::ImageFormat::Jpeg {
+        let mut output = std::io::BufWriter::new(std::fs::File::create(output_path)?);
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output, FORENSIC_JPEG_QUALITY)
+            .encode_image(&DynamicImage::ImageRgba8(image).to_rgb8())?;
+    } else {
+        image.save_with_format(output_path, image::ImageFormat::Png)?;
+    }
+    Ok(())
+}
+
+/// JPEG sources are marked as JPEG so the copy stays about the size of the original, anything
+/// else is written as PNG.
+fn forensic_output_format(image_path: &str) -> image::ImageFormat {
+    match ImageReader::open(image_path).and_then(|reader| reader.with_guessed_format()).map(|reader| reader.format()) {
+        Ok(Some(image::ImageFormat::Jpeg)) => image::ImageFormat::Jpeg,
+        _ => image::ImageFormat::Png,
+    }
+}
+
+/// Recovers the forensic ID from a copy, with the weakest bit's margin as a rough confidence.
+fn detect_forensic_watermark(image_path: &str) -> Result<(u32, f32), Box<dyn Error>> {
+    let image = open(image_path)?.to_rgba8();
+    let (width, height) = image.dimensions();
+    check_forensic_size(width, height)?;
+    let grid = FORENSIC_GRID as usize;
+
+    // Average luminance per cell
+    let mut sums = vec![0.0f64; grid * grid];
+    let mut counts = vec![0u32; grid * grid];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let cell = forensic_cell(x, y, width, height);
+        sums[cell] += 0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64;
+        counts[cell] += 1;
+    }
+    let means: Vec<f64> = sums.iter().zip(&counts).map(|(sum, count)| sum / (*count).max(1) as f64).collect();
+
+    // Subtract the average of each cell's neighbours so the picture itself mostly cancels out
+    let chips = forensic_chips();
+    let mut correlations = vec![0.0f64; FORENSIC_BITS];
+    for row in 0..grid {
+        for column in 0..grid {
+            let mut neighbourhood = 0.0;
+            let mut neighbours = 0.0;
+            for r in row.saturating_sub(1)..(row + 2).min(grid) {
+                for c in column.saturating_sub(1)..(column + 2).min(grid) {
+                    if r == row && c == column {
+                        continue;
+                    }
+                    neighbourhood += means[r * grid + c];
+                    neighbours += 1.0;
+                }
+            }
+            let cell = row * grid + column;
+            let residual = means[cell] - neighbourhood / neighbours;
+            let (bit, chip) = chips[cell];
+            correlations[bit] += residual * chip as f64;
+        }
+    }
+
+    let mut watermark_id = 0u32;
+    for (bit, correlation) in correlations.iter().enumerate() {
+        if *correlation > 0.0 {
+            watermark_id |= 1 << bit;
+        }
+    }
+    let cells_per_bit = (grid * grid / FORENSIC_BITS) as f64;
+    let confidence = correlations
+        .iter()
+        .map(|correlation| correlation.abs() / (cells_per_bit * FORENSIC_STRENGTH as f64))
+        .fold(f64::INFINITY, f64::min);
+    Ok((watermark_id, confidence as f32))
+}
+
+/// `client watermark-detect <path>`: prints the forensic ID of a leaked copy and, if this owner
+/// embedded it, who it was delivered to.
+fn run_watermark_detect(image_path: &str) -> Result<(), Box<dyn Error>> {
+    let (watermark_id, confidence) = detect_forensic_watermark(image_path)?;
+    let watermark_key = format!("{:08x}", watermark_id);
+    println!("Watermark ID: {} (confidence {:.2})", watermark_key, confidence);
+    if confidence < 0.1 {
+        println!("Confidence is low, the image probably carries no watermark or was altered heavily.");
+    }
+
+    match ForensicWatermarks::load_from_file(FORENSIC_WATERMARKS_PATH).watermarks.get(&watermark_key) {
+        Some(watermark) => println!(
+            "Delivered to {} for image {} (request {})",
+            watermark.viewer, watermark.image_name, watermark.request_id
+        ),
+        None => println!("No delivery with this ID is recorded in {}", FORENSIC_WATERMARKS_PATH),
+    }
+    Ok(())
+}
+
+/// Lets the owner turn the visible watermark on or off for one of their images.
+fn toggle_watermark_for_image() -> Result<(), Box<dyn Error>> {
+    let mut image_name = String::new();
+    print!("Enter image name: ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut image_name)?;
+    let image_name = image_name.trim().to_string();
+
+    let mut settings = WatermarkSettings::load_from_file(WATERMARK_SETTINGS_PATH);
+    let enabled = !settings.is_enabled(&image_name);
+    settings.images.insert(image_name.clone(), enabled);
+    settings.save_to_file(WATERMARK_SETTINGS_PATH)?;
+    println!(
+        "Visible watermark {} for {}",
+        if enabled { "enabled" } else { "disabled" },
+        image_name
+    );
+    Ok(())
+}
+
+/// Encrypt the image for the viewer and hide it in the cover image with the default codec.
+fn encode_image(image_path: &str, cover_path: &str, output_path: &str, key: &[u8]) -> Result<(), Box<dyn Error>> {
+    let payload = encrypt_payload(key, &std::fs::read(image_path)?)?;
+    let codec = default_codec();
+    hide_payload(codec.as_ref(), cover_path, output_path, &payload, key)?;
+    println!("Image encrypted and encoded into {} using the {} codec", output_path, codec.name());
+    Ok(())
+}
+
+/// Decrypts a payload laid out as `nonce (12 bytes) | ciphertext`.
+fn decrypt_payload(key: &[u8], payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if payload.len() <= 12 {
+        return Err("Encoded payload is truncated".into());
+    }
+    let (nonce, ciphertext) = payload.split_at(12);
+
+    let cipher = ChaCha20Poly1305::new_from_slice(key).map_err(|_| "Shared key must be 32 bytes")?;
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Failed to decrypt image: wrong key or tampered payload".into())
+}
+
+/// Decode the received image, decrypt it with the shared key and save the decoded output.
+async fn decode_image(file_path: &str, output_path: &str, key: &[u8]) -> Result<(), Box<dyn Error>> {
+    let decoded_data = decrypt_payload(key, &reveal_payload(file_path, key)?)?;
+    //let output_path = "decoded_output.png";
+    std::fs::write(output_path, &decoded_data)?;
+    println!("Image decoded and saved to {}", output_path);
     Ok(())
 }
 
@@ -1596,6 +3550,47 @@ pub async fn add_image_to_dos(
     }
 }
 
+pub async fn add_cover_to_dos(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    cover_path: &str,
+    shared: bool,
+) -> Result<String, Box<dyn Error>> {
+    let client = Client::new();
+
+    // Covers are uploaded at full size, their resolution is what gives them capacity
+    let cover_name = Path::new(cover_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or("Cover path has no file name")?;
+    let payload = json!({
+        "client_id": client_id,
+        "password": password,
+        "cover_name": cover_name,
+        "cover_data": general_purpose::STANDARD.encode(std::fs::read(cover_path)?),
+        "shared": shared
+    });
+
+    let response = client
+        .post(format!("{}/add_cover", dos_address))
+        .json(&payload)
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        let response_text = response.text().await?;
+        Ok(response_text)
+    } else {
+        Err(format!(
+            "Failed to add cover (status: {}): {}",
+            response.status(),
+            response.text().await.unwrap_or_else(|_| "No response body".to_string())
+        )
+        .into())
+    }
+}
+
 fn resize_image(image_path: &str) -> Result<DynamicImage, Box<dyn Error>> {
     let image = ImageReader::open(image_path)?.decode()?;
     let resized_image = image.resize_exact(200, 200, image::imageops::FilterType::Triangle);
@@ -1618,7 +3613,9 @@ pub async fn register_client(
 
     let payload = json!({
         "id": client_id,
-        "password": password
+        "password": password,
+        "public_key": ClientIdentity::public_key(client_id)?,
+        "signing_key": ClientIdentity::verifying_key(client_id)?
     });
 
     let response = client
@@ -1672,7 +3669,14 @@ pub async fn delete_image_from_dos(
         .into())
     }
 }
-async fn embed_views_metadata(input_file_path: &str, output_file_path: &str, views: u32) -> Result<(), Box<dyn Error>> {
+/// Writes the access metadata and the owner's signature over it as `AccessMetadata` and
+/// `AccessSignature` text chunks.
+async fn embed_views_metadata(
+    input_file_path: &str,
+    output_file_path: &str,
+    metadata: &AccessMetadata,
+    signing_key: &SigningKey,
+) -> Result<(), Box<dyn Error>> {
     // Open the input file asynchronously
     let mut input_file = File::open(input_file_path).await?;
     let mut input_buffer = Vec::new();
@@ -1699,9 +3703,11 @@ async fn embed_views_metadata(input_file_path: &str, output_file_path: &str, vie
         encoder.set_color(reader.info().color_type);
         encoder.set_depth(reader.info().bit_depth);
 
-        // Add metadata (text chunk) for views
-        let views2 = "Views".to_string(); // Convert to String
-        encoder.add_text_chunk(views2, views.to_string())?;
+        // Add metadata (text chunk) for views, signed over the exact JSON text
+        let metadata_json = serde_json::to_string(metadata)?;
+        let signature = signing_key.sign(metadata_json.as_bytes());
+        encoder.add_text_chunk("AccessMetadata".to_string(), metadata_json)?;
+        encoder.add_text_chunk("AccessSignature".to_string(), general_purpose::STANDARD.encode(signature.to_bytes()))?;
 
         let mut writer = encoder.write_header()?;
         writer.write_image_data(&image_buffer)?;
@@ -1711,10 +3717,12 @@ async fn embed_views_metadata(input_file_path: &str, output_file_path: &str, vie
     let mut output_file = File::create(output_file_path).await?;
     output_file.write_all(output_cursor.get_ref()).await?;
 
-    println!("Embedded {} views in metadata and saved to {}", views, output_file_path);
+    println!("Embedded {} views in signed metadata and saved to {}", metadata.views, output_file_path);
     Ok(())
 }
-async fn extract_views_metadata(file_path: &str) -> Result<Option<u32>, Box<dyn Error>> {
+/// Reads the access metadata of a delivery. Fails if the metadata is missing or its signature
+/// doesn't verify with the owner's key, so edited files are never decoded.
+async fn extract_views_metadata(file_path: &str, owner_key: &VerifyingKey) -> Result<AccessMetadata, Box<dyn Error>> {
     // Open the file asynchronously
     let mut input_file = File::open(file_path).await?;
     let mut input_buffer = Vec::new();
@@ -1727,21 +3735,29 @@ async fn extract_views_metadata(file_path: &str) -> Result<Option<u32>, Box<dyn
 
     // Decode the PNG
     let decoder = png::Decoder::new(&mut cursor);
-    let mut reader = decoder.read_info()?;
+    let reader = decoder.read_info()?;
 
     // Access the text chunks directly as a Vec
-    for chunk in &reader.info().uncompressed_latin1_text {
-        if chunk.keyword == "Views" {
-            if let Ok(views) = chunk.text.parse::<u32>() {
-                return Ok(Some(views));
-            } else {
-                eprintln!("Failed to parse views from metadata");
-            }
-        }
-    }
-
-    println!("No 'Views' metadata found");
-    Ok(None)
+    let text_chunk = |keyword: &str| {
+        reader
+            .info()
+            .uncompressed_latin1_text
+            .iter()
+            .find(|chunk| chunk.keyword == keyword)
+            .map(|chunk| chunk.text.clone())
+    };
+    let metadata_json = text_chunk("AccessMetadata").ok_or("No access metadata found")?;
+    let signature = text_chunk("AccessSignature").ok_or("Access metadata is not signed")?;
+
+    let signature_bytes: [u8; 64] = general_purpose::STANDARD
+        .decode(signature)?
+        .try_into()
+        .map_err(|_| "Access signature must be 64 bytes")?;
+    owner_key
+        .verify(metadata_json.as_bytes(), &Signature::from_bytes(&signature_bytes))
+        .map_err(|_| "Access metadata signature is invalid, the file was modified")?;
+
+    Ok(serde_json::from_str(&metadata_json)?)
 }
 async fn strip_metadata(input_file_path: &str, output_file_path: &str) -> Result<(), Box<dyn Error >> {
     // Open the input file asynchronously
@@ -1788,46 +3804,545 @@ async fn strip_metadata(input_file_path: &str, output_file_path: &str) -> Result
     );
     Ok(())
 }
-pub async fn process_image_metadata_and_decode(input_file_path: &str, output_file_path: &str) -> Result<(), Box<dyn Error>> {
-    // Extract metadata
-    if let Some(mut views) = extract_views_metadata(input_file_path).await? {
-        if views > 0 {
-            println!("Image has {} views. Proceeding with processing...", views);
+/// Decodes one view of a delivered image. Each view asks the DOS for a single-use key share, which
+/// takes one off the viewer's count and is given back if decoding fails. The decoded image only
+/// exists on disk while it is being viewed.
+pub async fn process_image_metadata_and_decode(dos_address: &str, client_id: &str, password: &str, input_file_path: &str, output_file_path: &str) -> Result<(), Box<dyn Error>> {
+    // Revoked images are wiped before anything is decoded
+    if let Err(err) = apply_revocations(dos_address, client_id, password).await {
+        println!("Could not check for revocations, not decoding: {}", err);
+        return Ok(());
+    }
 
-            // Strip metadata
-            let stripped_file_path = "stripped_image.png";
-            strip_metadata(input_file_path, stripped_file_path).await?;
-            println!("Metadata stripped successfully and saved to {}", stripped_file_path);
+    // Derive the key from the owner's public key recorded when this image was requested
+    let inbox_index = InboxIndex::load_from_file(INBOX_INDEX_PATH);
+    let (request_id, entry) = match inbox_index.entries.iter().find(|(_, entry)| entry.path == input_file_path) {
+        Some((request_id, entry)) => (request_id.clone(), entry.clone()),
+        None => {
+            println!("No owner recorded for {}. Cannot decrypt the image.", input_file_path);
+            return Ok(());
+        }
+    };
+    let shared_key = match shared_key_with(dos_address, client_id, &entry.owner).await {
+        Ok(shared_key) => shared_key,
+        Err(err) => {
+            println!("Could not derive the key for {}: {}", input_file_path, err);
+            return Ok(());
+        }
+    };
 
-            // Decode the image
-            decode_image(stripped_file_path, output_file_path).await?;
-            println!("Image decoded successfully and saved to {}", output_file_path);
-            views=views-1;
-            embed_views_metadata(input_file_path, "reply_image.png", views).await?;
-            
+    // Refuse files whose access terms weren't signed by the owner for this delivery
+    let owner_key = match fetch_verifying_key(dos_address, &entry.owner).await {
+        Ok(owner_key) => owner_key,
+        Err(err) => {
+            println!("Could not fetch {}'s signing key: {}", entry.owner, err);
+            return Ok(());
+        }
+    };
+    let metadata = match extract_views_metadata(input_file_path, &owner_key).await {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            println!("Refusing to decode {}: {}", input_file_path, err);
+            return Ok(());
+        }
+    };
+    if metadata.owner != entry.owner || metadata.viewer != client_id || metadata.request_id != request_id {
+        println!("Refusing to decode {}: its metadata belongs to another delivery", input_file_path);
+        return Ok(());
+    }
+    let now = unix_timestamp();
+    if metadata.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        println!("Refusing to decode {}: access expired", input_file_path);
+        return Ok(());
+    }
+    if metadata.not_before.is_some_and(|not_before| not_before > now) {
+        println!("Refusing to decode {}: access is not valid yet", input_file_path);
+        return Ok(());
+    }
+    println!("Image was delivered with {} views.", metadata.views);
 
-        } else {
-            println!("Image has 0 views. Processing not allowed.");
+    // The DOS decides whether there is a view left, not the file
+    let grant = match request_view(dos_address, client_id, password, &entry.owner, &entry.image_name, &request_id).await {
+        Ok(grant) => grant,
+        Err(err) => {
+            println!("{}", err);
+            return Ok(());
+        }
+    };
+    if grant.expires_at <= unix_timestamp() {
+        println!("Refusing to decode {}: the view grant already expired", input_file_path);
+        return Ok(());
+    }
+    let view_share = wrap_view_share(&shared_key, &request_id, grant.view_index, &grant.share);
+    let view_key = derive_view_key(&shared_key, &view_share);
+    println!("View granted, {} views left after this one. Proceeding with processing...", grant.remaining_views);
+
+    // Strip metadata and decode the image
+    let stripped_file_path = "stripped_image.png";
+    let decode_result = match strip_metadata(input_file_path, stripped_file_path).await {
+        Ok(()) => decode_image(stripped_file_path, output_file_path, &view_key).await,
+        Err(err) => Err(err),
+    };
+    let _ = std::fs::remove_file(stripped_file_path);
+
+    // A view that could not be decoded is given back, a decoded one is settled and shows in the audit log
+    if let Err(err) = decode_result {
+        if let Err(refund_err) = refund_view(dos_address, client_id, password, &entry.owner, &entry.image_name, &request_id).await {
+            eprintln!("Failed to give the view back: {}", refund_err);
         }
+        println!("Failed to decode {}: {}", input_file_path, err);
+        return Ok(());
+    }
+    if let Err(err) = report_decode(dos_address, client_id, password, &entry.owner, &entry.image_name, &request_id).await {
+        eprintln!("Failed to report the decode: {}", err);
+    }
+
+    // Show the image, then remove the decoded copy
+    let opener = if cfg!(target_os = "macos") {
+        Some("open")
+    } else if cfg!(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd")) {
+        Some("xdg-open")
     } else {
-        println!("No 'Views' metadata found. Cannot process the image.");
+        None
+    };
+    match opener.map(|opener| Command::new(opener).arg(output_file_path).spawn()) {
+        Some(Ok(_)) => {}
+        Some(Err(err)) => println!("Could not open an image viewer ({}), the image is at {}", err, output_file_path),
+        None => println!("The image is at {}", output_file_path),
     }
+    println!("Press Enter when you are done viewing the image.");
+    let mut done = String::new();
+    io::stdin().read_line(&mut done)?;
+    std::fs::remove_file(output_file_path)?;
+    println!("Decoded image removed.");
 
     Ok(())
 }
 
+/// Gives back a view the DOS granted but this client could not decode.
+async fn refund_view(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    owner: &str,
+    image_name: &str,
+    request_id: &str,
+) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/refund_view", dos_address))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password,
+            "owner": owner,
+            "image_name": image_name,
+            "request_id": request_id
+        }))
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    match response_body.get("error") {
+        Some(error) => Err(format!("{}", error).into()),
+        None => Ok(()),
+    }
+}
+
+async fn report_decode(
+    dos_address: &str,
+    client_id: &str,
+    password: &str,
+    owner: &str,
+    image_name: &str,
+    request_id: &str,
+) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .post(format!("{}/report_decode", dos_address))
+        .json(&json!({
+            "client_id": client_id,
+            "password": password,
+            "owner": owner,
+            "image_name": image_name,
+            "request_id": request_id
+        }))
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    match response_body.get("error") {
+        Some(error) => Err(format!("{}", error).into()),
+        None => Ok(()),
+    }
+}
+
+/// Prints who was granted, viewed, decoded or lost access to one of the user's images.
+async fn view_audit_log(dos_address: &str, client_id: &str, password: &str, image_name: &str) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+    let response = client
+        .get(format!("{}/audit", dos_address))
+        .query(&[("client_id", client_id), ("password", password), ("image_name", image_name)])
+        .send()
+        .await?;
+
+    let response_body: Value = response.json().await?;
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("Failed to fetch the audit log: {}", error).into());
+    }
+    let entries = response_body.get("entries").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+    if entries.is_empty() {
+        println!("Nothing recorded for {} yet.", image_name);
+    }
+    for entry in entries {
+        let field = |name: &str| entry.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        println!(
+            "{} {} {} (viewer {}) {}",
+            field("timestamp"),
+            field("actor"),
+            field("action"),
+            field("viewer"),
+            entry.get("details").map(|details| details.to_string()).unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Prints `prompt` and reads one trimmed line from stdin.
+fn read_input(prompt: &str) -> io::Result<String> {
+    println!("{}", prompt);
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+/// Lists the client's groups and lets it create groups, change their members or grant them an image.
+async fn manage_groups(dos_address: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+    let response_body: Value = client
+        .get(format!("{}/list_groups", dos_address))
+        .query(&[("client_id", client_id), ("password", password)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("Failed to fetch groups: {}", error).into());
+    }
+
+    if let Some(groups) = response_body.get("groups").and_then(|v| v.as_object()) {
+        for (group_name, members) in groups {
+            println!("{}: {}", group_name, members);
+        }
+    }
+    for group in response_body.get("member_of").and_then(|v| v.as_array()).into_iter().flatten() {
+        println!("Member of {}'s group {}", group["owner"].as_str().unwrap_or_default(), group["group_name"].as_str().unwrap_or_default());
+    }
+
+    let choice = read_input("Choose: (c)reate a group, (a)dd a member, (r)emove a member, (g)rant a group an image, anything else to go back")?;
+    let response = match choice.as_str() {
+        "c" => {
+            let group_name = read_input("Enter group name:")?;
+            let members: Vec<String> = read_input("Enter members, separated by commas:")?
+                .split(',')
+                .map(|member| member.trim().to_string())
+                .filter(|member| !member.is_empty())
+                .collect();
+            client
+                .post(format!("{}/create_group", dos_address))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "group_name": group_name,
+                    "members": members
+                }))
+                .send()
+                .await?
+        }
+        "a" | "r" => {
+            let group_name = read_input("Enter group name:")?;
+            let member = read_input("Enter member:")?;
+            let endpoint = if choice == "a" { "add_group_member" } else { "remove_group_member" };
+            client
+                .post(format!("{}/{}", dos_address, endpoint))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "group_name": group_name,
+                    "member": member
+                }))
+                .send()
+                .await?
+        }
+        "g" => {
+            let group_name = read_input("Enter group name:")?;
+            let image_name = read_input("Enter image name:")?;
+            let views: u32 = read_input("Enter views per member:")?.parse().map_err(|_| "Invalid number of views")?;
+            let mut group_access = HashMap::new();
+            group_access.insert(group_name, views);
+            client
+                .post(format!("{}/modify_access", dos_address))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "image_name": image_name,
+                    "group_access": group_access
+                }))
+                .send()
+                .await?
+        }
+        _ => return Ok(()),
+    };
+
+    let response_body: Value = response.json().await?;
+    match response_body.get("error") {
+        Some(error) => println!("Failed: {}", error),
+        None => println!("{}", response_body["message"].as_str().unwrap_or("Done")),
+    }
+    Ok(())
+}
+
+/// Lists the client's albums and the ones shared with it, and lets it create albums,
+/// change the images in them or grant them to viewers and groups.
+async fn manage_albums(dos_address: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+    let response_body: Value = client
+        .get(format!("{}/list_albums", dos_address))
+        .query(&[("client_id", client_id), ("password", password)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("Failed to fetch albums: {}", error).into());
+    }
+
+    if let Some(albums) = response_body.get("albums").and_then(|v| v.as_object()) {
+        for (album_name, album) in albums {
+            println!("{}: images {}, viewers {}, groups {}", album_name, album["images"], album["access_users"], album["group_access"]);
+        }
+    }
+    for album in response_body.get("shared_with_me").and_then(|v| v.as_array()).into_iter().flatten() {
+        println!(
+            "Shared with you: {}/{} {}",
+            album["owner"].as_str().unwrap_or_default(),
+            album["album_name"].as_str().unwrap_or_default(),
+            album["images"]
+        );
+    }
+
+    let choice = read_input("Choose: (c)reate an album, (a)dd an image, (r)emove an image, (g)rant an album, anything else to go back")?;
+    let response = match choice.as_str() {
+        "c" => {
+            let album_name = read_input("Enter album name:")?;
+            client
+                .post(format!("{}/create_album", dos_address))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "album_name": album_name
+                }))
+                .send()
+                .await?
+        }
+        "a" | "r" => {
+            let album_name = read_input("Enter album name:")?;
+            let image_name = read_input("Enter image name:")?;
+            let endpoint = if choice == "a" { "add_album_image" } else { "remove_album_image" };
+            client
+                .post(format!("{}/{}", dos_address, endpoint))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "album_name": album_name,
+                    "image_name": image_name
+                }))
+                .send()
+                .await?
+        }
+        "g" => {
+            let album_name = read_input("Enter album name:")?;
+            let target = read_input("Enter a viewer, or a group as @group:")?;
+            let views: u32 = read_input("Enter views of each image:")?.parse().map_err(|_| "Invalid number of views")?;
+            let mut grant = HashMap::new();
+            let field = match target.strip_prefix('@') {
+                Some(group_name) => {
+                    grant.insert(group_name.to_string(), views);
+                    "group_access"
+                }
+                None => {
+                    grant.insert(target.clone(), views);
+                    "access_rights"
+                }
+            };
+            client
+                .post(format!("{}/grant_album", dos_address))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "album_name": album_name,
+                    field: grant
+                }))
+                .send()
+                .await?
+        }
+        _ => return Ok(()),
+    };
+
+    let response_body: Value = response.json().await?;
+    match response_body.get("error") {
+        Some(error) => println!("Failed: {}", error),
+        None => println!("{}", response_body["message"].as_str().unwrap_or("Done")),
+    }
+    Ok(())
+}
+
+/// Lets the owner set an image's re-share policy or revoke a viewer's delegation subtree,
+/// and lets a viewer pass some of its access on to another client.
+async fn manage_resharing(dos_address: &str, client_id: &str, password: &str) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+
+    let choice = read_input("Choose: (p)olicy for one of your images, (d)elegate access you have, (r)evoke a delegation, anything else to go back")?;
+    let response = match choice.as_str() {
+        "p" => {
+            let image_name = read_input("Enter image name:")?;
+            let policy = match read_input("Allow re-sharing: (n)ot at all, (v)iews up to a limit, (g)roup members only")?.as_str() {
+                "v" => {
+                    let max_views: u32 = read_input("Enter the most views each viewer may pass on:")?
+                        .parse()
+                        .map_err(|_| "Invalid number of views")?;
+                    json!({ "mode": "max_views", "max_views": max_views })
+                }
+                "g" => json!({ "mode": "group", "group": read_input("Enter group name:")? }),
+                _ => json!({ "mode": "none" }),
+            };
+            client
+                .post(format!("{}/set_reshare_policy", dos_address))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "image_name": image_name,
+                    "reshare_policy": policy
+                }))
+                .send()
+                .await?
+        }
+        "d" => {
+            let owner = read_input("Enter the image's owner:")?;
+            let image_name = read_input("Enter image name:")?;
+            let delegate = read_input("Enter the client to pass access on to:")?;
+            let views: u32 = read_input("Enter the number of views:")?.parse().map_err(|_| "Invalid number of views")?;
+            client
+                .post(format!("{}/delegate_access", dos_address))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "owner": owner,
+                    "image_name": image_name,
+                    "delegate": delegate,
+                    "views": views
+                }))
+                .send()
+                .await?
+        }
+        "r" => {
+            let image_name = read_input("Enter image name:")?;
+            let viewer = read_input("Enter the viewer whose delegations to revoke:")?;
+            client
+                .post(format!("{}/revoke_delegation", dos_address))
+                .json(&json!({
+                    "client_id": client_id,
+                    "password": password,
+                    "image_name": image_name,
+                    "viewer": viewer
+                }))
+                .send()
+                .await?
+        }
+        _ => return Ok(()),
+    };
+
+    let response_body: Value = response.json().await?;
+    match response_body.get("error") {
+        Some(error) => println!("Failed: {}", error),
+        None => println!("{}", response_body["message"].as_str().unwrap_or("Done")),
+    }
+    Ok(())
+}
+
+/// Prints the images in the gallery with the views the client has left and any request it has pending.
+async fn list_gallery(dos_address: &str, client_id: &str, password: &str, album: Option<(&str, &str)>) -> Result<(), Box<dyn Error>> {
+    let mut query = vec![("client_id", client_id), ("password", password)];
+    if let Some((owner, album_name)) = album {
+        query.push(("owner", owner));
+        query.push(("album", album_name));
+    }
+    let response_body: Value = Client::new()
+        .get(format!("{}/gallery", dos_address))
+        .query(&query)
+        .send()
+        .await?
+        .json()
+        .await?;
+    if let Some(error) = response_body.get("error") {
+        return Err(format!("{}", error).into());
+    }
+
+    let images = response_body.get("images").and_then(|v| v.as_array()).cloned().unwrap_or_default();
+    if images.is_empty() {
+        println!("The gallery is empty.");
+    }
+    for image in images {
+        let access = match (image["remaining_views"].as_u64(), image["pending_request"].get("access_rights")) {
+            (None, _) => "yours".to_string(),
+            (Some(_), Some(requested)) => format!("request for {} views pending", requested),
+            (Some(views), None) => format!("{} views left", views),
+        };
+        println!(
+            "{}/{} ({}), added {}, thumbnail {}{}",
+            image["owner"].as_str().unwrap_or_default(),
+            image["name"].as_str().unwrap_or_default(),
+            access,
+            image["created_at"].as_str().unwrap_or("unknown"),
+            dos_address,
+            image["thumbnail_url"].as_str().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
 pub async fn fetch_composite_image(
     dos_address: &str,
     output_path: &str,
+    album: Option<(&str, &str)>,
+    page: u32,
 ) -> Result<(), Box<dyn Error>> {
     // Create an HTTP client
     let client = Client::new();
 
-    // Construct the URL with query parameters
-    let url = format!("{}/list_all", dos_address);
-
-    // Send the GET request
-    let response = client.get(&url).send().await?;
+    // Send the GET request, for one owner's album if given
+    let page = page.to_string();
+    let response = match album {
+        Some((owner, album_name)) => {
+            client
+                .get(format!("{}/list_by_client", dos_address))
+                .query(&[("client_id", owner), ("album", album_name), ("page", page.as_str())])
+                .send()
+                .await?
+        }
+        None => {
+            client
+                .get(format!("{}/list_all", dos_address))
+                .query(&[("page", page.as_str())])
+                .send()
+                .await?
+        }
+    };
+    let total_pages = response
+        .headers()
+        .get("X-Total-Pages")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("1")
+        .to_string();
 
     // Check if the response status is success
     if response.status().is_success() {
@@ -1835,7 +4350,7 @@ pub async fn fetch_composite_image(
         // Save the image to the specified output path
         let mut file = File::create(output_path).await?;
         file.write_all(&image_bytes).await?;
-        println!("Composite image saved to {}", output_path);
+        println!("Composite image saved to {} (page {} of {})", output_path, page, total_pages);
         Ok(())
     } else {
         Err(format!(
@@ -1846,3 +4361,266 @@ pub async fn fetch_composite_image(
         .into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A noisy opaque cover saved as a PNG in the temp directory.
+    fn generated_cover(name: &str, width: u32, height: u32) -> String {
+        let mut rng = StdRng::seed_from_u64(7);
+        let cover = image::RgbaImage::from_fn(width, height, |_, _| Rgba([rng.gen(), rng.gen(), rng.gen(), 255]));
+        let path = std::env::temp_dir().join(format!("{}_{}.png", name, std::process::id()));
+        cover.save_with_format(&path, image::ImageFormat::Png).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn round_trip(codec: &dyn StegoCodec) {
+        let cover_path = generated_cover(&format!("{}_cover", codec.name()), 64, 48);
+        let output_path = cover_path.replace("_cover", "_stego");
+        let key = [5u8; 32];
+        let payload: Vec<u8> = (0..300).map(|_| rand::random()).collect();
+
+        hide_payload(codec, &cover_path, &output_path, &payload, &key).unwrap();
+        assert_eq!(reveal_payload(&output_path, &key).unwrap(), payload);
+
+        let _ = std::fs::remove_file(cover_path);
+        let _ = std::fs::remove_file(output_path);
+    }
+
+    fn rejects_oversized_payload(codec: &dyn StegoCodec) {
+        let cover_path = generated_cover(&format!("{}_small", codec.name()), 16, 16);
+        let output_path = cover_path.replace("_small", "_small_stego");
+        let payload = vec![0xAB; codec.capacity(16, 16)];
+
+        assert!(hide_payload(codec, &cover_path, &output_path, &payload, &[1u8; 32]).is_err());
+        assert!(codec.embed(&cover_path, &output_path, &vec![0xAB; codec.capacity(16, 16) + 1], &[1u8; 32]).is_err());
+
+        let _ = std::fs::remove_file(cover_path);
+        let _ = std::fs::remove_file(output_path);
+    }
+
+    #[test]
+    fn alpha_codec_round_trip() {
+        round_trip(&AlphaCodec);
+    }
+
+    #[test]
+    fn lsb_rgb_codec_round_trip() {
+        round_trip(&LsbRgbCodec);
+    }
+
+    #[test]
+    fn alpha_codec_rejects_oversized_payload() {
+        rejects_oversized_payload(&AlphaCodec);
+    }
+
+    #[test]
+    fn lsb_rgb_codec_rejects_oversized_payload() {
+        rejects_oversized_payload(&LsbRgbCodec);
+    }
+
+    fn published_key(secret: &StaticSecret) -> String {
+        general_purpose::STANDARD.encode(PublicKey::from(secret).as_bytes())
+    }
+
+    #[test]
+    fn payload_encryption_round_trip() {
+        let owner = StaticSecret::random_from_rng(OsRng);
+        let viewer = StaticSecret::random_from_rng(OsRng);
+        let plaintext = b"original image bytes".to_vec();
+
+        let owner_key = derive_shared_key(&owner, &published_key(&viewer)).unwrap();
+        let payload = encrypt_payload(&owner_key, &plaintext).unwrap();
+        assert_ne!(&payload[12..], plaintext.as_slice());
+
+        let viewer_key = derive_shared_key(&viewer, &published_key(&owner)).unwrap();
+        assert_eq!(decrypt_payload(&viewer_key, &payload).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn payload_encryption_rejects_another_viewers_key() {
+        let owner = StaticSecret::random_from_rng(OsRng);
+        let viewer = StaticSecret::random_from_rng(OsRng);
+        let other_viewer = StaticSecret::random_from_rng(OsRng);
+
+        let owner_key = derive_shared_key(&owner, &published_key(&viewer)).unwrap();
+        let payload = encrypt_payload(&owner_key, b"original image bytes").unwrap();
+
+        let other_key = derive_shared_key(&other_viewer, &published_key(&owner)).unwrap();
+        assert!(decrypt_payload(&other_key, &payload).is_err());
+    }
+
+    /// Writes `frame` to a loopback listener and returns what `receive_image_save` made of it.
+    async fn receive_frame(frame: Vec<u8>, output_path: &str) -> Result<Value, String> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let sender = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            // The receiver may hang up as soon as the prefix is rejected
+            let _ = stream.write_all(&frame).await;
+        });
+        let received = receive_image_save(listener, output_path).await.map_err(|err| err.to_string());
+        sender.await.unwrap();
+        received
+    }
+
+    fn frame_output_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("frame_{}_{}.png", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn image_frame_round_trip() {
+        let input_path = frame_output_path("sent");
+        let output_path = frame_output_path("received");
+        let body: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        std::fs::write(&input_path, &body).unwrap();
+        let metadata = json!({ "image_name": "cat.png", "request_id": "42" });
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let receiver = tokio::spawn(async move {
+            let output_path = frame_output_path("received");
+            receive_image_save(listener, &output_path).await.map_err(|err| err.to_string())
+        });
+        send_image("127.0.0.1", port, &input_path, &metadata).await.unwrap();
+
+        assert_eq!(receiver.await.unwrap().unwrap(), metadata);
+        assert_eq!(std::fs::read(&output_path).unwrap(), body);
+
+        let _ = std::fs::remove_file(input_path);
+        let _ = std::fs::remove_file(output_path);
+    }
+
+    #[tokio::test]
+    async fn image_frame_rejects_a_flipped_body_byte() {
+        let output_path = frame_output_path("flipped");
+        let header = serde_json::to_vec(&json!({ "image_name": "cat.png" })).unwrap();
+        let body = vec![7u8; 1024];
+        let mut frame = frame_prefix(&header, &body);
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&body);
+        let last = frame.len() - 1;
+        frame[last] ^= 0x01;
+
+        let err = receive_frame(frame, &output_path).await.unwrap_err();
+        assert!(err.contains("digest mismatch"), "{}", err);
+        assert!(!Path::new(&output_path).exists());
+    }
+
+    #[tokio::test]
+    async fn image_frame_rejects_bad_magic() {
+        let output_path = frame_output_path("magic");
+        let mut frame = frame_prefix(b"{}", b"body");
+        frame[..4].copy_from_slice(b"JUNK");
+        frame.extend_from_slice(b"{}body");
+
+        let err = receive_frame(frame, &output_path).await.unwrap_err();
+        assert!(err.contains("bad magic"), "{}", err);
+        assert!(!Path::new(&output_path).exists());
+    }
+
+    #[tokio::test]
+    async fn image_frame_rejects_oversized_lengths() {
+        let output_path = frame_output_path("oversized");
+
+        let mut header_too_large = frame_prefix(b"{}", b"body");
+        header_too_large[5..9].copy_from_slice(&(MAX_FRAME_HEADER_LEN as u32 + 1).to_be_bytes());
+        let err = receive_frame(header_too_large, &output_path).await.unwrap_err();
+        assert!(err.contains("too large"), "{}", err);
+
+        let mut body_too_large = frame_prefix(b"{}", b"body");
+        body_too_large[9..17].copy_from_slice(&(MAX_FRAME_BODY_LEN + 1).to_be_bytes());
+        let err = receive_frame(body_too_large, &output_path).await.unwrap_err();
+        assert!(err.contains("too large"), "{}", err);
+
+        assert!(!Path::new(&output_path).exists());
+    }
+
+    #[test]
+    fn view_shares_only_unwrap_with_their_index() {
+        let shared_key = [9u8; 32];
+        let share: [u8; 32] = rand::random();
+        let first = wrap_view_share(&shared_key, "req-1", 0, &share);
+        let second = wrap_view_share(&shared_key, "req-1", 1, &share);
+
+        assert_ne!(first, second);
+        assert_eq!(wrap_view_share(&shared_key, "req-1", 0, &first), share);
+        assert_eq!(wrap_view_share(&shared_key, "req-1", 1, &second), share);
+        assert_ne!(wrap_view_share(&shared_key, "req-1", 1, &first), share);
+        assert_ne!(wrap_view_share(&[8u8; 32], "req-1", 0, &first), share);
+    }
+
+    #[test]
+    fn forensic_watermark_survives_recompression_and_resize() {
+        // A smooth picture, like a photo, rather than noise that JPEG would flatten
+        let mut rng = StdRng::seed_from_u64(11);
+        let picture = image::RgbaImage::from_fn(640, 480, |x, y| {
+            let shade = (x * 255 / 640) as u8;
+            Rgba([shade, (y * 255 / 480) as u8, shade / 2 + rng.gen_range(0..8), 255])
+        });
+        let picture_path = std::env::temp_dir().join(format!("forensic_picture_{}.png", std::process::id()));
+        picture.save_with_format(&picture_path, image::ImageFormat::Png).unwrap();
+        let picture_path = picture_path.to_string_lossy().into_owned();
+        let marked_path = picture_path.replace("_picture", "_marked");
+        let leaked_path = picture_path.replace("_picture", "_leaked");
+        let watermark_id = 0xC0FF_EE42;
+
+        apply_forensic_watermark(&picture_path, &marked_path, image::ImageFormat::Png, watermark_id).unwrap();
+
+        // Re-compress as JPEG, then scale down as a screenshot or re-upload would
+        let marked = open(&marked_path).unwrap().to_rgb8();
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 75).encode_image(&marked).unwrap();
+        let recompressed = image::load_from_memory(&jpeg).unwrap();
+        let resized = recompressed.resize_exact(480, 360, image::imageops::FilterType::Triangle);
+        resized.save_with_format(&leaked_path, image::ImageFormat::Png).unwrap();
+
+        let (detected_id, confidence) = detect_forensic_watermark(&leaked_path).unwrap();
+        assert_eq!(detected_id, watermark_id);
+        assert!(confidence > 0.1, "confidence {}", confidence);
+
+        for path in [picture_path, marked_path, leaked_path] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn forensic_watermark_keeps_jpeg_sources_as_jpeg() {
+        let picture = image::RgbImage::from_fn(512, 384, |x, y| image::Rgb([(x / 2) as u8, (y / 2) as u8, 96]));
+        let picture_path = std::env::temp_dir().join(format!("forensic_photo_{}.jpg", std::process::id()));
+        picture.save_with_format(&picture_path, image::ImageFormat::Jpeg).unwrap();
+        let picture_path = picture_path.to_string_lossy().into_owned();
+        let marked_path = picture_path.replace("_photo", "_photo_marked");
+
+        let format = forensic_output_format(&picture_path);
+        assert_eq!(format, image::ImageFormat::Jpeg);
+        apply_forensic_watermark(&picture_path, &marked_path, format, 0x1234_5678).unwrap();
+        assert_eq!(forensic_output_format(&marked_path), image::ImageFormat::Jpeg);
+        assert_eq!(detect_forensic_watermark(&marked_path).unwrap().0, 0x1234_5678);
+
+        let _ = std::fs::remove_file(picture_path);
+        let _ = std::fs::remove_file(marked_path);
+    }
+
+    #[test]
+    fn forensic_watermark_rejects_small_images() {
+        let cover_path = generated_cover("forensic_small", FORENSIC_GRID, FORENSIC_GRID - 1);
+        let output_path = cover_path.replace("_small", "_small_marked");
+
+        assert!(apply_forensic_watermark(&cover_path, &output_path, image::ImageFormat::Png, 1).is_err());
+        assert!(detect_forensic_watermark(&cover_path).is_err());
+
+        let _ = std::fs::remove_file(cover_path);
+    }
+
+    #[test]
+    fn pixel_order_is_a_permutation() {
+        let mut order: Vec<u32> = LsbRgbCodec::pixel_order(b"key", 1000).collect();
+        order.sort_unstable();
+        assert_eq!(order, (0..1000).collect::<Vec<u32>>());
+    }
+}
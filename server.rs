@@ -3,30 +3,29 @@ use tokio::net::UdpSocket;
 use tokio::time::{timeout, Duration, sleep};
 use std::error::Error;
 use std::sync::Arc;
-use std::collections::HashMap;
-use steganography::util::{file_as_dynamic_image, save_image_buffer};
-use steganography::encoder::*;
+use std::collections::{HashMap, HashSet};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::fs::{File as OtherFile, OpenOptions};
 use std::io::{BufReader, BufWriter};
-use warp::{Filter, reply};
+use warp::{Filter, Reply, reply};
 use warp::ws::{WebSocket, Message};
 use tokio::sync::broadcast;
 use futures_util::{StreamExt, SinkExt};
 use serde_json::json;
 use image::{DynamicImage, Rgba, ImageBuffer, GenericImage};
-use imageproc::drawing::draw_text_mut;
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
+use imageproc::rect::Rect;
 use rusttype::{Font, Scale};
 use base64::{engine::general_purpose, Engine};
 use std::sync::Mutex;
 use image::ImageOutputFormat;
+use sha2::{Digest, Sha256};
 use std::io::Cursor;
 use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::Mutex as new_Mutex; // Import tokio's Mutex instead of std::sync::Mutex
-use itertools::Itertools;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use std::io::Write;
@@ -41,26 +40,248 @@ use tokio::io::{AsyncBufReadExt, BufReader as OtherBufReader};
 use serde_json::Value;
 use reqwest::Client;
 use tokio::time;
-const CHUNK_SIZE: usize = 1024;
-const ACK: &[u8] = b"ACK";
 static IS_LEADER: AtomicBool = AtomicBool::new(false);
+const SERVER_ADDRESS: &str = "10.7.17.50:8081";
+const ADMIN_CLIENTS: [&str; 1] = ["admin"]; // Clients allowed to add covers to the shared library
+const SHARED_COVERS_KEY: &str = "shared";
+const NOTIFICATION_TTL_DAYS: i64 = 7; // Pending requests nobody acted on expire after this
+const ACCESS_REQUEST_LIMIT: usize = 10; // Access requests one client may make per window
+const ACCESS_REQUEST_WINDOW_MINS: i64 = 60;
+const EVENT_RETENTION_DAYS: i64 = 7; // Events older than this can no longer be replayed
+const MAX_RETAINED_EVENTS: usize = 1000;
+const VIEW_GRANT_TTL_SECS: u64 = 60; // How long the key share handed out for one view may be used
+const MAX_VIEW_SHARES: usize = 1000; // Single-use key shares one delivery may hold
+static EVENT_LOG_LOCK: Mutex<()> = Mutex::new(()); // Keeps event IDs unique across concurrent requests
+static DIRECTORY_LOCK: Mutex<()> = Mutex::new(()); // Held from load to save by everything writing directory.json or revocations.json
+static NOTIFICATIONS_LOCK: Mutex<()> = Mutex::new(()); // Held from load to save by everything writing notifications.json
+const AUDIT_LOG_PATH: &str = "audit.json";
+const THUMBNAIL_SIZE: u32 = 128; // Longest side of the thumbnails the gallery links to
+const COMPOSITE_CELL_SIZE: u32 = 256; // Each image is scaled to fit a square cell of this size
+const COMPOSITE_CAPTION_HEIGHT: u32 = 50;
+const MAX_COMPOSITE_WIDTH: u32 = 2048;
+const MAX_COMPOSITE_HEIGHT: u32 = 4096;
+const DEFAULT_COMPOSITE_COLUMNS: u32 = 4;
+const DEFAULT_COMPOSITE_PAGE_SIZE: usize = 12;
+static AUDIT_LOG_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct AuditEntry {
+    id: u64,
+    #[serde(default)]
+    origin: String, // Server that recorded the entry, ids are only unique per origin
+    actor: String,
+    action: String, // grant, edit_views, revoke, reject, expire, view, decode, wipe
+    image_owner: String,
+    image_name: String,
+    viewer: String,
+    #[serde(default)]
+    details: serde_json::Value,
+    timestamp: String,
+}
+
+/// Append-only record of every grant, revocation and view. Entries are never edited or removed,
+/// which is also what lets replicas merge it by concatenation.
+#[derive(Serialize, Deserialize, Clone)]
+struct AuditLog {
+    entries: Vec<AuditEntry>,
+    next_id: u64,
+}
+
+impl AuditLog {
+    fn new() -> Self {
+        AuditLog {
+            entries: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    fn load_from_file(file_path: &str) -> Self {
+        let file = match std::fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => std::fs::File::create(file_path).unwrap(),
+        };
+
+        let reader = BufReader::new(file);
+        serde_json::from_reader(reader).unwrap_or_else(|_| AuditLog::new())
+    }
+
+    fn save_to_file(&self, file_path: &str) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap();
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).unwrap();
+    }
+}
+
+fn record_audit(actor: &str, action: &str, image_owner: &str, image_name: &str, viewer: &str, details: serde_json::Value) {
+    let _guard = AUDIT_LOG_LOCK.lock().unwrap();
+    let mut audit_log = AuditLog::load_from_file(AUDIT_LOG_PATH);
+    // Entries replicated from a former leader may be ahead of our counter
+    let next_id = audit_log.entries.iter().map(|entry| entry.id + 1).max().unwrap_or(1).max(audit_log.next_id);
+    audit_log.entries.push(AuditEntry {
+        id: next_id,
+        origin: SERVER_ADDRESS.to_string(),
+        actor: actor.to_string(),
+        action: action.to_string(),
+        image_owner: image_owner.to_string(),
+        image_name: image_name.to_string(),
+        viewer: viewer.to_string(),
+        details,
+        timestamp: Utc::now().to_rfc3339(),
+    });
+    audit_log.next_id = next_id + 1;
+    audit_log.save_to_file(AUDIT_LOG_PATH);
+}
+
+/// Merges a replicated audit.json into ours. Entries are matched on (origin, id), so two leaders
+/// handing out the same id never hide each other's entries.
+fn merge_audit_log(data: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let replicated: AuditLog = serde_json::from_str(data)?;
+
+    let _guard = AUDIT_LOG_LOCK.lock().unwrap();
+    let mut audit_log = AuditLog::load_from_file(AUDIT_LOG_PATH);
+    let mut seen: HashSet<(String, u64)> = audit_log.entries.iter().map(|entry| (entry.origin.clone(), entry.id)).collect();
+    for entry in replicated.entries {
+        if seen.insert((entry.origin.clone(), entry.id)) {
+            audit_log.entries.push(entry);
+        }
+    }
+    // RFC 3339 timestamps in UTC sort chronologically as strings
+    audit_log.entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    audit_log.next_id = audit_log.next_id.max(replicated.next_id);
+    audit_log.save_to_file(AUDIT_LOG_PATH);
+    println!("Merged replicated entries into {}", AUDIT_LOG_PATH);
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct EventLog {
+    events: Vec<serde_json::Value>, // Oldest first, each with an "id" and "recipients"
+    next_id: u64,
+    #[serde(default)]
+    epoch: String, // Random per log; IDs are only comparable within one, events.json is not replicated
+}
+
+impl EventLog {
+    fn new() -> Self {
+        EventLog {
+            events: Vec::new(),
+            next_id: 1,
+            epoch: String::new(),
+        }
+    }
+
+    fn load_from_file(file_path: &str) -> Self {
+        let file = match std::fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => std::fs::File::create(file_path).unwrap(),
+        };
+
+        let reader = BufReader::new(file);
+        let mut event_log: EventLog = serde_json::from_reader(reader).unwrap_or_else(|_| EventLog::new());
+        // A new log starts a new epoch, saved right away so every reader sees the same one
+        if event_log.epoch.is_empty() {
+            event_log.epoch = format!("{:016x}", rand::random::<u64>());
+            event_log.save_to_file(file_path);
+        }
+        event_log
+    }
+
+    fn save_to_file(&self, file_path: &str) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap();
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).unwrap();
+    }
+
+    /// Assigns the next ID to `event`, appends it and drops events past the retention limits.
+    fn append(&mut self, mut event: serde_json::Value) -> serde_json::Value {
+        event["id"] = json!(self.next_id);
+        event["epoch"] = json!(self.epoch);
+        self.next_id += 1;
+        self.events.push(event.clone());
+
+        let cutoff = Utc::now() - chrono::Duration::days(EVENT_RETENTION_DAYS);
+        self.events.retain(|event| {
+            event["timestamp"]
+                .as_str()
+                .and_then(|timestamp| chrono::DateTime::parse_from_rfc3339(timestamp).ok())
+                .map(|timestamp| timestamp >= cutoff)
+                .unwrap_or(false)
+        });
+        if self.events.len() > MAX_RETAINED_EVENTS {
+            let excess = self.events.len() - MAX_RETAINED_EVENTS;
+            self.events.drain(..excess);
+        }
+        event
+    }
+
+    /// Events for `client_id` with an ID greater than `since`, oldest first. A cursor from another
+    /// epoch says nothing about this log, so the client gets everything retained.
+    fn since(&self, client_id: &str, epoch: &str, since: u64) -> Vec<serde_json::Value> {
+        let since = if epoch == self.epoch { since } else { 0 };
+        self.events
+            .iter()
+            .filter(|event| event["id"].as_u64().unwrap_or(0) > since && is_event_recipient(event, client_id))
+            .map(without_recipients)
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+enum NotificationStatus {
+    #[default]
+    Pending,
+    Approved,
+    Rejected,
+    Expired,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+enum NotificationKind {
+    #[default]
+    AccessRequest, // Sent to the owner, waiting for a decision
+    Outcome,       // Sent back to the requester once the owner decided
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct Notification {
+    #[serde(default)]
+    id: u64,
     image_owner: String,
     image_name: String,
     requester: String,
     access_rights: u32,
+    #[serde(default)]
+    kind: NotificationKind,
+    #[serde(default)]
+    status: NotificationStatus,
+    #[serde(default)]
+    created_at: String,
+    #[serde(default)]
+    request_id: String, // Ties the request to the one sent to the owner's client, if any
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct NotificationDirectory {
     notifications: HashMap<String, Vec<Notification>>, // Keyed by client ID
+    #[serde(default)]
+    next_id: u64,
 }
 
 impl NotificationDirectory {
     fn new() -> Self {
         NotificationDirectory {
             notifications: HashMap::new(),
+            next_id: 1,
         }
     }
 
@@ -71,7 +292,64 @@ impl NotificationDirectory {
         };
 
         let reader = BufReader::new(file);
-        serde_json::from_reader(reader).unwrap_or_else(|_| NotificationDirectory::new())
+        let mut notification_directory: NotificationDirectory =
+            serde_json::from_reader(reader).unwrap_or_else(|_| NotificationDirectory::new());
+
+        // Notifications saved before they had IDs get one, once: in a fixed order and saved right
+        // away, so an ID handed out to an owner keeps pointing at the same notification
+        notification_directory.next_id = notification_directory.next_id.max(1);
+        let mut next_id = notification_directory.next_id;
+        let mut recipients: Vec<String> = notification_directory.notifications.keys().cloned().collect();
+        recipients.sort();
+        let migrated_at = Utc::now().to_rfc3339();
+        let mut migrated = false;
+        for recipient in recipients {
+            for notification in notification_directory.notifications.get_mut(&recipient).into_iter().flatten() {
+                if notification.id == 0 {
+                    notification.id = next_id;
+                    next_id += 1;
+                    migrated = true;
+                }
+                if notification.created_at.is_empty() {
+                    notification.created_at = migrated_at.clone();
+                    migrated = true;
+                }
+            }
+        }
+        notification_directory.next_id = next_id;
+        if migrated {
+            notification_directory.save_to_file(file_path);
+        }
+        notification_directory
+    }
+
+    /// Adds a notification for `recipient` and returns the ID it was given.
+    fn push(&mut self, recipient: &str, mut notification: Notification) -> u64 {
+        notification.id = self.next_id;
+        notification.created_at = Utc::now().to_rfc3339();
+        self.next_id += 1;
+        let id = notification.id;
+        self.notifications
+            .entry(recipient.to_string())
+            .or_default()
+            .push(notification);
+        id
+    }
+
+    /// Marks pending requests older than NOTIFICATION_TTL_DAYS as expired.
+    fn expire_stale(&mut self) -> bool {
+        let cutoff = Utc::now() - chrono::Duration::days(NOTIFICATION_TTL_DAYS);
+        let mut changed = false;
+        for notification in self.notifications.values_mut().flatten() {
+            let is_stale = chrono::DateTime::parse_from_rfc3339(&notification.created_at)
+                .map(|created_at| created_at < cutoff)
+                .unwrap_or(false);
+            if notification.status == NotificationStatus::Pending && is_stale {
+                notification.status = NotificationStatus::Expired;
+                changed = true;
+            }
+        }
+        changed
     }
 
     fn save_to_file(&self, file_path: &str) {
@@ -86,13 +364,22 @@ impl NotificationDirectory {
     }
 }
 #[derive(Serialize, Deserialize, Clone)]
-struct Directory {
-    clients: HashMap<String, Vec<String>>,
+struct Cover {
+    name: String,
+    data: String, // Base64 encoded image
+    width: u32,
+    height: u32,
 }
-impl Directory {
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CoverDirectory {
+    covers: HashMap<String, Vec<Cover>>, // Keyed by client ID, SHARED_COVERS_KEY holds the admin library
+}
+
+impl CoverDirectory {
     fn new() -> Self {
-        Directory {
-            clients: HashMap::new(),
+        CoverDirectory {
+            covers: HashMap::new(),
         }
     }
 
@@ -103,11 +390,7 @@ impl Directory {
         };
 
         let reader = BufReader::new(file);
-        serde_json::from_reader(reader).unwrap_or_else(|_| Directory::new())
-    }
-
-    fn clear(&mut self) {
-        self.clients.clear();
+        serde_json::from_reader(reader).unwrap_or_else(|_| CoverDirectory::new())
     }
 
     fn save_to_file(&self, file_path: &str) {
@@ -123,21 +406,14 @@ impl Directory {
 }
 
 #[derive(Serialize, Deserialize, Clone)]
-struct ClientInfo {
-    id: String,
-    password: String,
-    current_ip: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-struct ClientDirectory {
-    clients: HashMap<String, ClientInfo>,
+struct GroupDirectory {
+    groups: HashMap<String, HashMap<String, Vec<String>>>, // Owner -> group name -> member client IDs
 }
 
-impl ClientDirectory {
+impl GroupDirectory {
     fn new() -> Self {
-        ClientDirectory {
-            clients: HashMap::new(),
+        GroupDirectory {
+            groups: HashMap::new(),
         }
     }
 
@@ -148,7 +424,7 @@ impl ClientDirectory {
         };
 
         let reader = BufReader::new(file);
-        serde_json::from_reader(reader).unwrap_or_else(|_| ClientDirectory::new())
+        serde_json::from_reader(reader).unwrap_or_else(|_| GroupDirectory::new())
     }
 
     fn save_to_file(&self, file_path: &str) {
@@ -161,192 +437,2580 @@ impl ClientDirectory {
         let writer = BufWriter::new(file);
         serde_json::to_writer(writer, &self).unwrap();
     }
-}
 
-type SharedClientDirectory = Arc<Mutex<ClientDirectory>>;
-type SharedDirectory = Arc<Mutex<Directory>>;
-fn with_directory(
-    directory: SharedDirectory,
-) -> impl Filter<Extract = (SharedDirectory,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || directory.clone())
-}
-fn with_client_directory(
-    client_directory: SharedClientDirectory,
-) -> impl Filter<Extract = (SharedClientDirectory,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || client_directory.clone())
+    fn members(&self, owner: &str, group: &str) -> Option<&Vec<String>> {
+        self.groups.get(owner).and_then(|groups| groups.get(group))
+    }
+
+    fn is_member(&self, owner: &str, group: &str, client_id: &str) -> bool {
+        self.members(owner, group).is_some_and(|members| members.iter().any(|m| m == client_id))
+    }
 }
-fn with_notifier(
-    notifier: broadcast::Sender<String>,
-) -> impl Filter<Extract = (broadcast::Sender<String>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || notifier.clone())
+
+/// A named collection of one owner's images. Grants made on the album apply to each of its
+/// images, with every viewer getting that many views of each image.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct Album {
+    images: Vec<String>,
+    access_users: HashMap<String, u32>,
+    group_access: HashMap<String, u32>,
 }
-async fn handle_ws_connection(ws: WebSocket, mut rx: broadcast::Receiver<String>) {
-    let (mut ws_tx, _ws_rx) = ws.split();
-    while let Ok(msg) = rx.recv().await {
-        if ws_tx.send(Message::text(msg)).await.is_err() {
-            break; // Exit if the WebSocket connection is closed
+
+impl Album {
+    /// Views of each image the album grants `viewer`, directly or through the best of its group grants.
+    fn quota(&self, owner: &str, viewer: &str, groups: &GroupDirectory) -> Option<u32> {
+        let direct = self.access_users.get(viewer).copied();
+        let grouped = self
+            .group_access
+            .iter()
+            .filter(|(group, _)| groups.is_member(owner, group, viewer))
+            .map(|(_, quota)| *quota)
+            .max();
+        direct.into_iter().chain(grouped).max()
+    }
+
+    fn viewers(&self, owner: &str, groups: &GroupDirectory) -> Vec<String> {
+        let mut viewers: Vec<String> = self.access_users.keys().cloned().collect();
+        for group in self.group_access.keys() {
+            for member in groups.members(owner, group).into_iter().flatten() {
+                if !viewers.contains(member) {
+                    viewers.push(member.clone());
+                }
+            }
         }
+        viewers
     }
 }
 
-fn create_composite_image(
-    images: &[String],
-) -> Result<DynamicImage, Box<dyn std::error::Error>> {
-    const FONT_PATH: &str = "Roboto-Bold.ttf"; // Update to the correct font path
-    let font_data = std::fs::read(FONT_PATH)?;
-    let font = Font::try_from_vec(font_data).ok_or("Failed to load font")?;
-
-    let mut decoded_images = Vec::new();
-    for img_data in images {
-        // Deserialize the image JSON string
-        let image_json: serde_json::Value = serde_json::from_str(img_data)?;
-        let image_name = image_json["name"].as_str().unwrap_or("Unknown");
-        let client_id = image_json["client_id"].as_str().unwrap_or("Unknown");
-        let image_base64 = image_json["data"].as_str().unwrap();
-        let decoded_bytes = general_purpose::STANDARD.decode(image_base64)?;
-        let decoded_image = image::load_from_memory(&decoded_bytes)?;
+#[derive(Serialize, Deserialize, Clone)]
+struct AlbumDirectory {
+    albums: HashMap<String, HashMap<String, Album>>, // Owner -> album name -> album
+}
 
-        decoded_images.push((client_id.to_string(), image_name.to_string(), decoded_image));
+impl AlbumDirectory {
+    fn new() -> Self {
+        AlbumDirectory {
+            albums: HashMap::new(),
+        }
     }
 
-    let total_width = decoded_images.iter().map(|(_, _, img)| img.width()).max().unwrap_or(0);
-    let total_height: u32 = decoded_images.iter().map(|(_, _, img)| img.height()).sum();
+    fn load_from_file(file_path: &str) -> Self {
+        let file = match std::fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => std::fs::File::create(file_path).unwrap(),
+        };
 
-    let mut composite = ImageBuffer::new(total_width, total_height);
-    let mut y_offset = 0;
+        let reader = BufReader::new(file);
+        serde_json::from_reader(reader).unwrap_or_else(|_| AlbumDirectory::new())
+    }
 
-    for (client_id, image_name, img) in decoded_images {
-        composite.copy_from(&img.to_rgba8(), 0, y_offset)?;
+    fn save_to_file(&self, file_path: &str) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap();
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).unwrap();
+    }
 
-       // Draw client ID and image name line by line
-       let scale = Scale { x: 20.0, y: 20.0 };
-       let text_color = Rgba([255, 255, 255, 255]);
-       let lines = vec![
-           format!("Client: {}", client_id),
-           format!("Name: {}", image_name),
-       ];
-       let mut text_y_offset = y_offset as i32 + 10; // Starting position for text
+    /// The albums of `owner` that hold the image.
+    fn containing<'a>(&'a self, owner: &str, image_name: &'a str) -> impl Iterator<Item = (&'a String, &'a Album)> {
+        self.albums
+            .get(owner)
+            .into_iter()
+            .flatten()
+            .filter(move |(_, album)| album.images.iter().any(|image| image == image_name))
+    }
+}
 
-       for line in lines {
-           draw_text_mut(
-               &mut composite,
-               text_color,
-               10,
-               text_y_offset,
-               scale,
-               &font,
-               &line,
-           );
-           text_y_offset += scale.y as i32 + 5; // Add line height and some padding
-       }
+/// Whether viewers of an image may pass access on to other clients, stored per image under `reshare_policy`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+enum ResharePolicy {
+    #[default]
+    None,
+    /// Each viewer may hand out at most `max_views` views in total
+    MaxViews { max_views: u32 },
+    /// Viewers may only hand access to members of one of the owner's groups
+    Group { group: String },
+}
 
-       y_offset += img.height();
+impl ResharePolicy {
+    fn of(image_data: &serde_json::Value) -> Self {
+        serde_json::from_value(image_data["reshare_policy"].clone()).unwrap_or_default()
     }
+}
 
-    Ok(DynamicImage::ImageRgba8(composite))
+/// Who handed a viewer access to an image, stored per image under `delegations`
+/// so that the chain back to the owner can be followed.
+#[derive(Serialize, Deserialize, Clone)]
+struct Delegation {
+    delegated_by: String,
+    views: u32,
+    delegated_at: String,
 }
 
+/// Checks passing `views` more on against a `MaxViews` re-share budget. The error holds how many
+/// views `delegator` already passed on.
+fn check_reshare_budget(delegations: &HashMap<String, Delegation>, delegator: &str, views: u32, max_views: u32) -> Result<(), u32> {
+    let delegated: u32 = delegations
+        .values()
+        .filter(|delegation| delegation.delegated_by == delegator)
+        .fold(0u32, |total, delegation| total.saturating_add(delegation.views));
+    if matches!(delegated.checked_add(views), Some(total) if total <= max_views) {
+        Ok(())
+    } else {
+        Err(delegated)
+    }
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let own_address = "10.7.17.50:8081";
-    let peer_addresses = vec!["10.7.17.88:8080", "10.7.17.155:8082"];
-    let socket = Arc::new(UdpSocket::bind(own_address).await?);
-    let jsons_addresses = vec!["10.7.17.88:5000", "10.7.17.155:5002"];
-    //  Directory::new().save_to_file("directory.json");
-    // ClientDirectory::new().save_to_file("clients.json");
+/// `viewer` and everyone who got access to the image through them, directly or further down the chain.
+fn delegation_subtree(image_data: &serde_json::Value, viewer: &str) -> Vec<String> {
+    let delegations: HashMap<String, Delegation> =
+        serde_json::from_value(image_data["delegations"].clone()).unwrap_or_default();
+    let mut subtree = vec![viewer.to_string()];
+    let mut index = 0;
+    while index < subtree.len() {
+        for (delegate, delegation) in &delegations {
+            if delegation.delegated_by == subtree[index] && !subtree.contains(delegate) {
+                subtree.push(delegate.clone());
+            }
+        }
+        index += 1;
+    }
+    subtree
+}
 
-    // Load shared directory and client directory
-    let directory: SharedDirectory = Arc::new(Mutex::new(Directory::load_from_file("directory.json")));
-    let client_directory: SharedClientDirectory = Arc::new(Mutex::new(ClientDirectory::load_from_file("clients.json")));
-    println!("Server running at {}", own_address);
+/// Where the views a viewer is using on an image come from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum GrantSource {
+    Direct,
+    Group(String),
+    Album(String),
+}
 
-    // Leader Election Task
-    let socket_clone = Arc::clone(&socket);
-    let server_task = tokio::spawn(leader_election(socket_clone, peer_addresses));
+/// Optional time limits of one viewer's grant, in seconds since the epoch.
+/// Stored per image under `access_windows`, next to the view counts in `access_users`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct AccessWindow {
+    #[serde(default)]
+    not_before: Option<u64>,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
 
-    // Define addresses for the JSON listener
-    let listener_addresses = "10.7.17.50:5001";
+impl AccessWindow {
+    fn of(image_data: &serde_json::Value, viewer: &str) -> Self {
+        serde_json::from_value(image_data["access_windows"][viewer].clone()).unwrap_or_default()
+    }
 
-    // Spawn the listener task
-    let listener_task = task::spawn(async move {
-        if let Err(err) = listen_and_save_json(&listener_addresses).await {
-            eprintln!("Error in listener task: {}", err);
-        }
-    });
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 
-    // DOS Server Task
-    let ip = [10, 7, 17, 50];
-    let port = 8084;
-    let dos_task = run_dos(ip, port).await;
+    fn is_open(&self, now: u64) -> bool {
+        self.not_before.is_none_or(|not_before| not_before <= now) && !self.is_expired(now)
+    }
+}
 
-    // Sender Task (periodically sends JSON files to peers)
-    let sender_task = tokio::spawn(async move {
-        send_json_files_to_peers(
-            &jsons_addresses,
-            "directory.json",
-            "clients.json"
-        ).await
-    });
+fn unix_now() -> u64 {
+    Utc::now().timestamp().max(0) as u64
+}
 
-   
+/// Same rule as the clients apply: request IDs end up in file names, so no path separators.
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 64
+        && request_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
 
-    // Run all tasks concurrently
-    tokio::try_join!(server_task, dos_task, listener_task,sender_task)?;
+#[derive(Serialize, Deserialize, Clone)]
+struct Delivery {
+    request_id: String,
+    owner: String,
+    image_name: String,
+    data: String, // Base64 image, already encrypted for the viewer by the owner's client
+    uploaded_at: String,
+}
 
-    Ok(())
+#[derive(Serialize, Deserialize, Clone)]
+struct DeliveryDirectory {
+    deliveries: HashMap<String, Vec<Delivery>>, // Keyed by viewer ID, waiting until the viewer comes online
 }
 
+impl DeliveryDirectory {
+    fn new() -> Self {
+        DeliveryDirectory {
+            deliveries: HashMap::new(),
+        }
+    }
 
+    fn load_from_file(file_path: &str) -> Self {
+        let file = match std::fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => std::fs::File::create(file_path).unwrap(),
+        };
 
+        let reader = BufReader::new(file);
+        serde_json::from_reader(reader).unwrap_or_else(|_| DeliveryDirectory::new())
+    }
 
-pub async fn run_dos(ip: [u8; 4], port: u16) -> tokio::task::JoinHandle<()> {
-    let file_path = "directory.json";
-    let clients_file_path = "clients.json";
-    let directory: SharedDirectory = Arc::new(Mutex::new(Directory::load_from_file(file_path)));
-    let client_directory: SharedClientDirectory = Arc::new(Mutex::new(ClientDirectory::load_from_file(clients_file_path)));
-    let notifications_file_path = "notifications.json";
+    fn save_to_file(&self, file_path: &str) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap();
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).unwrap();
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Revocation {
+    owner: String,
+    image_name: String,
+    revoked_at: String,
+    #[serde(default)]
+    wiped_at: Option<String>, // Set once the viewer's client reports its copies were deleted
+    #[serde(default)]
+    wiped_copies: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RevocationDirectory {
+    revocations: HashMap<String, Vec<Revocation>>, // Keyed by viewer ID
+}
+
+impl RevocationDirectory {
+    fn new() -> Self {
+        RevocationDirectory {
+            revocations: HashMap::new(),
+        }
+    }
+
+    fn load_from_file(file_path: &str) -> Self {
+        let file = match std::fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => std::fs::File::create(file_path).unwrap(),
+        };
+
+        let reader = BufReader::new(file);
+        serde_json::from_reader(reader).unwrap_or_else(|_| RevocationDirectory::new())
+    }
+
+    fn save_to_file(&self, file_path: &str) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap();
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).unwrap();
+    }
+
+    /// Drops a viewer's revocation of an image once they are granted access to it again,
+    /// so their client doesn't wipe the new delivery.
+    fn clear(&mut self, viewer: &str, owner: &str, image_name: &str) {
+        if let Some(revocations) = self.revocations.get_mut(viewer) {
+            revocations.retain(|revocation| revocation.owner != owner || revocation.image_name != image_name);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Directory {
+    clients: HashMap<String, Vec<String>>,
+}
+impl Directory {
+    fn new() -> Self {
+        Directory {
+            clients: HashMap::new(),
+        }
+    }
+
+    fn load_from_file(file_path: &str) -> Self {
+        let file = match std::fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => std::fs::File::create(file_path).unwrap(),
+        };
+
+        let reader = BufReader::new(file);
+        serde_json::from_reader(reader).unwrap_or_else(|_| Directory::new())
+    }
+
+    fn clear(&mut self) {
+        self.clients.clear();
+    }
+
+    fn save_to_file(&self, file_path: &str) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap();
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).unwrap();
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ClientInfo {
+    id: String,
+    password: String,
+    current_ip: Option<String>,
+    #[serde(default)]
+    public_key: Option<String>, // Base64 X25519 public key, owners encrypt shared images against it
+    #[serde(default)]
+    signing_key: Option<String>, // Base64 Ed25519 public key, viewers verify delivery metadata with it
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ClientDirectory {
+    clients: HashMap<String, ClientInfo>,
+}
+
+impl ClientDirectory {
+    fn new() -> Self {
+        ClientDirectory {
+            clients: HashMap::new(),
+        }
+    }
+
+    fn load_from_file(file_path: &str) -> Self {
+        let file = match std::fs::File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => std::fs::File::create(file_path).unwrap(),
+        };
+
+        let reader = BufReader::new(file);
+        serde_json::from_reader(reader).unwrap_or_else(|_| ClientDirectory::new())
+    }
+
+    fn save_to_file(&self, file_path: &str) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_path)
+            .unwrap();
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).unwrap();
+    }
+}
+
+type SharedClientDirectory = Arc<Mutex<ClientDirectory>>;
+type SharedDirectory = Arc<Mutex<Directory>>;
+fn with_directory(
+    directory: SharedDirectory,
+) -> impl Filter<Extract = (SharedDirectory,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || directory.clone())
+}
+fn with_client_directory(
+    client_directory: SharedClientDirectory,
+) -> impl Filter<Extract = (SharedClientDirectory,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || client_directory.clone())
+}
+fn with_notifier(
+    notifier: broadcast::Sender<String>,
+) -> impl Filter<Extract = (broadcast::Sender<String>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || notifier.clone())
+}
+/// Records an event in events.json and publishes it on the notifier. Events with no recipients
+/// go to every connected client, the others only to the listed clients.
+fn publish_event(notifier: &broadcast::Sender<String>, event: &str, recipients: &[&str], details: serde_json::Value) {
+    let mut payload = json!({
+        "event": event,
+        "recipients": recipients,
+        "timestamp": Utc::now().to_rfc3339()
+    });
+    if let (Some(payload), Some(details)) = (payload.as_object_mut(), details.as_object()) {
+        payload.extend(details.clone());
+    }
+
+    // Persist before pushing so a client that misses the push can still replay it
+    let payload = {
+        let _guard = EVENT_LOG_LOCK.lock().unwrap();
+        let mut event_log = EventLog::load_from_file("events.json");
+        let payload = event_log.append(payload);
+        event_log.save_to_file("events.json");
+        payload
+    };
+    let _ = notifier.send(payload.to_string());
+}
+
+fn is_event_recipient(event: &serde_json::Value, client_id: &str) -> bool {
+    match event.get("recipients").and_then(|v| v.as_array()) {
+        Some(recipients) => recipients.is_empty() || recipients.iter().any(|r| r.as_str() == Some(client_id)),
+        None => true,
+    }
+}
+
+fn without_recipients(event: &serde_json::Value) -> serde_json::Value {
+    let mut event = event.clone();
+    if let Some(event) = event.as_object_mut() {
+        event.remove("recipients");
+    }
+    event
+}
+
+/// The log's current epoch and the client's events after its cursor.
+fn events_since(client_id: &str, epoch: &str, since: u64) -> (String, Vec<serde_json::Value>) {
+    let _guard = EVENT_LOG_LOCK.lock().unwrap();
+    let event_log = EventLog::load_from_file("events.json");
+    let events = event_log.since(client_id, epoch, since);
+    (event_log.epoch, events)
+}
+
+/// Streams the client's events, starting with the ones logged after `since` so nothing is lost
+/// between connections. Events are sent in ID order and each ID at most once.
+async fn handle_ws_connection(ws: WebSocket, mut rx: broadcast::Receiver<String>, client_id: String, epoch: String, since: u64) {
+    let (mut ws_tx, _ws_rx) = ws.split();
+
+    // The receiver was subscribed before replaying, so events published meanwhile are not missed
+    let (current_epoch, mut backlog) = events_since(&client_id, &epoch, since);
+    let mut last_sent_id = if current_epoch == epoch { since } else { 0 };
+    loop {
+        for event in backlog.drain(..) {
+            let id = event["id"].as_u64().unwrap_or(0);
+            if id <= last_sent_id {
+                continue;
+            }
+            if ws_tx.send(Message::text(event.to_string())).await.is_err() {
+                return; // Exit if the WebSocket connection is closed
+            }
+            last_sent_id = id;
+        }
+
+        match rx.recv().await {
+            Ok(msg) => {
+                // Only forward events meant for everyone or for this client, without the recipient list
+                if let Ok(event) = serde_json::from_str::<serde_json::Value>(&msg) {
+                    if is_event_recipient(&event, &client_id) {
+                        backlog.push(without_recipients(&event));
+                    }
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                // The channel dropped events for us, catch up from the log instead
+                backlog = events_since(&client_id, &current_epoch, last_sent_id).1;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Adds or updates the views of each viewer in `access_rights` on one of `owner`'s images, and
+/// the time limits of those in `access_windows`. Returns false if the owner has no image with that name.
+fn grant_access(
+    directory: &mut Directory,
+    owner: &str,
+    image_name: &str,
+    access_rights: HashMap<String, u32>,
+    access_windows: HashMap<String, AccessWindow>,
+) -> bool {
+    let Some(images) = directory.clients.get_mut(owner) else {
+        return false;
+    };
+    let Some(image_entry) = images.iter_mut().find(|img| {
+        let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+        image_data["name"] == image_name
+    }) else {
+        return false;
+    };
+    let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+
+    // Update or initialize access rights
+    if let Some(existing_access) = image_data.get_mut("access_users").and_then(|v| v.as_object_mut()) {
+        // Add or update the provided access rights
+        for (other_client, allowed_views) in access_rights {
+            existing_access.insert(other_client, serde_json::Value::from(allowed_views));
+        }
+    } else {
+        // Initialize access_users if it doesn't exist
+        let new_access: serde_json::Map<String, serde_json::Value> = access_rights
+            .into_iter()
+            .map(|(client, views)| (client, serde_json::Value::from(views)))
+            .collect();
+        image_data["access_users"] = serde_json::Value::Object(new_access);
+    }
+
+    for (viewer, window) in access_windows {
+        image_data["access_windows"][viewer.as_str()] = json!(window);
+    }
+
+    *image_entry = serde_json::to_string(&image_data).unwrap();
+    true
+}
+
+/// Grants every member of each of `owner`'s groups in `group_access` that many views of the image.
+/// Views used so far by the group's members are tracked under `group_views` and start over.
+/// Returns false if the owner has no image with that name.
+fn grant_group_access(directory: &mut Directory, owner: &str, image_name: &str, group_access: &HashMap<String, u32>) -> bool {
+    let Some(images) = directory.clients.get_mut(owner) else {
+        return false;
+    };
+    let Some(image_entry) = images.iter_mut().find(|img| {
+        let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+        image_data["name"] == image_name
+    }) else {
+        return false;
+    };
+    let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+
+    for (group, quota) in group_access {
+        image_data["group_access"][group.as_str()] = json!(quota);
+        image_data["group_views"][group.as_str()] = json!({});
+    }
+
+    *image_entry = serde_json::to_string(&image_data).unwrap();
+    true
+}
+
+/// Views `viewer` has left on one of `owner`'s images: their own grant if it has views left,
+/// otherwise the group or album grant with the most views left. Views used through a group or
+/// album grant are counted on the image under `group_views` and `album_views`.
+fn resolve_access(
+    image_data: &serde_json::Value,
+    owner: &str,
+    viewer: &str,
+    groups: &GroupDirectory,
+    albums: &AlbumDirectory,
+) -> Option<(u64, GrantSource)> {
+    if let Some(views) = image_data["access_users"][viewer].as_u64().filter(|views| *views > 0) {
+        return Some((views, GrantSource::Direct));
+    }
+
+    let group_grants = image_data["group_access"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(group, _)| groups.is_member(owner, group, viewer))
+        .map(|(group, quota)| {
+            let used = image_data["group_views"][group.as_str()][viewer].as_u64().unwrap_or(0);
+            (quota.as_u64().unwrap_or(0).saturating_sub(used), GrantSource::Group(group.clone()))
+        });
+    let image_name = image_data["name"].as_str().unwrap_or_default();
+    let album_grants = albums.containing(owner, image_name).filter_map(|(album_name, album)| {
+        let quota = album.quota(owner, viewer, groups)?;
+        let used = image_data["album_views"][album_name.as_str()][viewer].as_u64().unwrap_or(0);
+        Some((u64::from(quota).saturating_sub(used), GrantSource::Album(album_name.clone())))
+    });
+
+    group_grants
+        .chain(album_grants)
+        .filter(|(views, _)| *views > 0)
+        .max_by_key(|(views, _)| *views)
+}
+
+/// Takes `views` off what `viewer` has left through `source`, as reported by `resolve_access`.
+/// Callers check there are that many views left first.
+fn consume_views(image_data: &mut serde_json::Value, viewer: &str, source: &GrantSource, views: u64) {
+    match source {
+        GrantSource::Direct => {
+            let left = image_data["access_users"][viewer].as_u64().unwrap_or(0);
+            image_data["access_users"][viewer] = json!(left.saturating_sub(views));
+        }
+        GrantSource::Group(name) | GrantSource::Album(name) => {
+            let field = if matches!(source, GrantSource::Group(_)) { "group_views" } else { "album_views" };
+            let used = image_data[field][name.as_str()][viewer].as_u64().unwrap_or(0);
+            image_data[field][name.as_str()][viewer] = json!(used.saturating_add(views));
+        }
+    }
+}
+
+/// Gives back `views` that `consume_views` took through `source`, for a view that could not be decoded.
+/// Nothing is given back if the grant was removed in the meantime.
+fn refund_views(image_data: &mut serde_json::Value, viewer: &str, source: &GrantSource, views: u64) {
+    match source {
+        GrantSource::Direct => {
+            if let Some(left) = image_data["access_users"][viewer].as_u64() {
+                image_data["access_users"][viewer] = json!(left.saturating_add(views));
+            }
+        }
+        GrantSource::Group(name) | GrantSource::Album(name) => {
+            let field = if matches!(source, GrantSource::Group(_)) { "group_views" } else { "album_views" };
+            if let Some(used) = image_data[field][name.as_str()][viewer].as_u64() {
+                image_data[field][name.as_str()][viewer] = json!(used.saturating_sub(views));
+            }
+        }
+    }
+}
+
+/// Everyone holding a grant on one of `owner`'s images, whether or not they have views left.
+fn granted_viewers(image_data: &serde_json::Value, owner: &str, groups: &GroupDirectory, albums: &AlbumDirectory) -> Vec<String> {
+    let mut viewers: Vec<String> = image_data["access_users"]
+        .as_object()
+        .map(|access_users| access_users.keys().cloned().collect())
+        .unwrap_or_default();
+    let image_name = image_data["name"].as_str().unwrap_or_default();
+    let group_members = image_data["group_access"]
+        .as_object()
+        .into_iter()
+        .flat_map(|group_access| group_access.keys())
+        .flat_map(|group| groups.members(owner, group).cloned().unwrap_or_default());
+    let album_viewers = albums.containing(owner, image_name).flat_map(|(_, album)| album.viewers(owner, groups));
+    for viewer in group_members.chain(album_viewers).collect::<Vec<_>>() {
+        if !viewers.contains(&viewer) {
+            viewers.push(viewer);
+        }
+    }
+    viewers
+}
+
+/// Records a revocation for each of `viewers` no longer holding any grant on one of `owner`'s
+/// images, so their clients wipe the copies they have, and tells them. Returns those viewers.
+fn revoke_lost_access(
+    notifier: &broadcast::Sender<String>,
+    owner: &str,
+    image_name: &str,
+    viewers: &[String],
+    details: serde_json::Value,
+) -> Vec<String> {
+    let guard = DIRECTORY_LOCK.lock().unwrap();
+    let directory = Directory::load_from_file("directory.json");
+    let group_directory = GroupDirectory::load_from_file("groups.json");
+    let album_directory = AlbumDirectory::load_from_file("albums.json");
+    let Some(image_data) = directory.clients.get(owner).into_iter().flatten().find_map(|img| {
+        serde_json::from_str::<serde_json::Value>(img).ok().filter(|image_data| image_data["name"] == image_name)
+    }) else {
+        return Vec::new();
+    };
+
+    let still_granted = granted_viewers(&image_data, owner, &group_directory, &album_directory);
+    let revoked: Vec<String> = viewers.iter().filter(|viewer| !still_granted.contains(viewer)).cloned().collect();
+    if revoked.is_empty() {
+        return revoked;
+    }
+
+    let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+    for viewer in &revoked {
+        record_audit(owner, "revoke", owner, image_name, viewer, details.clone());
+        revocation_directory.clear(viewer, owner, image_name);
+        revocation_directory.revocations.entry(viewer.clone()).or_default().push(Revocation {
+            owner: owner.to_string(),
+            image_name: image_name.to_string(),
+            revoked_at: Utc::now().to_rfc3339(),
+            wiped_at: None,
+            wiped_copies: 0,
+        });
+    }
+    revocation_directory.save_to_file("revocations.json");
+    drop(guard);
+
+    let recipients: Vec<&str> = revoked.iter().map(String::as_str).collect();
+    publish_event(notifier, "access_revoked", &recipients, json!({
+        "message": format!("{} revoked your access to {}", owner, image_name),
+        "owner": owner,
+        "image_name": image_name
+    }));
+    revoked
+}
+
+/// Removes grants whose `expires_at` is not after `now`, with their key shares and pending views.
+/// Returns the owner, image name and viewer of each.
+fn expire_grants(directory: &mut Directory, now: u64) -> Vec<(String, String, String)> {
+    let mut expired = Vec::new();
+
+    for (owner, images) in directory.clients.iter_mut() {
+        for image_entry in images.iter_mut() {
+            let Ok(mut image_data) = serde_json::from_str::<serde_json::Value>(image_entry) else {
+                continue;
+            };
+            let expired_viewers: Vec<String> = image_data["access_windows"]
+                .as_object()
+                .map(|windows| {
+                    windows
+                        .keys()
+                        .filter(|viewer| AccessWindow::of(&image_data, viewer).is_expired(now))
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default();
+            if expired_viewers.is_empty() {
+                continue;
+            }
+
+            for viewer in &expired_viewers {
+                for field in ["access_users", "access_windows", "view_shares", "view_grants"] {
+                    if let Some(entries) = image_data.get_mut(field).and_then(|v| v.as_object_mut()) {
+                        entries.remove(viewer);
+                    }
+                }
+                expired.push((owner.clone(), image_data["name"].as_str().unwrap_or_default().to_string(), viewer.clone()));
+            }
+            *image_entry = serde_json::to_string(&image_data).unwrap();
+        }
+    }
+    expired
+}
+
+/// Removes grants whose `expires_at` has passed and tells the owners and viewers.
+fn sweep_expired_grants(notifier: &broadcast::Sender<String>) {
+    let _guard = DIRECTORY_LOCK.lock().unwrap();
+    let mut directory = Directory::load_from_file("directory.json");
+    let expired = expire_grants(&mut directory, unix_now());
+    if expired.is_empty() {
+        return;
+    }
+    directory.save_to_file("directory.json");
+    for (owner, image_name, viewer) in expired {
+        record_audit("dos", "expire", &owner, &image_name, &viewer, json!({}));
+        publish_event(notifier, "grant_expired", &[owner.as_str(), viewer.as_str()], json!({
+            "message": format!("Access of {} to {}'s image {} expired", viewer, owner, image_name),
+            "owner": owner,
+            "viewer": viewer,
+            "image_name": image_name
+        }));
+    }
+}
+
+/// Stable ID of one of `owner`'s images, usable in URLs whatever the image is called.
+fn image_id(owner: &str, image_name: &str) -> String {
+    let digest = Sha256::digest(format!("{}/{}", owner, image_name).as_bytes());
+    digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Layout of one page of the gallery composite, from the `columns`, `page` (from 1) and
+/// `page_size` query parameters, clamped so the composite stays within the maximum dimensions.
+struct GridLayout {
+    columns: u32,
+    page: usize,
+    page_size: usize,
+}
+
+impl GridLayout {
+    fn from_query(query: &HashMap<String, String>) -> Self {
+        let max_columns = MAX_COMPOSITE_WIDTH / COMPOSITE_CELL_SIZE;
+        let max_rows = MAX_COMPOSITE_HEIGHT / (COMPOSITE_CELL_SIZE + COMPOSITE_CAPTION_HEIGHT);
+        let columns = query
+            .get("columns")
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_COMPOSITE_COLUMNS)
+            .clamp(1, max_columns);
+        let page = query.get("page").and_then(|v| v.parse::<usize>().ok()).unwrap_or(1).max(1);
+        let page_size = query
+            .get("page_size")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_COMPOSITE_PAGE_SIZE)
+            .clamp(1, (columns * max_rows) as usize);
+        GridLayout { columns, page, page_size }
+    }
+}
+
+/// Renders one page of `images` (image JSON with a `client_id` field) as a PNG grid.
+/// Images are ordered by owner and name so pages stay stable between requests; the page count
+/// is returned in the `X-Total-Pages` header.
+fn composite_page_response(mut images: Vec<serde_json::Value>, layout: &GridLayout) -> warp::http::Response<Vec<u8>> {
+    images.sort_by(|a, b| {
+        (a["client_id"].as_str(), a["name"].as_str()).cmp(&(b["client_id"].as_str(), b["name"].as_str()))
+    });
+    let total_pages = images.len().div_ceil(layout.page_size).max(1);
+    let page: Vec<serde_json::Value> = images
+        .into_iter()
+        .skip((layout.page - 1).saturating_mul(layout.page_size))
+        .take(layout.page_size)
+        .collect();
+    if page.is_empty() {
+        return warp::http::Response::builder()
+            .status(404)
+            .header("Content-Type", "text/plain")
+            .header("X-Total-Pages", total_pages.to_string())
+            .body(format!("Page {} is empty, there are {} pages", layout.page, total_pages).into_bytes())
+            .unwrap();
+    }
+
+    match create_composite_image(&page, layout.columns) {
+        Ok(composite_image) => {
+            let mut buffer = Cursor::new(Vec::new());
+            if composite_image.write_to(&mut buffer, ImageOutputFormat::Png).is_err() {
+                return warp::http::Response::builder()
+                    .status(500)
+                    .header("Content-Type", "text/plain")
+                    .body("Failed to encode composite image".to_string().into_bytes())
+                    .unwrap();
+            }
+
+            warp::http::Response::builder()
+                .header("Content-Type", "image/png")
+                .header("X-Total-Pages", total_pages.to_string())
+                .body(buffer.into_inner())
+                .unwrap()
+        }
+        Err(e) => warp::http::Response::builder()
+            .status(500)
+            .header("Content-Type", "text/plain")
+            .body(format!("Failed to create composite image: {}", e).into_bytes())
+            .unwrap(),
+    }
+}
+
+/// Lays the images out in a grid, `columns` wide, each scaled to fit its cell and captioned
+/// with its owner and name in a dark box underneath.
+fn create_composite_image(
+    images: &[serde_json::Value],
+    columns: u32,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    const FONT_PATH: &str = "Roboto-Bold.ttf"; // Update to the correct font path
+    let font_data = std::fs::read(FONT_PATH)?;
+    let font = Font::try_from_vec(font_data).ok_or("Failed to load font")?;
+
+    let cell_height = COMPOSITE_CELL_SIZE + COMPOSITE_CAPTION_HEIGHT;
+    let rows = (images.len() as u32).div_ceil(columns);
+    let width = (columns.min(images.len() as u32) * COMPOSITE_CELL_SIZE).min(MAX_COMPOSITE_WIDTH);
+    let height = (rows * cell_height).min(MAX_COMPOSITE_HEIGHT);
+    let mut composite = ImageBuffer::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+
+    let scale = Scale { x: 18.0, y: 18.0 };
+    let max_caption_chars = (COMPOSITE_CELL_SIZE / 10) as usize;
+    for (index, image_json) in images.iter().enumerate() {
+        let x = (index as u32 % columns) * COMPOSITE_CELL_SIZE;
+        let y = (index as u32 / columns) * cell_height;
+        if x + COMPOSITE_CELL_SIZE > width || y + cell_height > height {
+            break;
+        }
+
+        let image_name = image_json["name"].as_str().unwrap_or("Unknown");
+        let client_id = image_json["client_id"].as_str().unwrap_or("Unknown");
+        let image_base64 = image_json["data"].as_str().unwrap_or_default();
+        let decoded_bytes = general_purpose::STANDARD.decode(image_base64)?;
+        let image = image::load_from_memory(&decoded_bytes)?.thumbnail(COMPOSITE_CELL_SIZE, COMPOSITE_CELL_SIZE);
+
+        // Centre the image in its cell
+        let image_x = x + (COMPOSITE_CELL_SIZE - image.width()) / 2;
+        let image_y = y + (COMPOSITE_CELL_SIZE - image.height()) / 2;
+        composite.copy_from(&image.to_rgba8(), image_x, image_y)?;
+
+        // White text on a dark box stays readable whatever the image looks like
+        draw_filled_rect_mut(
+            &mut composite,
+            Rect::at(x as i32, (y + COMPOSITE_CELL_SIZE) as i32).of_size(COMPOSITE_CELL_SIZE, COMPOSITE_CAPTION_HEIGHT),
+            Rgba([30, 30, 30, 255]),
+        );
+        let lines = [format!("Client: {}", client_id), format!("Name: {}", image_name)];
+        let mut text_y_offset = (y + COMPOSITE_CELL_SIZE) as i32 + 4;
+        for line in lines {
+            let line: String = line.chars().take(max_caption_chars).collect();
+            draw_text_mut(&mut composite, Rgba([255, 255, 255, 255]), x as i32 + 6, text_y_offset, scale, &font, &line);
+            text_y_offset += scale.y as i32 + 4;
+        }
+    }
+
+    Ok(DynamicImage::ImageRgba8(composite))
+}
+
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let own_address = SERVER_ADDRESS;
+    let peer_addresses = vec!["10.7.17.88:8080", "10.7.17.155:8082"];
+    let socket = Arc::new(UdpSocket::bind(own_address).await?);
+    let jsons_addresses = vec!["10.7.17.88:5000", "10.7.17.155:5002"];
+    //  Directory::new().save_to_file("directory.json");
+    // ClientDirectory::new().save_to_file("clients.json");
+
+    // Load shared directory and client directory
+    let directory: SharedDirectory = Arc::new(Mutex::new(Directory::load_from_file("directory.json")));
+    let client_directory: SharedClientDirectory = Arc::new(Mutex::new(ClientDirectory::load_from_file("clients.json")));
+    println!("Server running at {}", own_address);
+
+    // Leader Election Task
+    let socket_clone = Arc::clone(&socket);
+    let server_task = tokio::spawn(leader_election(socket_clone, peer_addresses));
+
+    // Define addresses for the JSON listener
+    let listener_addresses = "10.7.17.50:5001";
+
+    // Spawn the listener task
+    let listener_task = task::spawn(async move {
+        if let Err(err) = listen_and_save_json(&listener_addresses).await {
+            eprintln!("Error in listener task: {}", err);
+        }
+    });
+
+    // DOS Server Task
+    let ip = [10, 7, 17, 50];
+    let port = 8084;
+    let dos_task = run_dos(ip, port).await;
+
+    // Sender Task (periodically sends JSON files to peers)
+    let sender_task = tokio::spawn(async move {
+        send_json_files_to_peers(
+            &jsons_addresses,
+            "directory.json",
+            "clients.json"
+        ).await
+    });
+
+   
+
+    // Run all tasks concurrently
+    tokio::try_join!(server_task, dos_task, listener_task,sender_task)?;
+
+    Ok(())
+}
+
+
+
+
+pub async fn run_dos(ip: [u8; 4], port: u16) -> tokio::task::JoinHandle<()> {
+    let file_path = "directory.json";
+    let clients_file_path = "clients.json";
+    let directory: SharedDirectory = Arc::new(Mutex::new(Directory::load_from_file(file_path)));
+    let client_directory: SharedClientDirectory = Arc::new(Mutex::new(ClientDirectory::load_from_file(clients_file_path)));
+    let notifications_file_path = "notifications.json";
     let notification_directory: Arc<Mutex<NotificationDirectory>> =
         Arc::new(Mutex::new(NotificationDirectory::load_from_file(notifications_file_path)));
     // Directory::new().save_to_file("directory.json");
     // ClientDirectory::new().save_to_file("clients.json");
 
-    let (notifier_tx, _) = broadcast::channel(100);
-    tokio::spawn(async move {
-        loop {
-            let file_path = "directory.json";
-            let clients_file_path = "clients.json";
-            let directory: SharedDirectory = Arc::new(Mutex::new(Directory::load_from_file(file_path)));
-            let client_directory: SharedClientDirectory = Arc::new(Mutex::new(ClientDirectory::load_from_file(clients_file_path)));
-           
-    
-            // Wait for 10 seconds before the next iteration
-            tokio::time::sleep(Duration::from_secs(10)).await;
+    let (notifier_tx, _) = broadcast::channel(100);
+    let sweeper_notifier = notifier_tx.clone();
+    tokio::spawn(async move {
+        loop {
+            let file_path = "directory.json";
+            let clients_file_path = "clients.json";
+            let directory: SharedDirectory = Arc::new(Mutex::new(Directory::load_from_file(file_path)));
+            let client_directory: SharedClientDirectory = Arc::new(Mutex::new(ClientDirectory::load_from_file(clients_file_path)));
+
+            // Only the leader edits the directory, the others get it through replication
+            if is_leader() {
+                sweep_expired_grants(&sweeper_notifier);
+            }
+    
+            // Wait for 10 seconds before the next iteration
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+    
+
+    
+    let add_image = warp::path("add_image")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, serde_json::Value>, notifier: broadcast::Sender<String>| {
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        // Extract and validate required fields
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_data = body.get("image_data").and_then(|v| v.as_str()).unwrap_or_default();
+
+        // Extract access_users as a map (client_id -> allowed views)
+        let access_users: HashMap<String, u32> = match body.get("access_users") {
+            Some(users) => serde_json::from_value(users.clone()).unwrap_or_else(|_| HashMap::new()),
+            None => HashMap::new(),
+        };
+        // Optional time limits per viewer (client_id -> not_before / expires_at)
+        let access_windows: HashMap<String, AccessWindow> = match body.get("access_windows") {
+            Some(windows) => serde_json::from_value(windows.clone()).unwrap_or_else(|_| HashMap::new()),
+            None => HashMap::new(),
+        };
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Add image to directory
+        let images = directory.clients.entry(client_id.to_string()).or_insert_with(Vec::new);
+        images.push(
+            json!({
+                "name": image_name,
+                "data": image_data,
+                "access_users": access_users,
+                "access_windows": access_windows,
+                "created_at": Utc::now().to_rfc3339()
+            })
+            .to_string(),
+        );
+
+        // Save updated directory to file
+        directory.save_to_file("directory.json");
+
+        // Send notification
+        let notification = format!("Client {} added image {}", client_id, image_name);
+        publish_event(&notifier, "image_added", &[], json!({
+            "message": notification,
+            "client_id": client_id,
+            "image_name": image_name
+        }));
+
+        warp::reply::json(&notification)
+    });
+
+
+
+    
+    let delete_image = warp::path("delete_image")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_notifier(notifier_tx.clone()))
+        .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+            let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+            let client_directory = ClientDirectory::load_from_file("clients.json");
+
+            let client_id = body.get("client_id").unwrap();
+            let password = body.get("password").unwrap();
+            let image_name = body.get("image_name").unwrap();
+
+            if let Some(client_info) = client_directory.clients.get(client_id) {
+                if client_info.password != *password {
+                    return warp::reply::json(&json!({ "error": "Authentication failed" }));
+                }
+            } else {
+                return warp::reply::json(&json!({ "error": "Client ID not found" }));
+            }
+
+            if let Some(images) = directory.clients.get_mut(client_id) {
+                if let Some(pos) = images.iter().position(|img| {
+                    let image_data: serde_json::Value = serde_json::from_str(img).unwrap();
+                    image_data["name"] == *image_name
+                }) {
+                    images.remove(pos);
+                    directory.save_to_file("directory.json");
+
+                    let mut album_directory = AlbumDirectory::load_from_file("albums.json");
+                    for album in album_directory.albums.get_mut(client_id).into_iter().flat_map(|albums| albums.values_mut()) {
+                        album.images.retain(|image| image != image_name);
+                    }
+                    album_directory.save_to_file("albums.json");
+                    let notification = json!({
+                        "message": format!("Client {} deleted image {}", client_id, image_name),
+                        "client_id": client_id,
+                        "image_name": image_name
+                    });
+                    publish_event(&notifier, "image_deleted", &[], notification.clone());
+                    return warp::reply::json(&notification);
+                }
+            }
+
+            warp::reply::json(&json!({
+                "error": format!("Image {} not found for client {}", image_name, client_id)
+            }))
+        });
+
+
+
+    
+    // What the caller can see in the gallery, as JSON; `list_all` still renders it as one image
+    let gallery = warp::path("gallery")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
+
+        let directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+        let album_directory = AlbumDirectory::load_from_file("albums.json");
+        let notification_directory = NotificationDirectory::load_from_file("notifications.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Optionally only one owner's images, or one of their albums
+        let album_images = match (query.get("owner"), query.get("album")) {
+            (Some(owner), Some(album_name)) => {
+                match album_directory.albums.get(owner).and_then(|albums| albums.get(album_name)) {
+                    Some(album) => Some(album.images.clone()),
+                    None => return warp::reply::json(&json!({ "error": format!("Album '{}' not found", album_name) })),
+                }
+            }
+            _ => None,
+        };
+
+        let now = unix_now();
+        let cutoff = Utc::now() - chrono::Duration::days(NOTIFICATION_TTL_DAYS);
+        let mut listing = Vec::new();
+        for (owner, images) in &directory.clients {
+            if query.get("owner").is_some_and(|wanted| wanted != owner) {
+                continue;
+            }
+            for image in images {
+                let Ok(image_data) = serde_json::from_str::<serde_json::Value>(image) else {
+                    continue;
+                };
+                let image_name = image_data["name"].as_str().unwrap_or_default();
+                if album_images.as_ref().is_some_and(|album_images| !album_images.iter().any(|name| name == image_name)) {
+                    continue;
+                }
+
+                // Owners see their own images without a view count
+                let remaining_views = if owner == client_id {
+                    None
+                } else if AccessWindow::of(&image_data, client_id).is_open(now) {
+                    Some(resolve_access(&image_data, owner, client_id, &group_directory, &album_directory).map_or(0, |(views, _)| views))
+                } else {
+                    Some(0)
+                };
+                let pending_request = notification_directory
+                    .notifications
+                    .get(owner)
+                    .into_iter()
+                    .flatten()
+                    .filter(|n| {
+                        n.kind == NotificationKind::AccessRequest
+                            && n.status == NotificationStatus::Pending
+                            && n.requester == *client_id
+                            && n.image_name == image_name
+                    })
+                    .find(|n| {
+                        chrono::DateTime::parse_from_rfc3339(&n.created_at)
+                            .map(|created_at| created_at >= cutoff)
+                            .unwrap_or(true)
+                    })
+                    .map(|n| json!({ "id": n.id, "access_rights": n.access_rights, "created_at": n.created_at }));
+
+                let id = image_id(owner, image_name);
+                listing.push(json!({
+                    "owner": owner,
+                    "name": image_name,
+                    "id": id,
+                    "thumbnail_url": format!("/thumbnail/{}", id),
+                    "created_at": image_data.get("created_at"),
+                    "remaining_views": remaining_views,
+                    "pending_request": pending_request
+                }));
+            }
+        }
+        listing.sort_by(|a, b| {
+            (a["owner"].as_str(), a["name"].as_str()).cmp(&(b["owner"].as_str(), b["name"].as_str()))
+        });
+
+        warp::reply::json(&json!({ "images": listing }))
+    });
+
+    let thumbnail = warp::path!("thumbnail" / String)
+    .and(warp::get())
+    .map(|id: String| {
+        let directory = Directory::load_from_file("directory.json");
+
+        let image_base64 = directory.clients.iter().find_map(|(owner, images)| {
+            images.iter().find_map(|image| {
+                let image_data: serde_json::Value = serde_json::from_str(image).ok()?;
+                if image_id(owner, image_data["name"].as_str()?) != id {
+                    return None;
+                }
+                image_data["data"].as_str().map(str::to_string)
+            })
+        });
+        let Some(image_base64) = image_base64 else {
+            return warp::http::Response::builder()
+                .status(404)
+                .header("Content-Type", "text/plain")
+                .body("Image not found".to_string().into_bytes())
+                .unwrap();
+        };
+
+        let thumbnail = general_purpose::STANDARD
+            .decode(image_base64)
+            .ok()
+            .and_then(|bytes| image::load_from_memory(&bytes).ok())
+            .map(|image| image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE));
+        let mut buffer = Cursor::new(Vec::new());
+        match thumbnail.map(|thumbnail| thumbnail.write_to(&mut buffer, ImageOutputFormat::Png)) {
+            Some(Ok(())) => warp::http::Response::builder()
+                .header("Content-Type", "image/png")
+                .body(buffer.into_inner())
+                .unwrap(),
+            _ => warp::http::Response::builder()
+                .status(500)
+                .header("Content-Type", "text/plain")
+                .body("Failed to create thumbnail".to_string().into_bytes())
+                .unwrap(),
+        }
+    });
+
+    let list_all = warp::path("list_all")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(|query: HashMap<String, String>| {
+            let directory = Directory::load_from_file("directory.json");
+
+            let mut all_images = Vec::new();
+            for (client_id, images) in &directory.clients {
+                for image in images {
+                    if let Ok(mut image_data) = serde_json::from_str::<serde_json::Value>(image) {
+                        image_data["client_id"] = serde_json::Value::String(client_id.clone());
+                        all_images.push(image_data);
+                    }
+                }
+            }
+
+            composite_page_response(all_images, &GridLayout::from_query(&query))
+        });
+
+    
+    let list_by_client = warp::path("list_by_client")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let directory = Directory::load_from_file("directory.json"); // Dynamically load JSON on every request
+
+        let default_client_id = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_client_id);
+
+        // Optionally only the images in one of the client's albums
+        let album_images = match query.get("album") {
+            Some(album_name) => {
+                let album_directory = AlbumDirectory::load_from_file("albums.json");
+                match album_directory.albums.get(client_id).and_then(|albums| albums.get(album_name)) {
+                    Some(album) => Some(album.images.clone()),
+                    None => {
+                        return warp::http::Response::builder()
+                            .status(404)
+                            .header("Content-Type", "text/plain")
+                            .body(format!("Album '{}' not found", album_name).into_bytes())
+                            .unwrap();
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let images = match directory.clients.get(client_id) {
+            Some(images) => {
+                let mut client_images = Vec::new();
+                for image in images {
+                    if let Ok(mut image_data) = serde_json::from_str::<serde_json::Value>(image) {
+                        if let Some(album_images) = &album_images {
+                            if !album_images.iter().any(|name| image_data["name"] == *name) {
+                                continue;
+                            }
+                        }
+                        image_data["client_id"] = serde_json::Value::String(client_id.clone());
+                        client_images.push(image_data);
+                    }
+                }
+                client_images
+            }
+            None => {
+                return warp::http::Response::builder()
+                    .status(404)
+                    .header("Content-Type", "text/plain")
+                    .body("No images found".to_string().into_bytes())
+                    .unwrap();
+            }
+        };
+
+        composite_page_response(images, &GridLayout::from_query(&query))
+    });
+
+      
+        let fetch_clients = warp::path("fetch_clients")
+    .and(warp::get())
+    .map(|| {
+        let client_directory = ClientDirectory::load_from_file("clients.json"); // Dynamically load JSON on every request
+
+        let clients_with_ips: HashMap<String, Option<String>> = client_directory
+            .clients
+            .iter()
+            .map(|(client_id, client_info)| {
+                (client_id.clone(), client_info.current_ip.clone())
+            })
+            .collect();
+
+        warp::reply::json(&clients_with_ips)
+    });
+
+
+    
+    let login = warp::path("login")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, String>| {
+        // Dynamically load the client directory from the JSON file
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        // Extract client_id and password from the request body
+        let default_client_id = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_client_id);
+
+        let default_password = String::new();
+        let password = body.get("password").unwrap_or(&default_password);
+
+        // Check if the client exists and validate the password
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if &client_info.password == password {
+                warp::reply::with_status(
+                    warp::reply::json(&json!({
+                        "message": "Login successful",
+                        "client_id": client_id
+                    })),
+                    warp::http::StatusCode::OK,
+                )
+            } else {
+                warp::reply::with_status(
+                    warp::reply::json(&json!({
+                        "error": "Invalid password"
+                    })),
+                    warp::http::StatusCode::UNAUTHORIZED,
+                )
+            }
+        } else {
+            warp::reply::with_status(
+                warp::reply::json(&json!({
+                    "error": "Client ID not found"
+                })),
+                warp::http::StatusCode::NOT_FOUND,
+            )
+        }
+    });
+
+       
+        let register_client = warp::path("register_client")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, String>| {
+        // Dynamically load the JSON files
+        let mut client_directory = ClientDirectory::load_from_file("clients.json");
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+
+        let client_id = body.get("id").unwrap().to_string();
+        let password = body.get("password").unwrap().to_string();
+        let public_key = body.get("public_key").cloned();
+        let signing_key = body.get("signing_key").cloned();
+
+        // The shared cover library and the admins go by these IDs, admins are provisioned in clients.json
+        if client_id == SHARED_COVERS_KEY || ADMIN_CLIENTS.contains(&client_id.as_str()) {
+            return warp::reply::json(&json!({
+                "error": format!("Client ID '{}' is reserved", client_id)
+            }));
+        }
+
+        // Check if the client already exists
+        if client_directory.clients.contains_key(&client_id) {
+            return warp::reply::json(&json!({
+                "error": "Client ID already exists"
+            }));
+        }
+
+        // Add the client to `clients.json`
+        let client_info = ClientInfo {
+            id: client_id.clone(),
+            password,
+            current_ip: None,
+            public_key,
+            signing_key,
+        };
+        client_directory.clients.insert(client_id.clone(), client_info);
+        client_directory.save_to_file("clients.json");
+
+        // Add the client to `directory.json`
+        directory.clients.entry(client_id.clone()).or_insert_with(Vec::new);
+        directory.save_to_file("directory.json");
+
+        warp::reply::json(&json!({
+            "message": "Client registered successfully",
+            "client_id": client_id
+        }))
+    });
+
+
+
+let update_ip = warp::path("update_ip")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, String>| {
+        // Dynamically load the JSON file
+        let mut client_directory = ClientDirectory::load_from_file("clients.json");
+
+        let client_id = body.get("id").unwrap();
+        let new_ip = body.get("current_ip").unwrap();
+
+        // Update the client's IP address
+        if let Some(client) = client_directory.clients.get_mut(client_id) {
+            client.current_ip = Some(new_ip.clone());
+            client_directory.save_to_file("clients.json");
+
+            return warp::reply::json(&json!({
+                "message": "IP updated successfully",
+                "client_id": client_id,
+                "current_ip": new_ip
+            }));
+        }
+
+        warp::reply::json(&json!({
+            "error": "Client ID not found"
+        }))
+    });
+    let update_public_key = warp::path("update_public_key")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, String>| {
+        let mut client_directory = ClientDirectory::load_from_file("clients.json");
+
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let public_key = body.get("public_key").unwrap_or(&default_value);
+
+        // Only the client itself may replace its public key
+        if let Some(client) = client_directory.clients.get_mut(client_id) {
+            if client.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+            client.public_key = Some(public_key.clone());
+            if let Some(signing_key) = body.get("signing_key") {
+                client.signing_key = Some(signing_key.clone());
+            }
+            client_directory.save_to_file("clients.json");
+
+            return warp::reply::json(&json!({
+                "message": "Public key updated successfully",
+                "client_id": client_id
+            }));
+        }
+
+        warp::reply::json(&json!({
+            "error": "Client ID not found"
+        }))
+    });
+
+    let get_public_key = warp::path("get_public_key")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        let default_client_id = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_client_id);
+
+        let client_info = client_directory.clients.get(client_id);
+        match client_info.and_then(|client| client.public_key.clone()) {
+            Some(public_key) => warp::reply::json(&json!({
+                "client_id": client_id,
+                "public_key": public_key,
+                "signing_key": client_info.and_then(|client| client.signing_key.clone())
+            })),
+            None => warp::reply::json(&json!({
+                "error": format!("No public key found for client '{}'", client_id)
+            })),
+        }
+    });
+
+    let add_cover = warp::path("add_cover")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, serde_json::Value>| {
+        let mut cover_directory = CoverDirectory::load_from_file("covers.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let cover_name = body.get("cover_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let cover_data = body.get("cover_data").and_then(|v| v.as_str()).unwrap_or_default();
+        let shared = body.get("shared").and_then(|v| v.as_bool()).unwrap_or(false);
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        if shared && !ADMIN_CLIENTS.contains(&client_id) {
+            return warp::reply::json(&json!({ "error": "Only admins can add covers to the shared library" }));
+        }
+        if cover_name.is_empty() {
+            return warp::reply::json(&json!({ "error": "Cover name must not be empty" }));
+        }
+
+        // Record the dimensions so clients can pick a cover without downloading it
+        let decoded_cover = general_purpose::STANDARD
+            .decode(cover_data)
+            .ok()
+            .and_then(|bytes| image::load_from_memory(&bytes).ok());
+        let cover_image = match decoded_cover {
+            Some(cover_image) => cover_image,
+            None => return warp::reply::json(&json!({ "error": "Cover data is not a valid image" })),
+        };
+
+        let owner = if shared { SHARED_COVERS_KEY } else { client_id };
+        let covers = cover_directory.covers.entry(owner.to_string()).or_default();
+        covers.retain(|cover| cover.name != cover_name);
+        covers.push(Cover {
+            name: cover_name.to_string(),
+            data: cover_data.to_string(),
+            width: cover_image.width(),
+            height: cover_image.height(),
+        });
+        cover_directory.save_to_file("covers.json");
+
+        warp::reply::json(&json!({
+            "message": "Cover added successfully",
+            "owner": owner,
+            "cover_name": cover_name
+        }))
+    });
+
+    let list_covers = warp::path("list_covers")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let cover_directory = CoverDirectory::load_from_file("covers.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // The shared library plus the client's own covers
+        let covers: Vec<serde_json::Value> = [SHARED_COVERS_KEY, client_id.as_str()]
+            .iter()
+            .filter_map(|owner| cover_directory.covers.get(*owner).map(|covers| (owner, covers)))
+            .flat_map(|(owner, covers)| {
+                covers.iter().map(move |cover| {
+                    json!({
+                        "owner": owner,
+                        "name": cover.name,
+                        "width": cover.width,
+                        "height": cover.height
+                    })
+                })
+            })
+            .collect();
+
+        warp::reply::json(&covers)
+    });
+
+    let get_cover = warp::path("get_cover")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let cover_directory = CoverDirectory::load_from_file("covers.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
+        let owner = query.get("owner").unwrap_or(&default_value);
+        let cover_name = query.get("cover_name").unwrap_or(&default_value);
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Only the shared library and the caller's own uploads are readable
+        if owner != SHARED_COVERS_KEY && owner != client_id {
+            return warp::reply::json(&json!({
+                "error": format!("Cover '{}' not found for '{}'", cover_name, owner)
+            }));
+        }
+
+        match cover_directory
+            .covers
+            .get(owner)
+            .and_then(|covers| covers.iter().find(|cover| cover.name == *cover_name))
+        {
+            Some(cover) => warp::reply::json(&json!({
+                "owner": owner,
+                "name": cover.name,
+                "data": cover.data
+            })),
+            None => warp::reply::json(&json!({
+                "error": format!("Cover '{}' not found for '{}'", cover_name, owner)
+            })),
+        }
+    });
+
+    let upload_delivery = warp::path("upload_delivery")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let mut delivery_directory = DeliveryDirectory::load_from_file("deliveries.json");
+
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let viewer = body.get("viewer").unwrap_or(&default_value);
+        let image_name = body.get("image_name").unwrap_or(&default_value);
+        let request_id = body.get("request_id").unwrap_or(&default_value);
+        let data = body.get("data").unwrap_or(&default_value);
+
+        // Authenticate the owner
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        if !client_directory.clients.contains_key(viewer) {
+            return warp::reply::json(&json!({ "error": format!("Viewer '{}' not found", viewer) }));
+        }
+        if !is_valid_request_id(request_id) {
+            return warp::reply::json(&json!({ "error": "Invalid request ID" }));
+        }
+
+        // Owners can only leave their own images, and only for someone they granted them to
+        let directory = Directory::load_from_file("directory.json");
+        let Some(image_data) = directory.clients.get(client_id).and_then(|images| {
+            images
+                .iter()
+                .map(|img| serde_json::from_str::<serde_json::Value>(img).unwrap_or_default())
+                .find(|image_data| image_data["name"] == *image_name)
+        }) else {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+            }));
+        };
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+        let album_directory = AlbumDirectory::load_from_file("albums.json");
+        if !granted_viewers(&image_data, client_id, &group_directory, &album_directory).contains(viewer) {
+            return warp::reply::json(&json!({ "error": format!("'{}' has no access to '{}'", viewer, image_name) }));
+        }
+
+        // The blob is encrypted for the viewer, the DOS only keeps it until they fetch it.
+        // Request IDs come from the viewer, so another owner may well use the same one.
+        let deliveries = delivery_directory.deliveries.entry(viewer.clone()).or_default();
+        deliveries.retain(|delivery| delivery.owner != *client_id || delivery.request_id != *request_id);
+        deliveries.push(Delivery {
+            request_id: request_id.clone(),
+            owner: client_id.clone(),
+            image_name: image_name.clone(),
+            data: data.clone(),
+            uploaded_at: Utc::now().to_rfc3339(),
+        });
+        delivery_directory.save_to_file("deliveries.json");
+        publish_event(&notifier, "delivery", &[viewer.as_str()], json!({
+            "message": format!("{} left {} for you on the DOS", client_id, image_name),
+            "owner": client_id,
+            "image_name": image_name,
+            "request_id": request_id
+        }));
+
+        warp::reply::json(&json!({
+            "message": "Delivery stored until the viewer comes online",
+            "viewer": viewer,
+            "request_id": request_id
+        }))
+    });
+
+    let fetch_deliveries = warp::path("fetch_deliveries")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, String>| {
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let delivery_directory = DeliveryDirectory::load_from_file("deliveries.json");
+
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+
+        // Only the viewer may collect their deliveries
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Kept on the DOS until the viewer's client acknowledges it stored them
+        let deliveries = delivery_directory.deliveries.get(client_id).cloned().unwrap_or_default();
+
+        warp::reply::json(&json!({ "deliveries": deliveries }))
+    });
+
+    let acknowledge_deliveries = warp::path!("deliveries" / "ack")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, serde_json::Value>| {
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let mut delivery_directory = DeliveryDirectory::load_from_file("deliveries.json");
+
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let request_ids: Vec<String> = match body.get("request_ids") {
+            Some(ids) => serde_json::from_value(ids.clone()).unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        let mut removed = 0;
+        if let Some(deliveries) = delivery_directory.deliveries.get_mut(client_id) {
+            let before = deliveries.len();
+            deliveries.retain(|delivery| !request_ids.contains(&delivery.request_id));
+            removed = before - deliveries.len();
+            if deliveries.is_empty() {
+                delivery_directory.deliveries.remove(client_id);
+            }
+        }
+        delivery_directory.save_to_file("deliveries.json");
+
+        warp::reply::json(&json!({ "message": "Deliveries acknowledged", "removed": removed }))
+    });
+
+    let remove_access = warp::path("remove_access")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, serde_json::Value>, notifier: broadcast::Sender<String>| {
+        // Extract required fields
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let users_to_remove: Vec<String> = match body.get("users_to_remove") {
+            Some(users) => serde_json::from_value(users.clone()).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let groups_to_remove: Vec<String> = match body.get("groups_to_remove") {
+            Some(groups) => serde_json::from_value(groups.clone()).unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        // Load directories
+        let guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Find the image
+        if let Some(images) = directory.clients.get_mut(client_id) {
+            if let Some(image_entry) = images.iter_mut().find(|img| {
+                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+                image_data["name"] == image_name
+            }) {
+                let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+
+                // Remove users from access list
+                if let Some(access_users) = image_data.get_mut("access_users").and_then(|v| v.as_object_mut()) {
+                    for user in &users_to_remove {
+                        access_users.remove(user);
+                    }
+                }
+                // Their key shares, time limits and place in the delegation chain go too,
+                // so no further views can be granted
+                for field in ["view_shares", "view_grants", "access_windows", "delegations"] {
+                    if let Some(entries) = image_data.get_mut(field).and_then(|v| v.as_object_mut()) {
+                        for user in &users_to_remove {
+                            entries.remove(user);
+                        }
+                    }
+                }
+                // Members of a removed group lose the access it gave them, see below
+                let group_directory = GroupDirectory::load_from_file("groups.json");
+                let revoked_groups: Vec<(&String, Vec<String>)> = groups_to_remove
+                    .iter()
+                    .filter(|group| image_data["group_access"].get(group.as_str()).is_some())
+                    .map(|group| (group, group_directory.members(client_id, group).cloned().unwrap_or_default()))
+                    .collect();
+                for field in ["group_access", "group_views"] {
+                    if let Some(entries) = image_data.get_mut(field).and_then(|v| v.as_object_mut()) {
+                        for group in &groups_to_remove {
+                            entries.remove(group);
+                        }
+                    }
+                }
+
+                *image_entry = serde_json::to_string(&image_data).unwrap();
+                directory.save_to_file("directory.json");
+
+                // Remembered until each viewer's client reports its copies were wiped
+                let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+                for user in &users_to_remove {
+                    record_audit(client_id, "revoke", client_id, image_name, user, json!({}));
+                    revocation_directory.clear(user, client_id, image_name);
+                    revocation_directory.revocations.entry(user.clone()).or_default().push(Revocation {
+                        owner: client_id.to_string(),
+                        image_name: image_name.to_string(),
+                        revoked_at: Utc::now().to_rfc3339(),
+                        wiped_at: None,
+                        wiped_copies: 0,
+                    });
+                }
+                revocation_directory.save_to_file("revocations.json");
+                drop(guard);
+
+                // An empty recipient list would reach everyone
+                if !users_to_remove.is_empty() {
+                    let revoked_users: Vec<&str> = users_to_remove.iter().map(String::as_str).collect();
+                    publish_event(&notifier, "access_revoked", &revoked_users, json!({
+                        "message": format!("{} revoked your access to {}", client_id, image_name),
+                        "owner": client_id,
+                        "image_name": image_name
+                    }));
+                }
+
+                // Members keep their copies if they still have access some other way
+                for (group, members) in &revoked_groups {
+                    revoke_lost_access(&notifier, client_id, image_name, members, json!({ "group": group }));
+                }
+
+                return warp::reply::json(&json!({
+                    "message": "Users removed successfully from access list",
+                    "client_id": client_id,
+                    "image_name": image_name
+                }));
+            }
+        }
+
+        warp::reply::json(&json!({
+            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+        }))
+    });
+
+
+    let modify_access = warp::path("modify_access")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, serde_json::Value>, notifier: broadcast::Sender<String>| {
+        // Extract required fields
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let access_rights: HashMap<String, u32> = match body.get("access_rights") {
+            Some(rights) => serde_json::from_value(rights.clone()).unwrap_or_default(),
+            None => HashMap::new(),
+        };
+        let access_windows: HashMap<String, AccessWindow> = match body.get("access_windows") {
+            Some(windows) => serde_json::from_value(windows.clone()).unwrap_or_default(),
+            None => HashMap::new(),
+        };
+        let group_access: HashMap<String, u32> = match body.get("group_access") {
+            Some(groups) => serde_json::from_value(groups.clone()).unwrap_or_default(),
+            None => HashMap::new(),
+        };
+
+        // Load directories
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Group grants can only target the owner's own groups
+        if let Some(group) = group_access.keys().find(|group| group_directory.members(client_id, group).is_none()) {
+            return warp::reply::json(&json!({ "error": format!("Group '{}' not found", group) }));
+        }
+        if !grant_group_access(&mut directory, client_id, image_name, &group_access) {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+            }));
+        }
+
+        // Find the image and update its access rights
+        let mut viewers: Vec<String> = access_rights.keys().cloned().collect();
+        let audited_rights = access_rights.clone();
+        let audited_windows = access_windows.clone();
+        if grant_access(&mut directory, client_id, image_name, access_rights, access_windows) {
+            directory.save_to_file("directory.json");
+            for (viewer, views) in &audited_rights {
+                record_audit(client_id, "grant", client_id, image_name, viewer, json!({
+                    "views": views,
+                    "access_window": audited_windows.get(viewer)
+                }));
+            }
+            for (group, quota) in &group_access {
+                record_audit(client_id, "grant", client_id, image_name, "", json!({
+                    "group": group,
+                    "views_per_member": quota
+                }));
+                for member in group_directory.members(client_id, group).into_iter().flatten() {
+                    if !viewers.contains(member) {
+                        viewers.push(member.clone());
+                    }
+                }
+            }
+
+            let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+            for viewer in &viewers {
+                revocation_directory.clear(viewer, client_id, image_name);
+            }
+            revocation_directory.save_to_file("revocations.json");
+
+            let viewers: Vec<&str> = viewers.iter().map(String::as_str).collect();
+            publish_event(&notifier, "access_granted", &viewers, json!({
+                "message": format!("{} granted you access to {}", client_id, image_name),
+                "owner": client_id,
+                "image_name": image_name
+            }));
+
+            return warp::reply::json(&json!({
+                "message": "Access rights updated successfully",
+                "client_id": client_id,
+                "image_name": image_name
+            }));
+        }
+
+        warp::reply::json(&json!({
+            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+        }))
+    });
+
+    let edit_views = warp::path("edit_views")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, serde_json::Value>| {
+        // Extract required fields
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let new_views: HashMap<String, u32> = match body.get("new_views") {
+            Some(views) => serde_json::from_value(views.clone()).unwrap_or_default(),
+            None => HashMap::new(),
+        };
+
+        // Load directories
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Find the image
+        if let Some(images) = directory.clients.get_mut(client_id) {
+            if let Some(image_entry) = images.iter_mut().find(|img| {
+                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+                image_data["name"] == image_name
+            }) {
+                let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+
+                // Update views for existing users
+                if let Some(access_users) = image_data.get_mut("access_users").and_then(|v| v.as_object_mut()) {
+                    for (user, views) in new_views {
+                        if access_users.contains_key(&user) {
+                            record_audit(client_id, "edit_views", client_id, image_name, &user, json!({ "views": views }));
+                            access_users.insert(user, serde_json::Value::from(views));
+                        }
+                    }
+                }
+
+                *image_entry = serde_json::to_string(&image_data).unwrap();
+                directory.save_to_file("directory.json");
+
+                return warp::reply::json(&json!({
+                    "message": "Number of views updated successfully",
+                    "client_id": client_id,
+                    "image_name": image_name
+                }));
+            }
+        }
+
+        warp::reply::json(&json!({
+            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+        }))
+    });
+    // Groups let an owner grant access to several clients at once
+    let create_group = warp::path("create_group")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, serde_json::Value>| {
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let group_name = body.get("group_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let members: Vec<String> = match body.get("members") {
+            Some(members) => serde_json::from_value(members.clone()).unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let mut group_directory = GroupDirectory::load_from_file("groups.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        if group_name.is_empty() {
+            return warp::reply::json(&json!({ "error": "Group name is required" }));
+        }
+        if group_directory.members(client_id, group_name).is_some() {
+            return warp::reply::json(&json!({ "error": format!("Group '{}' already exists", group_name) }));
         }
+        if let Some(unknown) = members.iter().find(|member| !client_directory.clients.contains_key(*member)) {
+            return warp::reply::json(&json!({ "error": format!("Client '{}' not found", unknown) }));
+        }
+
+        group_directory
+            .groups
+            .entry(client_id.to_string())
+            .or_default()
+            .insert(group_name.to_string(), members.clone());
+        group_directory.save_to_file("groups.json");
+
+        warp::reply::json(&json!({
+            "message": "Group created successfully",
+            "group_name": group_name,
+            "members": members
+        }))
     });
-    
 
-    
-    let add_image = warp::path("add_image")
+    let add_group_member = warp::path("add_group_member")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let group_name = body.get("group_name").unwrap_or(&default_value);
+        let member = body.get("member").unwrap_or(&default_value);
+
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let mut group_directory = GroupDirectory::load_from_file("groups.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        if !client_directory.clients.contains_key(member) {
+            return warp::reply::json(&json!({ "error": format!("Client '{}' not found", member) }));
+        }
+        let Some(members) = group_directory.groups.get_mut(client_id).and_then(|groups| groups.get_mut(group_name)) else {
+            return warp::reply::json(&json!({ "error": format!("Group '{}' not found", group_name) }));
+        };
+        if !members.contains(member) {
+            members.push(member.clone());
+        }
+        group_directory.save_to_file("groups.json");
+
+        // The new member can use every grant already made to the group
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+        let directory = Directory::load_from_file("directory.json");
+        for image_entry in directory.clients.get(client_id).into_iter().flatten() {
+            let image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap_or_default();
+            if image_data["group_access"].get(group_name.as_str()).is_some() {
+                revocation_directory.clear(member, client_id, image_data["name"].as_str().unwrap_or_default());
+            }
+        }
+        revocation_directory.save_to_file("revocations.json");
+
+        publish_event(&notifier, "group_joined", &[member.as_str()], json!({
+            "message": format!("{} added you to the group {}", client_id, group_name),
+            "owner": client_id,
+            "group_name": group_name
+        }));
+
+        warp::reply::json(&json!({
+            "message": "Member added successfully",
+            "group_name": group_name,
+            "member": member
+        }))
+    });
+
+    let remove_group_member = warp::path("remove_group_member")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let group_name = body.get("group_name").unwrap_or(&default_value);
+        let member = body.get("member").unwrap_or(&default_value);
+
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let mut group_directory = GroupDirectory::load_from_file("groups.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Images the member could see before, through this group or otherwise
+        let directory = Directory::load_from_file("directory.json");
+        let album_directory = AlbumDirectory::load_from_file("albums.json");
+        let granted_images: Vec<String> = directory
+            .clients
+            .get(client_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|image_entry| serde_json::from_str::<serde_json::Value>(image_entry).ok())
+            .filter(|image_data| granted_viewers(image_data, client_id, &group_directory, &album_directory).contains(member))
+            .map(|image_data| image_data["name"].as_str().unwrap_or_default().to_string())
+            .collect();
+
+        let Some(members) = group_directory.groups.get_mut(client_id).and_then(|groups| groups.get_mut(group_name)) else {
+            return warp::reply::json(&json!({ "error": format!("Group '{}' not found", group_name) }));
+        };
+        let Some(position) = members.iter().position(|m| m == member) else {
+            return warp::reply::json(&json!({ "error": format!("'{}' is not a member of '{}'", member, group_name) }));
+        };
+        members.remove(position);
+        group_directory.save_to_file("groups.json");
+
+        // Copies the former member got through the group have to be wiped, unless they
+        // still have access to the image some other way
+        let revoked_images: Vec<&String> = granted_images
+            .iter()
+            .filter(|image_name| {
+                !revoke_lost_access(&notifier, client_id, image_name, std::slice::from_ref(member), json!({ "group": group_name })).is_empty()
+            })
+            .collect();
+
+        warp::reply::json(&json!({
+            "message": "Member removed successfully",
+            "group_name": group_name,
+            "member": member,
+            "revoked_images": revoked_images
+        }))
+    });
+
+    let list_groups = warp::path("list_groups")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
+
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // The caller's own groups, and the groups of others they are a member of
+        let owned = group_directory.groups.get(client_id).cloned().unwrap_or_default();
+        let member_of: Vec<serde_json::Value> = group_directory
+            .groups
+            .iter()
+            .flat_map(|(owner, groups)| {
+                groups
+                    .iter()
+                    .filter(|(_, members)| members.contains(client_id))
+                    .map(move |(group_name, _)| json!({ "owner": owner, "group_name": group_name }))
+            })
+            .collect();
+
+        warp::reply::json(&json!({
+            "groups": owned,
+            "member_of": member_of
+        }))
+    });
+
+    // Albums group an owner's images so they can be shared in one grant
+    let create_album = warp::path("create_album")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, String>| {
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let album_name = body.get("album_name").unwrap_or(&default_value);
+
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let mut album_directory = AlbumDirectory::load_from_file("albums.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        if album_name.is_empty() {
+            return warp::reply::json(&json!({ "error": "Album name is required" }));
+        }
+        let albums = album_directory.albums.entry(client_id.clone()).or_default();
+        if albums.contains_key(album_name) {
+            return warp::reply::json(&json!({ "error": format!("Album '{}' already exists", album_name) }));
+        }
+        albums.insert(album_name.clone(), Album::default());
+        album_directory.save_to_file("albums.json");
+
+        warp::reply::json(&json!({
+            "message": "Album created successfully",
+            "album_name": album_name
+        }))
+    });
+
+    let add_album_image = warp::path("add_album_image")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let album_name = body.get("album_name").unwrap_or(&default_value);
+        let image_name = body.get("image_name").unwrap_or(&default_value);
+
+        let directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+        let mut album_directory = AlbumDirectory::load_from_file("albums.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        let image_exists = directory.clients.get(client_id).into_iter().flatten().any(|img| {
+            let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+            image_data["name"] == *image_name
+        });
+        if !image_exists {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+            }));
+        }
+        let Some(album) = album_directory.albums.get_mut(client_id).and_then(|albums| albums.get_mut(album_name)) else {
+            return warp::reply::json(&json!({ "error": format!("Album '{}' not found", album_name) }));
+        };
+        let added = !album.images.contains(image_name);
+        if added {
+            album.images.push(image_name.clone());
+        }
+        let viewers = album.viewers(client_id, &group_directory);
+        let (access_users, group_access) = if added {
+            (album.access_users.clone(), album.group_access.clone())
+        } else {
+            Default::default()
+        };
+        album_directory.save_to_file("albums.json");
+
+        // The album's grants now cover this image too
+        for (viewer, views) in &access_users {
+            record_audit(client_id, "grant", client_id, image_name, viewer, json!({ "album": album_name, "views": views }));
+        }
+        for (group, quota) in &group_access {
+            record_audit(client_id, "grant", client_id, image_name, "", json!({
+                "album": album_name,
+                "group": group,
+                "views_per_member": quota
+            }));
+        }
+
+        // The album's viewers can now get the image, so earlier revocations no longer apply
+        let guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+        for viewer in &viewers {
+            revocation_directory.clear(viewer, client_id, image_name);
+        }
+        revocation_directory.save_to_file("revocations.json");
+        drop(guard);
+        let recipients: Vec<&str> = viewers.iter().map(String::as_str).collect();
+        publish_event(&notifier, "access_granted", &recipients, json!({
+            "message": format!("{} added {} to the album {}", client_id, image_name, album_name),
+            "owner": client_id,
+            "image_name": image_name,
+            "album_name": album_name
+        }));
+
+        warp::reply::json(&json!({
+            "message": "Image added to album successfully",
+            "album_name": album_name,
+            "image_name": image_name
+        }))
+    });
+
+    let remove_album_image = warp::path("remove_album_image")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let album_name = body.get("album_name").unwrap_or(&default_value);
+        let image_name = body.get("image_name").unwrap_or(&default_value);
+
+        let guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+        let mut album_directory = AlbumDirectory::load_from_file("albums.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        let Some(album) = album_directory.albums.get_mut(client_id).and_then(|albums| albums.get_mut(album_name)) else {
+            return warp::reply::json(&json!({ "error": format!("Album '{}' not found", album_name) }));
+        };
+        let Some(position) = album.images.iter().position(|image| image == image_name) else {
+            return warp::reply::json(&json!({ "error": format!("'{}' is not in the album '{}'", image_name, album_name) }));
+        };
+        album.images.remove(position);
+        let viewers = album.viewers(client_id, &group_directory);
+        album_directory.save_to_file("albums.json");
+
+        // Views used through the album start over if the image is added back
+        if let Some(image_entry) = directory.clients.get_mut(client_id).into_iter().flatten().find(|img| {
+            let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+            image_data["name"] == *image_name
+        }) {
+            let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+            if let Some(album_views) = image_data.get_mut("album_views").and_then(|v| v.as_object_mut()) {
+                album_views.remove(album_name);
+            }
+            *image_entry = serde_json::to_string(&image_data).unwrap();
+            directory.save_to_file("directory.json");
+        }
+        drop(guard);
+
+        let revoked = revoke_lost_access(&notifier, client_id, image_name, &viewers, json!({ "album": album_name }));
+
+        warp::reply::json(&json!({
+            "message": "Image removed from album successfully",
+            "album_name": album_name,
+            "image_name": image_name,
+            "revoked_viewers": revoked
+        }))
+    });
+
+    let list_albums = warp::path("list_albums")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
+
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+        let album_directory = AlbumDirectory::load_from_file("albums.json");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // The caller's own albums with their grants, and the albums of others shared with them
+        let owned = album_directory.albums.get(client_id).cloned().unwrap_or_default();
+        let shared_with_me: Vec<serde_json::Value> = album_directory
+            .albums
+            .iter()
+            .filter(|(owner, _)| *owner != client_id)
+            .flat_map(|(owner, albums)| {
+                albums
+                    .iter()
+                    .filter(|(_, album)| album.quota(owner, client_id, &group_directory).is_some())
+                    .map(move |(album_name, album)| json!({
+                        "owner": owner,
+                        "album_name": album_name,
+                        "images": album.images
+                    }))
+            })
+            .collect();
+
+        warp::reply::json(&json!({
+            "albums": owned,
+            "shared_with_me": shared_with_me
+        }))
+    });
+
+    let grant_album = warp::path("grant_album")
     .and(warp::post())
     .and(warp::body::json())
     .and(with_notifier(notifier_tx.clone()))
     .map(|body: HashMap<String, serde_json::Value>, notifier: broadcast::Sender<String>| {
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let album_name = body.get("album_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let access_rights: HashMap<String, u32> = match body.get("access_rights") {
+            Some(rights) => serde_json::from_value(rights.clone()).unwrap_or_default(),
+            None => HashMap::new(),
+        };
+        let group_access: HashMap<String, u32> = match body.get("group_access") {
+            Some(groups) => serde_json::from_value(groups.clone()).unwrap_or_default(),
+            None => HashMap::new(),
+        };
+
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
         let mut directory = Directory::load_from_file("directory.json");
         let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+        let mut album_directory = AlbumDirectory::load_from_file("albums.json");
 
-        // Extract and validate required fields
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        if let Some(group) = group_access.keys().find(|group| group_directory.members(client_id, group).is_none()) {
+            return warp::reply::json(&json!({ "error": format!("Group '{}' not found", group) }));
+        }
+        let Some(album) = album_directory.albums.get_mut(client_id).and_then(|albums| albums.get_mut(album_name)) else {
+            return warp::reply::json(&json!({ "error": format!("Album '{}' not found", album_name) }));
+        };
+        album.access_users.extend(access_rights.clone());
+        album.group_access.extend(group_access.clone());
+        let album_images = album.images.clone();
+        let viewers = album.viewers(client_id, &group_directory);
+        album_directory.save_to_file("albums.json");
+
+        // Views used through the album start over with the new grant
+        for image_entry in directory.clients.get_mut(client_id).into_iter().flatten() {
+            let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap_or_default();
+            if !album_images.iter().any(|image| image_data["name"] == *image) {
+                continue;
+            }
+            image_data["album_views"][album_name] = json!({});
+            *image_entry = serde_json::to_string(&image_data).unwrap();
+        }
+        directory.save_to_file("directory.json");
+
+        let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+        for image_name in &album_images {
+            for viewer in &viewers {
+                revocation_directory.clear(viewer, client_id, image_name);
+            }
+        }
+        revocation_directory.save_to_file("revocations.json");
+
+        // One entry per image, so the audit of each image shows it was granted through the album
+        for image_name in &album_images {
+            for (viewer, views) in &access_rights {
+                record_audit(client_id, "grant", client_id, image_name, viewer, json!({ "album": album_name, "views": views }));
+            }
+            for (group, quota) in &group_access {
+                record_audit(client_id, "grant", client_id, image_name, "", json!({
+                    "album": album_name,
+                    "group": group,
+                    "views_per_member": quota
+                }));
+            }
+        }
+
+        let recipients: Vec<&str> = viewers.iter().map(String::as_str).collect();
+        publish_event(&notifier, "access_granted", &recipients, json!({
+            "message": format!("{} shared the album {} with you", client_id, album_name),
+            "owner": client_id,
+            "album_name": album_name
+        }));
+
+        warp::reply::json(&json!({
+            "message": "Album access updated successfully",
+            "album_name": album_name,
+            "images": album_images
+        }))
+    });
+
+    // The owner decides whether viewers of an image may pass their access on
+    let set_reshare_policy = warp::path("set_reshare_policy")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, serde_json::Value>| {
         let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
         let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
         let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
-        let image_data = body.get("image_data").and_then(|v| v.as_str()).unwrap_or_default();
-
-        // Extract access_users as a map (client_id -> allowed views)
-        let access_users: HashMap<String, u32> = match body.get("access_users") {
-            Some(users) => serde_json::from_value(users.clone()).unwrap_or_else(|_| HashMap::new()),
-            None => HashMap::new(),
+        let Some(policy) = body.get("reshare_policy").and_then(|v| serde_json::from_value::<ResharePolicy>(v.clone()).ok()) else {
+            return warp::reply::json(&json!({ "error": "Invalid re-share policy" }));
         };
 
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+
         // Authenticate client
         if let Some(client_info) = client_directory.clients.get(client_id) {
             if client_info.password != password {
@@ -356,441 +3020,712 @@ pub async fn run_dos(ip: [u8; 4], port: u16) -> tokio::task::JoinHandle<()> {
             return warp::reply::json(&json!({ "error": "Client ID not found" }));
         }
 
-        // Add image to directory
-        let images = directory.clients.entry(client_id.to_string()).or_insert_with(Vec::new);
-        images.push(
-            json!({
-                "name": image_name,
-                "data": image_data,
-                "access_users": access_users
-            })
-            .to_string(),
-        );
+        if let ResharePolicy::Group { group } = &policy {
+            if group_directory.members(client_id, group).is_none() {
+                return warp::reply::json(&json!({ "error": format!("Group '{}' not found", group) }));
+            }
+        }
 
-        // Save updated directory to file
+        let Some(image_entry) = directory.clients.get_mut(client_id).and_then(|images| {
+            images.iter_mut().find(|img| {
+                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+                image_data["name"] == image_name
+            })
+        }) else {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+            }));
+        };
+        let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+        image_data["reshare_policy"] = json!(policy);
+        *image_entry = serde_json::to_string(&image_data).unwrap();
         directory.save_to_file("directory.json");
+        record_audit(client_id, "reshare_policy", client_id, image_name, "", json!(policy));
 
-        // Send notification
-        let notification = format!("Client {} added image {}", client_id, image_name);
-        let _ = notifier.send(notification.clone());
-
-        warp::reply::json(&notification)
+        warp::reply::json(&json!({
+            "message": "Re-share policy updated successfully",
+            "image_name": image_name,
+            "reshare_policy": policy
+        }))
     });
 
+    // A viewer passes some of its access on, as far as the owner's re-share policy allows
+    let delegate_access = warp::path("delegate_access")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, serde_json::Value>, notifier: broadcast::Sender<String>| {
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let owner = body.get("owner").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let delegate = body.get("delegate").and_then(|v| v.as_str()).unwrap_or_default();
+        let Ok(views) = u32::try_from(body.get("views").and_then(|v| v.as_u64()).unwrap_or(0)) else {
+            return warp::reply::json(&json!({ "error": "views is out of range" }));
+        };
 
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+        let album_directory = AlbumDirectory::load_from_file("albums.json");
 
-    
-    let delete_image = warp::path("delete_image")
-        .and(warp::post())
-        .and(warp::body::json())
-        .and(with_notifier(notifier_tx.clone()))
-        .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
-            let mut directory = Directory::load_from_file("directory.json");
-            let client_directory = ClientDirectory::load_from_file("clients.json");
-
-            let client_id = body.get("client_id").unwrap();
-            let password = body.get("password").unwrap();
-            let image_name = body.get("image_name").unwrap();
-
-            if let Some(client_info) = client_directory.clients.get(client_id) {
-                if client_info.password != *password {
-                    return warp::reply::json(&json!({ "error": "Authentication failed" }));
-                }
-            } else {
-                return warp::reply::json(&json!({ "error": "Client ID not found" }));
-            }
-
-            if let Some(images) = directory.clients.get_mut(client_id) {
-                if let Some(pos) = images.iter().position(|img| {
-                    let image_data: serde_json::Value = serde_json::from_str(img).unwrap();
-                    image_data["name"] == *image_name
-                }) {
-                    images.remove(pos);
-                    directory.save_to_file("directory.json");
-                    let notification = json!({
-                        "message": format!("Client {} deleted image {}", client_id, image_name),
-                        "client_id": client_id,
-                        "image_name": image_name
-                    });
-                    let _ = notifier.send(notification.to_string());
-                    return warp::reply::json(&notification);
-                }
+        // Authenticate the delegating viewer
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
             }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
 
-            warp::reply::json(&json!({
-                "error": format!("Image {} not found for client {}", image_name, client_id)
-            }))
-        });
-
+        if !client_directory.clients.contains_key(delegate) {
+            return warp::reply::json(&json!({ "error": format!("Client '{}' not found", delegate) }));
+        }
+        if views == 0 || delegate == owner || delegate == client_id {
+            return warp::reply::json(&json!({ "error": "Invalid delegation" }));
+        }
 
+        let Some(image_entry) = directory.clients.get_mut(owner).and_then(|images| {
+            images.iter_mut().find(|img| {
+                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+                image_data["name"] == image_name
+            })
+        }) else {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, owner)
+            }));
+        };
+        let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
 
-    
-    let list_all = warp::path("list_all")
-        .and(warp::get())
-        .map(|| {
-            let directory = Directory::load_from_file("directory.json");
+        // Delegated views are taken off the delegator's own, so nobody can hand out more than they have
+        let Some((remaining_views, source)) = resolve_access(&image_data, owner, client_id, &group_directory, &album_directory) else {
+            return warp::reply::json(&json!({ "error": "You have no access left to delegate" }));
+        };
+        if u64::from(views) > remaining_views {
+            return warp::reply::json(&json!({ "error": format!("You only have {} views left", remaining_views) }));
+        }
 
-            let mut all_images = Vec::new();
-            for (client_id, images) in &directory.clients {
-                println!("Client ID: {}", client_id);
-                for image in images {
-                    if let Ok(mut image_data) = serde_json::from_str::<serde_json::Value>(image) {
-                        image_data["client_id"] = serde_json::Value::String(client_id.clone());
-                        all_images.push(image_data.to_string());
-                    }
+        let mut delegations: HashMap<String, Delegation> =
+            serde_json::from_value(image_data["delegations"].clone()).unwrap_or_default();
+        match ResharePolicy::of(&image_data) {
+            ResharePolicy::None => {
+                return warp::reply::json(&json!({ "error": "The owner does not allow re-sharing this image" }));
+            }
+            ResharePolicy::MaxViews { max_views } => {
+                if let Err(delegated) = check_reshare_budget(&delegations, client_id, views, max_views) {
+                    return warp::reply::json(&json!({
+                        "error": format!("Re-sharing is limited to {} views, {} already delegated", max_views, delegated)
+                    }));
                 }
             }
-
-            match create_composite_image(&all_images) {
-                Ok(composite_image) => {
-                    let mut buffer = Cursor::new(Vec::new());
-                    if composite_image.write_to(&mut buffer, ImageOutputFormat::Png).is_err() {
-                        return warp::http::Response::builder()
-                            .status(500)
-                            .header("Content-Type", "text/plain")
-                            .body("Failed to encode composite image".to_string().into_bytes())
-                            .unwrap();
-                    }
-
-                    warp::http::Response::builder()
-                        .header("Content-Type", "image/png")
-                        .body(buffer.into_inner())
-                        .unwrap()
+            ResharePolicy::Group { group } => {
+                if !group_directory.is_member(owner, &group, delegate) {
+                    return warp::reply::json(&json!({ "error": format!("This image can only be re-shared within the group '{}'", group) }));
                 }
-                Err(e) => warp::http::Response::builder()
-                    .status(500)
-                    .header("Content-Type", "text/plain")
-                    .body(format!("Failed to create composite image: {}", e).into_bytes())
-                    .unwrap(),
             }
-        });
-
-    
-    let list_by_client = warp::path("list_by_client")
-    .and(warp::get())
-    .and(warp::query::<HashMap<String, String>>())
-    .map(|query: HashMap<String, String>| {
-        let directory = Directory::load_from_file("directory.json"); // Dynamically load JSON on every request
-
-        let default_client_id = String::new();
-        let client_id = query.get("client_id").unwrap_or(&default_client_id);
+        }
 
-        let images = match directory.clients.get(client_id) {
-            Some(images) => {
-                let mut client_images = Vec::new();
-                for image in images {
-                    if let Ok(mut image_data) = serde_json::from_str::<serde_json::Value>(image) {
-                        image_data["client_id"] = serde_json::Value::String(client_id.clone());
-                        client_images.push(image_data.to_string());
-                    }
-                }
-                client_images
+        // A delegate keeps a single place in the chain, and grants from the owner are not taken over
+        match delegations.get_mut(delegate) {
+            Some(delegation) if delegation.delegated_by != client_id => {
+                return warp::reply::json(&json!({ "error": format!("'{}' already got access from {}", delegate, delegation.delegated_by) }));
+            }
+            Some(delegation) => match delegation.views.checked_add(views) {
+                Some(total) => delegation.views = total,
+                None => return warp::reply::json(&json!({ "error": "Too many views" })),
+            },
+            None if image_data["access_users"].get(delegate).is_some() => {
+                return warp::reply::json(&json!({ "error": format!("'{}' already has access from the owner", delegate) }));
             }
             None => {
-                return warp::http::Response::builder()
-                    .status(404)
-                    .header("Content-Type", "text/plain")
-                    .body("No images found".to_string().into_bytes())
-                    .unwrap();
+                delegations.insert(delegate.to_string(), Delegation {
+                    delegated_by: client_id.to_string(),
+                    views,
+                    delegated_at: Utc::now().to_rfc3339(),
+                });
             }
+        }
+
+        let Some(granted_views) = image_data["access_users"][delegate].as_u64().unwrap_or(0).checked_add(u64::from(views)) else {
+            return warp::reply::json(&json!({ "error": "Too many views" }));
         };
+        consume_views(&mut image_data, client_id, &source, u64::from(views));
+        image_data["access_users"][delegate] = json!(granted_views);
+        image_data["delegations"] = json!(delegations);
+        *image_entry = serde_json::to_string(&image_data).unwrap();
+        directory.save_to_file("directory.json");
 
-        match create_composite_image(&images) {
-            Ok(composite_image) => {
-                let mut buffer = Cursor::new(Vec::new());
-                if composite_image
-                    .write_to(&mut buffer, ImageOutputFormat::Png)
-                    .is_err()
-                {
-                    return warp::http::Response::builder()
-                        .status(500)
-                        .header("Content-Type", "text/plain")
-                        .body("Failed to encode composite image".to_string().into_bytes())
-                        .unwrap();
-                }
+        let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+        revocation_directory.clear(delegate, owner, image_name);
+        revocation_directory.save_to_file("revocations.json");
+        record_audit(client_id, "delegate", owner, image_name, delegate, json!({ "views": views }));
 
-                warp::http::Response::builder()
-                    .header("Content-Type", "image/png")
-                    .body(buffer.into_inner())
-                    .unwrap()
-            }
-            Err(e) => warp::http::Response::builder()
-                .status(500)
-                .header("Content-Type", "text/plain")
-                .body(format!("Failed to create composite image: {}", e).into_bytes())
-                .unwrap(),
-        }
+        publish_event(&notifier, "access_delegated", &[owner, delegate], json!({
+            "message": format!("{} passed {} views of {}'s image {} on to {}", client_id, views, owner, image_name, delegate),
+            "owner": owner,
+            "image_name": image_name,
+            "delegated_by": client_id,
+            "delegate": delegate,
+            "views": views
+        }));
+
+        warp::reply::json(&json!({
+            "message": "Access delegated successfully",
+            "owner": owner,
+            "image_name": image_name,
+            "delegate": delegate,
+            "views": granted_views
+        }))
     });
 
-      
-        let fetch_clients = warp::path("fetch_clients")
-    .and(warp::get())
-    .map(|| {
-        let client_directory = ClientDirectory::load_from_file("clients.json"); // Dynamically load JSON on every request
+    // The owner takes access away from a viewer and everyone it was passed on to
+    let revoke_delegation = warp::path("revoke_delegation")
+    .and(warp::post())
+    .and(warp::body::json())
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let image_name = body.get("image_name").unwrap_or(&default_value);
+        let viewer = body.get("viewer").unwrap_or(&default_value);
+
+        let guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
 
-        let clients_with_ips: HashMap<String, Option<String>> = client_directory
-            .clients
-            .iter()
-            .map(|(client_id, client_info)| {
-                (client_id.clone(), client_info.current_ip.clone())
+        // Authenticate the owner
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        let Some(image_entry) = directory.clients.get_mut(client_id).and_then(|images| {
+            images.iter_mut().find(|img| {
+                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+                image_data["name"] == *image_name
             })
-            .collect();
+        }) else {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+            }));
+        };
+        let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
 
-        warp::reply::json(&clients_with_ips)
-    });
+        let subtree = delegation_subtree(&image_data, viewer);
+        for field in ["access_users", "access_windows", "view_shares", "view_grants", "delegations"] {
+            if let Some(entries) = image_data.get_mut(field).and_then(|v| v.as_object_mut()) {
+                for user in &subtree {
+                    entries.remove(user);
+                }
+            }
+        }
+        *image_entry = serde_json::to_string(&image_data).unwrap();
+        directory.save_to_file("directory.json");
+        drop(guard);
 
+        let revoked = revoke_lost_access(&notifier, client_id, image_name, &subtree, json!({ "delegation_of": viewer }));
 
-    
-    let login = warp::path("login")
-    .and(warp::post())
-    .and(warp::body::json())
-    .map(|body: HashMap<String, String>| {
-        // Dynamically load the client directory from the JSON file
-        let client_directory = ClientDirectory::load_from_file("clients.json");
+        warp::reply::json(&json!({
+            "message": "Delegated access revoked successfully",
+            "image_name": image_name,
+            "removed": subtree,
+            "revoked_viewers": revoked
+        }))
+    });
 
-        // Extract client_id and password from the request body
+    let get_access = warp::path("get_access")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        // Create persistent bindings for default values
         let default_client_id = String::new();
-        let client_id = body.get("client_id").unwrap_or(&default_client_id);
-
         let default_password = String::new();
-        let password = body.get("password").unwrap_or(&default_password);
+        let default_image_name = String::new();
 
-        // Check if the client exists and validate the password
+        // Extract required fields from the query parameters
+        let client_id = query.get("client_id").unwrap_or(&default_client_id);
+        let password = query.get("password").unwrap_or(&default_password);
+        let image_name = query.get("image_name").unwrap_or(&default_image_name);
+
+        // Load directories
+        let directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+
+        // Authenticate client
         if let Some(client_info) = client_directory.clients.get(client_id) {
-            if &client_info.password == password {
-                warp::reply::with_status(
-                    warp::reply::json(&json!({
-                        "message": "Login successful",
-                        "client_id": client_id
-                    })),
-                    warp::http::StatusCode::OK,
-                )
-            } else {
-                warp::reply::with_status(
-                    warp::reply::json(&json!({
-                        "error": "Invalid password"
-                    })),
-                    warp::http::StatusCode::UNAUTHORIZED,
-                )
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Find the image and return its access rights
+        if let Some(images) = directory.clients.get(client_id) {
+            if let Some(image_entry) = images.iter().find(|img| {
+                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+                image_data["name"] == *image_name
+            }) {
+                let image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+                // Members of granted groups and viewers of the image's albums count as viewers
+                // with the views they have left
+                let group_directory = GroupDirectory::load_from_file("groups.json");
+                let album_directory = AlbumDirectory::load_from_file("albums.json");
+                let viewers = granted_viewers(&image_data, client_id, &group_directory, &album_directory);
+                if !viewers.is_empty() || image_data.get("access_users").is_some() {
+
+                    // Expired grants don't count; grants that have not started yet still do, so they
+                    // can be delivered ahead of time, and `not_before` is checked when viewing
+                    let now = unix_now();
+                    let open_rights: serde_json::Map<String, serde_json::Value> = viewers
+                        .into_iter()
+                        .filter(|viewer| !AccessWindow::of(&image_data, viewer).is_expired(now))
+                        .map(|viewer| {
+                            let views = resolve_access(&image_data, client_id, &viewer, &group_directory, &album_directory)
+                                .map_or(0, |(views, _)| views);
+                            (viewer, json!(views))
+                        })
+                        .collect();
+                    return warp::reply::json(&json!({
+                        "access_rights": open_rights,
+                        "access_windows": image_data.get("access_windows").cloned().unwrap_or_else(|| json!({})),
+                        "group_access": image_data.get("group_access").cloned().unwrap_or_else(|| json!({})),
+                        "albums": album_directory.containing(client_id, image_name).map(|(album_name, _)| album_name).collect::<Vec<_>>(),
+                        "reshare_policy": ResharePolicy::of(&image_data),
+                        "delegations": image_data.get("delegations").cloned().unwrap_or_else(|| json!({})),
+                        "client_id": client_id,
+                        "image_name": image_name
+                    }));
+                } else {
+                    return warp::reply::json(&json!({
+                        "error": "No access rights found for this image"
+                    }));
+                }
             }
-        } else {
-            warp::reply::with_status(
-                warp::reply::json(&json!({
-                    "error": "Client ID not found"
-                })),
-                warp::http::StatusCode::NOT_FOUND,
-            )
         }
+
+        warp::reply::json(&json!({
+            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+        }))
     });
 
-       
-        let register_client = warp::path("register_client")
+    // The owner stores the key shares a viewer needs to decrypt one delivery, one per view
+    let register_view_share = warp::path("register_view_share")
     .and(warp::post())
     .and(warp::body::json())
-    .map(|body: HashMap<String, String>| {
-        // Dynamically load the JSON files
-        let mut client_directory = ClientDirectory::load_from_file("clients.json");
+    .map(|body: HashMap<String, serde_json::Value>| {
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
         let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
 
-        let client_id = body.get("id").unwrap().to_string();
-        let password = body.get("password").unwrap().to_string();
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
+        let viewer = body.get("viewer").and_then(|v| v.as_str()).unwrap_or_default();
+        let request_id = body.get("request_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let shares: Vec<String> = body
+            .get("shares")
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+            .unwrap_or_default();
 
-        // Check if the client already exists
-        if client_directory.clients.contains_key(&client_id) {
+        // Authenticate the owner
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        if shares.is_empty() || shares.len() > MAX_VIEW_SHARES {
             return warp::reply::json(&json!({
-                "error": "Client ID already exists"
+                "error": format!("Between 1 and {} key shares are needed", MAX_VIEW_SHARES)
             }));
         }
 
-        // Add the client to `clients.json`
-        let client_info = ClientInfo {
-            id: client_id.clone(),
-            password,
-            current_ip: None,
-        };
-        client_directory.clients.insert(client_id.clone(), client_info);
-        client_directory.save_to_file("clients.json");
+        if let Some(images) = directory.clients.get_mut(client_id) {
+            if let Some(image_entry) = images.iter_mut().find(|img| {
+                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+                image_data["name"] == image_name
+            }) {
+                let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+                // Handed out front to back, each one only once
+                let shares: Vec<serde_json::Value> = shares
+                    .iter()
+                    .enumerate()
+                    .map(|(index, share)| json!({ "index": index, "share": share }))
+                    .collect();
+                image_data["view_shares"][viewer][request_id] = json!(shares);
+                *image_entry = serde_json::to_string(&image_data).unwrap();
+                directory.save_to_file("directory.json");
 
-        // Add the client to `directory.json`
-        directory.clients.entry(client_id.clone()).or_insert_with(Vec::new);
-        directory.save_to_file("directory.json");
+                return warp::reply::json(&json!({
+                    "message": "View shares registered",
+                    "image_name": image_name,
+                    "viewer": viewer,
+                    "shares": shares.len()
+                }));
+            }
+        }
 
         warp::reply::json(&json!({
-            "message": "Client registered successfully",
-            "client_id": client_id
+            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
         }))
     });
 
-
-
-let update_ip = warp::path("update_ip")
+    // Every view of a delivered image goes through here: one view is taken from what the viewer
+    // has left and the next unused key share is handed out. The share is deleted, so the view can
+    // only be decoded once, and the grant stays pending until the viewer reports the decode.
+    let request_view = warp::path("request_view")
     .and(warp::post())
     .and(warp::body::json())
-    .map(|body: HashMap<String, String>| {
-        // Dynamically load the JSON file
-        let mut client_directory = ClientDirectory::load_from_file("clients.json");
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let mut directory = Directory::load_from_file("directory.json");
+        let client_directory = ClientDirectory::load_from_file("clients.json");
 
-        let client_id = body.get("id").unwrap();
-        let new_ip = body.get("current_ip").unwrap();
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let owner = body.get("owner").unwrap_or(&default_value);
+        let image_name = body.get("image_name").unwrap_or(&default_value);
+        let request_id = body.get("request_id").unwrap_or(&default_value);
 
-        // Update the client's IP address
-        if let Some(client) = client_directory.clients.get_mut(client_id) {
-            client.current_ip = Some(new_ip.clone());
-            client_directory.save_to_file("clients.json");
+        // Authenticate the viewer
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
 
+        let Some(image_entry) = directory.clients.get_mut(owner).and_then(|images| {
+            images.iter_mut().find(|img| {
+                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+                image_data["name"] == *image_name
+            })
+        }) else {
             return warp::reply::json(&json!({
-                "message": "IP updated successfully",
-                "client_id": client_id,
-                "current_ip": new_ip
+                "error": format!("Image '{}' not found for client '{}'", image_name, owner)
             }));
+        };
+        let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+
+        if image_data["view_shares"][client_id.as_str()].get(request_id.as_str()).is_none() {
+            return warp::reply::json(&json!({ "error": "No key share registered for this delivery" }));
+        }
+        let now = unix_now();
+        if !AccessWindow::of(&image_data, client_id).is_open(now) {
+            return warp::reply::json(&json!({ "error": "Access is not valid at this time" }));
         }
+        let group_directory = GroupDirectory::load_from_file("groups.json");
+        let album_directory = AlbumDirectory::load_from_file("albums.json");
+        let Some((remaining_views, source)) = resolve_access(&image_data, owner, client_id, &group_directory, &album_directory) else {
+            return warp::reply::json(&json!({ "error": "No views left for this image" }));
+        };
+        let Some(shares) = image_data["view_shares"][client_id.as_str()][request_id.as_str()]
+            .as_array_mut()
+            .filter(|shares| !shares.is_empty())
+        else {
+            return warp::reply::json(&json!({
+                "error": "Every key share of this delivery was used, ask the owner to send the image again"
+            }));
+        };
+        let grant = shares.remove(0);
+
+        let remaining_views = remaining_views - 1;
+        let expires_at = now + VIEW_GRANT_TTL_SECS;
+        consume_views(&mut image_data, client_id, &source, 1);
+        image_data["view_grants"][client_id.as_str()][request_id.as_str()] = json!({
+            "view_index": grant["index"],
+            "share": grant["share"],
+            "source": source,
+            "expires_at": expires_at
+        });
+        *image_entry = serde_json::to_string(&image_data).unwrap();
+        directory.save_to_file("directory.json");
+        record_audit(client_id, "view", owner, image_name, client_id, json!({
+            "request_id": request_id,
+            "remaining_views": remaining_views,
+            "source": source
+        }));
+
+        publish_event(&notifier, "image_viewed", &[owner.as_str()], json!({
+            "message": format!("{} viewed {}, {} views left", client_id, image_name, remaining_views),
+            "viewer": client_id,
+            "image_name": image_name,
+            "remaining_views": remaining_views
+        }));
 
         warp::reply::json(&json!({
-            "error": "Client ID not found"
+            "share": grant["share"],
+            "view_index": grant["index"],
+            "remaining_views": remaining_views,
+            "expires_at": expires_at
         }))
     });
-    let remove_access = warp::path("remove_access")
+
+    // The viewer's client could not decode a view it was granted: the view and its share are
+    // given back, as long as the grant has not expired
+    let refund_view = warp::path("refund_view")
     .and(warp::post())
     .and(warp::body::json())
-    .map(|body: HashMap<String, serde_json::Value>| {
-        // Extract required fields
-        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
-        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
-        let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
-        let users_to_remove: Vec<String> = match body.get("users_to_remove") {
-            Some(users) => serde_json::from_value(users.clone()).unwrap_or_default(),
-            None => Vec::new(),
-        };
-
-        // Load directories
+    .map(|body: HashMap<String, String>| {
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
         let mut directory = Directory::load_from_file("directory.json");
         let client_directory = ClientDirectory::load_from_file("clients.json");
 
-        // Authenticate client
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let owner = body.get("owner").unwrap_or(&default_value);
+        let image_name = body.get("image_name").unwrap_or(&default_value);
+        let request_id = body.get("request_id").unwrap_or(&default_value);
+
+        // Authenticate the viewer
         if let Some(client_info) = client_directory.clients.get(client_id) {
-            if client_info.password != password {
+            if client_info.password != *password {
                 return warp::reply::json(&json!({ "error": "Authentication failed" }));
             }
         } else {
             return warp::reply::json(&json!({ "error": "Client ID not found" }));
         }
 
-        // Find the image
-        if let Some(images) = directory.clients.get_mut(client_id) {
-            if let Some(image_entry) = images.iter_mut().find(|img| {
+        let Some(image_entry) = directory.clients.get_mut(owner).and_then(|images| {
+            images.iter_mut().find(|img| {
                 let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
-                image_data["name"] == image_name
-            }) {
-                let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+                image_data["name"] == *image_name
+            })
+        }) else {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, owner)
+            }));
+        };
+        let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
 
-                // Remove users from access list
-                if let Some(access_users) = image_data.get_mut("access_users").and_then(|v| v.as_object_mut()) {
-                    for user in users_to_remove {
-                        access_users.remove(&user);
-                    }
-                }
+        let Some(grant) = image_data["view_grants"][client_id.as_str()]
+            .as_object_mut()
+            .and_then(|grants| grants.remove(request_id.as_str()))
+        else {
+            return warp::reply::json(&json!({ "error": "No pending view for this delivery" }));
+        };
+        if grant["expires_at"].as_u64().unwrap_or(0) <= unix_now() {
+            *image_entry = serde_json::to_string(&image_data).unwrap();
+            directory.save_to_file("directory.json");
+            return warp::reply::json(&json!({ "error": "The view grant expired, the view stays used" }));
+        }
 
-                *image_entry = serde_json::to_string(&image_data).unwrap();
-                directory.save_to_file("directory.json");
+        if let Ok(source) = serde_json::from_value::<GrantSource>(grant["source"].clone()) {
+            refund_views(&mut image_data, client_id, &source, 1);
+        }
+        if let Some(shares) = image_data["view_shares"][client_id.as_str()][request_id.as_str()].as_array_mut() {
+            shares.insert(0, json!({ "index": grant["view_index"], "share": grant["share"] }));
+        }
+        *image_entry = serde_json::to_string(&image_data).unwrap();
+        directory.save_to_file("directory.json");
+        record_audit(client_id, "refund", owner, image_name, client_id, json!({ "request_id": request_id }));
 
-                return warp::reply::json(&json!({
-                    "message": "Users removed successfully from access list",
-                    "client_id": client_id,
-                    "image_name": image_name
-                }));
+        warp::reply::json(&json!({ "message": "View refunded" }))
+    });
+
+    // Revocations the viewer's client still has to apply to its local copies
+    let revocations = warp::path("revocations")
+    .and(warp::path::end())
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
             }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
         }
 
-        warp::reply::json(&json!({
-            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
-        }))
+        let pending: Vec<&Revocation> = revocation_directory
+            .revocations
+            .get(client_id)
+            .map(|revocations| revocations.iter().filter(|revocation| revocation.wiped_at.is_none()).collect())
+            .unwrap_or_default();
+        warp::reply::json(&json!({ "revocations": pending }))
     });
 
-
-    let modify_access = warp::path("modify_access")
+    // The viewer's client reports it deleted its copies of a revoked image
+    let acknowledge_revocation = warp::path!("revocations" / "ack")
     .and(warp::post())
     .and(warp::body::json())
-    .map(|body: HashMap<String, serde_json::Value>| {
-        // Extract required fields
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, serde_json::Value>, notifier: broadcast::Sender<String>| {
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
+        let client_directory = ClientDirectory::load_from_file("clients.json");
+        let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+
         let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
         let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let owner = body.get("owner").and_then(|v| v.as_str()).unwrap_or_default();
         let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
-        let access_rights: HashMap<String, u32> = match body.get("access_rights") {
-            Some(rights) => serde_json::from_value(rights.clone()).unwrap_or_default(),
-            None => HashMap::new(),
+        let Ok(wiped_copies) = u32::try_from(body.get("wiped_copies").and_then(|v| v.as_u64()).unwrap_or(0)) else {
+            return warp::reply::json(&json!({ "error": "wiped_copies is out of range" }));
         };
 
-        // Load directories
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        let revocation = revocation_directory.revocations.get_mut(client_id).and_then(|revocations| {
+            revocations
+                .iter_mut()
+                .find(|revocation| revocation.owner == owner && revocation.image_name == image_name && revocation.wiped_at.is_none())
+        });
+        let Some(revocation) = revocation else {
+            return warp::reply::json(&json!({ "error": "No pending revocation for this image" }));
+        };
+        revocation.wiped_at = Some(Utc::now().to_rfc3339());
+        revocation.wiped_copies = wiped_copies;
+        revocation_directory.save_to_file("revocations.json");
+        record_audit(client_id, "wipe", owner, image_name, client_id, json!({ "wiped_copies": wiped_copies }));
+
+        publish_event(&notifier, "revocation_applied", &[owner], json!({
+            "message": format!("{} deleted {} copies of {} after revocation", client_id, wiped_copies, image_name),
+            "viewer": client_id,
+            "image_name": image_name,
+            "wiped_copies": wiped_copies
+        }));
+
+        warp::reply::json(&json!({ "message": "Revocation acknowledged" }))
+    });
+
+    // The viewer's client reports each image it decoded, which settles the pending view grant
+    let report_decode = warp::path("report_decode")
+    .and(warp::post())
+    .and(warp::body::json())
+    .map(|body: HashMap<String, String>| {
+        let _guard = DIRECTORY_LOCK.lock().unwrap();
         let mut directory = Directory::load_from_file("directory.json");
         let client_directory = ClientDirectory::load_from_file("clients.json");
 
+        let default_value = String::new();
+        let client_id = body.get("client_id").unwrap_or(&default_value);
+        let password = body.get("password").unwrap_or(&default_value);
+        let owner = body.get("owner").unwrap_or(&default_value);
+        let image_name = body.get("image_name").unwrap_or(&default_value);
+        let request_id = body.get("request_id").unwrap_or(&default_value);
+
         // Authenticate client
         if let Some(client_info) = client_directory.clients.get(client_id) {
-            if client_info.password != password {
+            if client_info.password != *password {
                 return warp::reply::json(&json!({ "error": "Authentication failed" }));
             }
         } else {
             return warp::reply::json(&json!({ "error": "Client ID not found" }));
         }
 
-        // Find the image
-        if let Some(images) = directory.clients.get_mut(client_id) {
-            if let Some(image_entry) = images.iter_mut().find(|img| {
+        // Only a view the DOS granted can have been decoded
+        let Some(image_entry) = directory.clients.get_mut(owner).and_then(|images| {
+            images.iter_mut().find(|img| {
                 let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
-                image_data["name"] == image_name
-            }) {
-                let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+                image_data["name"] == *image_name
+            })
+        }) else {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, owner)
+            }));
+        };
+        let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+        let Some(grant) = image_data["view_grants"][client_id.as_str()]
+            .as_object_mut()
+            .and_then(|grants| grants.remove(request_id.as_str()))
+        else {
+            return warp::reply::json(&json!({ "error": "No pending view for this delivery" }));
+        };
+        *image_entry = serde_json::to_string(&image_data).unwrap();
+        directory.save_to_file("directory.json");
 
-                // Update or initialize access rights
-                if let Some(existing_access) = image_data.get_mut("access_users").and_then(|v| v.as_object_mut()) {
-                    // Add or update the provided access rights
-                    for (other_client, allowed_views) in access_rights {
-                        existing_access.insert(other_client, serde_json::Value::from(allowed_views));
-                    }
-                } else {
-                    // Initialize access_users if it doesn't exist
-                    let new_access: serde_json::Map<String, serde_json::Value> = access_rights
-                        .into_iter()
-                        .map(|(client, views)| (client, serde_json::Value::from(views)))
-                        .collect();
-                    image_data["access_users"] = serde_json::Value::Object(new_access);
-                }
+        record_audit(client_id, "decode", owner, image_name, client_id, json!({
+            "request_id": request_id,
+            "view_index": grant["view_index"]
+        }));
+        warp::reply::json(&json!({ "message": "Decode recorded" }))
+    });
 
-                // Save the updated image metadata
-                *image_entry = serde_json::to_string(&image_data).unwrap();
-                directory.save_to_file("directory.json");
+    // GET /audit?client_id=..&password=..&image_name=.. for the owner's images only
+    let audit = warp::path("audit")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
+        let client_directory = ClientDirectory::load_from_file("clients.json");
 
-                return warp::reply::json(&json!({
-                    "message": "Access rights updated successfully",
-                    "client_id": client_id,
-                    "image_name": image_name
-                }));
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
+        let image_name = query.get("image_name");
+
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
             }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
         }
 
-        warp::reply::json(&json!({
-            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
-        }))
+        let audit_log = {
+            let _guard = AUDIT_LOG_LOCK.lock().unwrap();
+            AuditLog::load_from_file(AUDIT_LOG_PATH)
+        };
+        let entries: Vec<&AuditEntry> = audit_log
+            .entries
+            .iter()
+            .filter(|entry| entry.image_owner == *client_id)
+            .filter(|entry| image_name.is_none_or(|image_name| entry.image_name == *image_name))
+            .collect();
+        warp::reply::json(&json!({ "entries": entries }))
     });
 
-    let edit_views = warp::path("edit_views")
+    // Every access request goes through here, whether the owner gets it live from the requester's
+    // client or later from their notifications
+    let access_requests = warp::path("access_requests")
     .and(warp::post())
     .and(warp::body::json())
-    .map(|body: HashMap<String, serde_json::Value>| {
-        // Extract required fields
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|body: HashMap<String, serde_json::Value>, notifier: broadcast::Sender<String>| {
         let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
         let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
+        let image_owner = body.get("image_owner").and_then(|v| v.as_str()).unwrap_or_default();
         let image_name = body.get("image_name").and_then(|v| v.as_str()).unwrap_or_default();
-        let new_views: HashMap<String, u32> = match body.get("new_views") {
-            Some(views) => serde_json::from_value(views.clone()).unwrap_or_default(),
-            None => HashMap::new(),
+        let request_id = body.get("request_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let Ok(access_rights) = u32::try_from(body.get("access_rights").and_then(|v| v.as_u64()).unwrap_or(0)) else {
+            return warp::reply::json(&json!({ "error": "access_rights is out of range" }));
         };
 
-        // Load directories
-        let mut directory = Directory::load_from_file("directory.json");
+        let notifications_file_path = "notifications.json";
+        // Held until the request is audited so concurrent requests see each other in the dedup and rate checks
+        let guard = NOTIFICATIONS_LOCK.lock().unwrap();
+        let mut notification_directory = NotificationDirectory::load_from_file(notifications_file_path);
+        let directory = Directory::load_from_file("directory.json");
         let client_directory = ClientDirectory::load_from_file("clients.json");
 
-        // Authenticate client
+        // Authenticate the requester
         if let Some(client_info) = client_directory.clients.get(client_id) {
             if client_info.password != password {
                 return warp::reply::json(&json!({ "error": "Authentication failed" }));
@@ -799,56 +3734,148 @@ let update_ip = warp::path("update_ip")
             return warp::reply::json(&json!({ "error": "Client ID not found" }));
         }
 
-        // Find the image
-        if let Some(images) = directory.clients.get_mut(client_id) {
-            if let Some(image_entry) = images.iter_mut().find(|img| {
-                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
-                image_data["name"] == image_name
-            }) {
-                let mut image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
+        if access_rights == 0 || image_owner == client_id {
+            return warp::reply::json(&json!({ "error": "Invalid access request" }));
+        }
+        let image_exists = directory.clients.get(image_owner).into_iter().flatten().any(|img| {
+            let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
+            image_data["name"] == image_name
+        });
+        if !image_exists {
+            return warp::reply::json(&json!({
+                "error": format!("Image '{}' not found for client '{}'", image_name, image_owner)
+            }));
+        }
 
-                // Update views for existing users
-                if let Some(access_users) = image_data.get_mut("access_users").and_then(|v| v.as_object_mut()) {
-                    for (user, views) in new_views {
-                        if access_users.contains_key(&user) {
-                            access_users.insert(user, serde_json::Value::from(views));
-                        }
-                    }
-                }
+        if notification_directory.expire_stale() {
+            notification_directory.save_to_file(notifications_file_path);
+        }
 
-                *image_entry = serde_json::to_string(&image_data).unwrap();
-                directory.save_to_file("directory.json");
+        // Asking again while the same request is still waiting changes nothing
+        if let Some(pending) = notification_directory.notifications.get(image_owner).into_iter().flatten().find(|n| {
+            n.kind == NotificationKind::AccessRequest
+                && n.status == NotificationStatus::Pending
+                && n.requester == client_id
+                && n.image_name == image_name
+                && n.access_rights == access_rights
+        }) {
+            return warp::reply::json(&json!({
+                "message": "An identical request is already pending",
+                "id": pending.id,
+                "request_id": pending.request_id,
+                "duplicate": true
+            }));
+        }
 
-                return warp::reply::json(&json!({
-                    "message": "Number of views updated successfully",
-                    "client_id": client_id,
-                    "image_name": image_name
-                }));
-            }
+        // Counted from the audit log, which keeps every request even after the owner dismisses it
+        let window_start = Utc::now() - chrono::Duration::minutes(ACCESS_REQUEST_WINDOW_MINS);
+        let audit_log = {
+            let _guard = AUDIT_LOG_LOCK.lock().unwrap();
+            AuditLog::load_from_file(AUDIT_LOG_PATH)
+        };
+        let recent_requests = audit_log
+            .entries
+            .iter()
+            .filter(|entry| entry.action == "request" && entry.actor == client_id)
+            .filter(|entry| {
+                chrono::DateTime::parse_from_rfc3339(&entry.timestamp)
+                    .map(|timestamp| timestamp > window_start)
+                    .unwrap_or(false)
+            })
+            .count();
+        if recent_requests >= ACCESS_REQUEST_LIMIT {
+            return warp::reply::json(&json!({
+                "error": format!("Too many access requests, at most {} per {} minutes", ACCESS_REQUEST_LIMIT, ACCESS_REQUEST_WINDOW_MINS)
+            }));
         }
 
+        let request_id = if request_id.is_empty() {
+            format!("{:016x}", rand::random::<u64>())
+        } else {
+            request_id.to_string()
+        };
+        let notification = Notification {
+            id: 0,
+            image_owner: image_owner.to_string(),
+            image_name: image_name.to_string(),
+            requester: client_id.to_string(),
+            access_rights,
+            kind: NotificationKind::AccessRequest,
+            status: NotificationStatus::Pending,
+            created_at: String::new(),
+            request_id: request_id.clone(),
+        };
+        let id = notification_directory.push(image_owner, notification);
+        notification_directory.save_to_file(notifications_file_path);
+        record_audit(client_id, "request", image_owner, image_name, client_id, json!({
+            "notification_id": id,
+            "views": access_rights
+        }));
+        drop(guard);
+
+        publish_event(&notifier, "access_request", &[image_owner], json!({
+            "message": format!("{} requests {} views of {}", client_id, access_rights, image_name),
+            "id": id,
+            "requester": client_id,
+            "image_name": image_name,
+            "access_rights": access_rights,
+            "request_id": request_id
+        }));
+
         warp::reply::json(&json!({
-            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
+            "message": "Access request recorded",
+            "id": id,
+            "request_id": request_id,
+            "duplicate": false
         }))
     });
-    let get_access = warp::path("get_access")
-    .and(warp::get())
+
+    // Live events for one client: ws://<dos>/ws?client_id=..&password=..&epoch=..&since=<last event ID seen>
+    let ws_route = warp::path("ws")
+    .and(warp::ws())
     .and(warp::query::<HashMap<String, String>>())
-    .map(|query: HashMap<String, String>| {
-        // Create persistent bindings for default values
-        let default_client_id = String::new();
-        let default_password = String::new();
-        let default_image_name = String::new();
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|ws: warp::ws::Ws, query: HashMap<String, String>, notifier: broadcast::Sender<String>| {
+        let client_directory = ClientDirectory::load_from_file("clients.json");
 
-        // Extract required fields from the query parameters
-        let client_id = query.get("client_id").unwrap_or(&default_client_id);
-        let password = query.get("password").unwrap_or(&default_password);
-        let image_name = query.get("image_name").unwrap_or(&default_image_name);
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value).clone();
+        let password = query.get("password").unwrap_or(&default_value);
 
-        // Load directories
-        let directory = Directory::load_from_file("directory.json");
+        // Authenticate client before upgrading
+        let authenticated = client_directory
+            .clients
+            .get(&client_id)
+            .map(|client_info| client_info.password == *password)
+            .unwrap_or(false);
+        if !authenticated {
+            return warp::reply::with_status(
+                warp::reply::json(&json!({ "error": "Authentication failed" })),
+                warp::http::StatusCode::UNAUTHORIZED,
+            )
+            .into_response();
+        }
+
+        let epoch = query.get("epoch").cloned().unwrap_or_default();
+        let since = query.get("since").and_then(|since| since.parse().ok()).unwrap_or(0);
+        let rx = notifier.subscribe();
+        ws.on_upgrade(move |socket| handle_ws_connection(socket, rx, client_id, epoch, since))
+            .into_response()
+    });
+
+    // Pull variant of the stream: GET /events?client_id=..&password=..&epoch=..&since=N
+    let events = warp::path("events")
+    .and(warp::get())
+    .and(warp::query::<HashMap<String, String>>())
+    .map(|query: HashMap<String, String>| {
         let client_directory = ClientDirectory::load_from_file("clients.json");
 
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
+        let epoch = query.get("epoch").unwrap_or(&default_value);
+        let since = query.get("since").and_then(|since| since.parse().ok()).unwrap_or(0);
+
         // Authenticate client
         if let Some(client_info) = client_directory.clients.get(client_id) {
             if client_info.password != *password {
@@ -858,110 +3885,166 @@ let update_ip = warp::path("update_ip")
             return warp::reply::json(&json!({ "error": "Client ID not found" }));
         }
 
-        // Find the image and return its access rights
-        if let Some(images) = directory.clients.get(client_id) {
-            if let Some(image_entry) = images.iter().find(|img| {
-                let image_data: serde_json::Value = serde_json::from_str(img).unwrap_or_default();
-                image_data["name"] == *image_name
-            }) {
-                let image_data: serde_json::Value = serde_json::from_str(image_entry).unwrap();
-                if let Some(access_rights) = image_data.get("access_users") {
-                    return warp::reply::json(&json!({
-                        "access_rights": access_rights,
-                        "client_id": client_id,
-                        "image_name": image_name
-                    }));
-                } else {
-                    return warp::reply::json(&json!({
-                        "error": "No access rights found for this image"
-                    }));
-                }
-            }
-        }
-
-        warp::reply::json(&json!({
-            "error": format!("Image '{}' not found for client '{}'", image_name, client_id)
-        }))
+        let (epoch, events) = events_since(client_id, epoch, since);
+        warp::reply::json(&json!({ "epoch": epoch, "events": events }))
     });
 
-    let add_notification = warp::path("add_notification")
+    // POST /notifications/{id}/approve|reject|dismiss
+    let decide_notification = warp::path!("notifications" / u64 / String)
     .and(warp::post())
     .and(warp::body::json())
-    .map(|body: HashMap<String, serde_json::Value>| {
-        // Dynamically load the notification directory
+    .and(with_notifier(notifier_tx.clone()))
+    .map(|id: u64, action: String, body: HashMap<String, serde_json::Value>, notifier: broadcast::Sender<String>| {
         let notifications_file_path = "notifications.json";
+        // Held until the outcome is saved so access requests arriving meanwhile are not overwritten
+        let guard = NOTIFICATIONS_LOCK.lock().unwrap();
         let mut notification_directory = NotificationDirectory::load_from_file(notifications_file_path);
+        let client_directory = ClientDirectory::load_from_file("clients.json");
 
-        // Extract required fields
-        let image_owner = body
-            .get("image_owner")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default();
-        let image_name = body
-            .get("image_name")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default();
-        let requester = body
-            .get("requester")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default();
-        let access_rights = body
-            .get("access_rights")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0) as u32;
+        let client_id = body.get("client_id").and_then(|v| v.as_str()).unwrap_or_default();
+        let password = body.get("password").and_then(|v| v.as_str()).unwrap_or_default();
 
-        // Create a new notification
-        let notification = Notification {
-            image_owner: image_owner.to_string(),
-            image_name: image_name.to_string(),
-            requester: requester.to_string(),
-            access_rights,
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        notification_directory.expire_stale();
+        let Some(notifications) = notification_directory.notifications.get_mut(client_id) else {
+            return warp::reply::json(&json!({ "error": format!("Notification {} not found", id) }));
+        };
+        let Some(position) = notifications.iter().position(|n| n.id == id) else {
+            return warp::reply::json(&json!({ "error": format!("Notification {} not found", id) }));
         };
 
-        // Add the notification to the directory
-        notification_directory
-            .notifications
-            .entry(image_owner.to_string())
-            .or_insert_with(Vec::new)
-            .push(notification);
+        // Dismissing only hides the notification, whatever its state
+        if action == "dismiss" {
+            notifications.remove(position);
+            notification_directory.save_to_file(notifications_file_path);
+            return warp::reply::json(&json!({ "message": "Notification dismissed", "id": id }));
+        }
+
+        let notification = notifications[position].clone();
+        if notification.kind != NotificationKind::AccessRequest || notification.status != NotificationStatus::Pending {
+            return warp::reply::json(&json!({ "error": format!("Notification {} is not a pending request", id) }));
+        }
+
+        let status = match action.as_str() {
+            "approve" => {
+                // The owner may grant fewer views than requested, but never none or more
+                let views = match body.get("access_rights").filter(|v| !v.is_null()) {
+                    None => notification.access_rights,
+                    Some(value) => match value.as_u64().and_then(|views| u32::try_from(views).ok()) {
+                        Some(views) if (1..=notification.access_rights).contains(&views) => views,
+                        _ => {
+                            return warp::reply::json(&json!({
+                                "error": format!("access_rights must be between 1 and {}", notification.access_rights)
+                            }))
+                        }
+                    },
+                };
+                let _guard = DIRECTORY_LOCK.lock().unwrap();
+                let mut directory = Directory::load_from_file("directory.json");
+                let mut access_rights = HashMap::new();
+                access_rights.insert(notification.requester.clone(), views);
+                let mut access_windows = HashMap::new();
+                if let Some(window) = body.get("access_window").and_then(|v| serde_json::from_value(v.clone()).ok()) {
+                    access_windows.insert(notification.requester.clone(), window);
+                }
+                if !grant_access(&mut directory, client_id, &notification.image_name, access_rights, access_windows) {
+                    return warp::reply::json(&json!({
+                        "error": format!("Image '{}' not found for client '{}'", notification.image_name, client_id)
+                    }));
+                }
+                directory.save_to_file("directory.json");
+                let mut revocation_directory = RevocationDirectory::load_from_file("revocations.json");
+                revocation_directory.clear(&notification.requester, client_id, &notification.image_name);
+                revocation_directory.save_to_file("revocations.json");
+                notifications[position].access_rights = views;
+                NotificationStatus::Approved
+            }
+            "reject" => NotificationStatus::Rejected,
+            _ => return warp::reply::json(&json!({ "error": format!("Unknown action '{}'", action) })),
+        };
+        notifications[position].status = status;
+        record_audit(
+            client_id,
+            if status == NotificationStatus::Approved { "grant" } else { "reject" },
+            client_id,
+            &notification.image_name,
+            &notification.requester,
+            json!({ "notification_id": id, "views": notifications[position].access_rights }),
+        );
 
-        // Save the updated directory back to the file
+        // Let the requester know how the owner decided
+        let outcome = Notification {
+            id: 0,
+            kind: NotificationKind::Outcome,
+            status,
+            ..notifications[position].clone()
+        };
+        let requester = outcome.requester.clone();
+        let views = outcome.access_rights;
+        notification_directory.push(&requester, outcome);
         notification_directory.save_to_file(notifications_file_path);
+        drop(guard);
+
+        let (event, verb) = if status == NotificationStatus::Approved {
+            ("access_granted", "approved")
+        } else {
+            ("request_rejected", "rejected")
+        };
+        publish_event(&notifier, event, &[requester.as_str()], json!({
+            "message": format!("{} {} your request for {} views of {}", client_id, verb, views, notification.image_name),
+            "owner": client_id,
+            "image_name": notification.image_name
+        }));
 
-        warp::reply::json(&json!({ "message": "Notification added successfully" }))
+        warp::reply::json(&json!({
+            "message": format!("Notification {} {}", id, if status == NotificationStatus::Approved { "approved" } else { "rejected" }),
+            "id": id,
+            "requester": requester,
+            "image_name": notification.image_name
+        }))
     });
     let get_notifications = warp::path("get_notifications")
     .and(warp::get())
     .and(warp::query::<HashMap<String, String>>())
     .map(|query: HashMap<String, String>| {
-        // Dynamically load the notification directory
-        let notifications_file_path = "notifications.json";
-        let notification_directory = NotificationDirectory::load_from_file(notifications_file_path);
+        let client_directory = ClientDirectory::load_from_file("clients.json");
 
         // Create a persistent binding for the default value
-        let default_client_id = String::new();
-        let client_id = query.get("client_id").unwrap_or(&default_client_id);
+        let default_value = String::new();
+        let client_id = query.get("client_id").unwrap_or(&default_value);
+        let password = query.get("password").unwrap_or(&default_value);
 
-        // Retrieve notifications for the client
-        if let Some(notifications) = notification_directory.notifications.get(client_id) {
-            let result: HashMap<String, Vec<serde_json::Value>> = notifications
-                .iter()
-                .group_by(|n| n.requester.clone())
-                .into_iter()
-                .map(|(requester, group)| {
-                    let data = group
-                        .map(|n| {
-                            json!({
-                                "image": n.image_name,
-                                "access_rights": n.access_rights,
-                            })
-                        })
-                        .collect();
-                    (requester, data)
-                })
-                .collect();
+        // Authenticate client
+        if let Some(client_info) = client_directory.clients.get(client_id) {
+            if client_info.password != *password {
+                return warp::reply::json(&json!({ "error": "Authentication failed" }));
+            }
+        } else {
+            return warp::reply::json(&json!({ "error": "Client ID not found" }));
+        }
+
+        // Dynamically load the notification directory
+        let notifications_file_path = "notifications.json";
+        let notification_directory = {
+            let _guard = NOTIFICATIONS_LOCK.lock().unwrap();
+            let mut notification_directory = NotificationDirectory::load_from_file(notifications_file_path);
+            if notification_directory.expire_stale() {
+                notification_directory.save_to_file(notifications_file_path);
+            }
+            notification_directory
+        };
 
-            warp::reply::json(&result)
+        // Retrieve notifications for the client, requests and outcomes alike
+        if let Some(notifications) = notification_directory.notifications.get(client_id) {
+            warp::reply::json(&json!({ "notifications": notifications }))
         } else {
             warp::reply::json(&json!({ "error": "No notifications found" }))
         }
@@ -975,20 +4058,63 @@ let update_ip = warp::path("update_ip")
 
 
  
-    let routes = register_client
+    // Grouped and boxed so the combined filter type stays shallow enough to check
+    let client_routes = register_client
         .or(fetch_clients)
         .or(update_ip)
+        .or(update_public_key)
+        .or(get_public_key)
+        .or(login)
+        .boxed();
+    let image_routes = add_cover
+        .or(list_covers)
+        .or(get_cover)
+        .or(upload_delivery)
+        .or(fetch_deliveries)
+        .or(acknowledge_deliveries)
         .or(add_image)
         .or(delete_image)
+        .or(gallery)
+        .or(thumbnail)
         .or(list_all)
         .or(list_by_client)
-        .or(modify_access)
+        .boxed();
+    let sharing_routes = modify_access
         .or(edit_views)
         .or(remove_access)
-        .or(get_access)
-        .or(get_notifications)
-        .or(add_notification)
-        .or(login);
+        .or(create_group)
+        .or(add_group_member)
+        .or(remove_group_member)
+        .or(list_groups)
+        .or(create_album)
+        .or(add_album_image)
+        .or(remove_album_image)
+        .or(list_albums)
+        .or(grant_album)
+        .or(set_reshare_policy)
+        .or(delegate_access)
+        .or(revoke_delegation)
+        .boxed();
+    let view_routes = get_access
+        .or(register_view_share)
+        .or(request_view)
+        .or(refund_view)
+        .or(revocations)
+        .or(acknowledge_revocation)
+        .or(report_decode)
+        .or(audit)
+        .boxed();
+    let notification_routes = get_notifications
+        .or(access_requests)
+        .or(decide_notification)
+        .or(ws_route)
+        .or(events)
+        .boxed();
+    let routes = client_routes
+        .or(image_routes)
+        .or(sharing_routes)
+        .or(view_routes)
+        .or(notification_routes);
 
     tokio::spawn(async move {
             loop {
@@ -1087,67 +4213,10 @@ async fn leader_election(
             }
 
             cpu_data.clear();
-        } else if message == "IMAGE_TRANSFER" {
-            println!("{} received IMAGE_TRANSFER request from {}", own_address, addr);
-            receive_image(Arc::clone(&socket), addr).await?;
-            encode_received_image().await?; // Call encoding after receiving the image
-            send_encoded_image(Arc::clone(&socket), addr).await?; // Send encoded image back to client
-        }
-    }
-}
-
-async fn receive_image(socket: Arc<UdpSocket>, src: std::net::SocketAddr) -> tokio::io::Result<()> {
-    let mut file = File::create("received_image_1.jpg").await?;
-    let mut expected_chunk: u32 = 0;
-    let mut buffer = [0u8; CHUNK_SIZE + 4];
-
-    loop {
-        let (bytes_received, _) = socket.recv_from(&mut buffer).await?;
-        if bytes_received == 3 && &buffer[..3] == b"END" {
-            println!("Server: Image transfer complete.");
-            break;
         }
-
-        if bytes_received < 4 {
-            println!("Server: Received a malformed packet, skipping.");
-            continue;
-        }
-
-        let chunk_number = u32::from_be_bytes(buffer[..4].try_into().unwrap());
-        let data = &buffer[4..bytes_received];
-
-        if chunk_number == expected_chunk {
-            println!("Server: Writing chunk {}", chunk_number);
-            file.write_all(data).await?;
-            expected_chunk += 1;
-        }
-
-        socket.send_to(ACK, src).await?;
-        println!("Server: Sent ACK for chunk {}", chunk_number);
     }
-
-    Ok(())
 }
 
-async fn encode_received_image() -> tokio::io::Result<()> {
-    let received_image_path = "received_image_1.jpg";
-    let mask_image_path = "mask.jpg";
-    let encoded_image_path = "encrypted_image_1.png";
-
-    let mask_image = file_as_dynamic_image(mask_image_path.to_string());
-
-    let mut received_file = File::open(received_image_path).await?;
-    let mut received_bytes = Vec::new();
-    received_file.read_to_end(&mut received_bytes).await?;
-
-    let encoder = Encoder::new(&received_bytes, mask_image);
-    let encoded_image = encoder.encode_alpha();
-
-    save_image_buffer(encoded_image, encoded_image_path.to_string());
-    println!("Server: Encoded received image and saved as {}", encoded_image_path);
-
-    Ok(())
-}
 fn set_leader_status(is_leader: bool) {
     IS_LEADER.store(is_leader, Ordering::SeqCst);
 }
@@ -1208,6 +4277,13 @@ async fn send_json_files_to_peers(
             let mut clients_json = String::new();
             clients_file.read_to_string(&mut clients_json).await?;
             println!("Read clients.json successfully!");
+
+            // The audit log only exists once something was audited
+            let audit_json = tokio::fs::read_to_string(AUDIT_LOG_PATH).await.ok();
+            let groups_json = tokio::fs::read_to_string("groups.json").await.ok();
+            let albums_json = tokio::fs::read_to_string("albums.json").await.ok();
+            let deliveries_json = tokio::fs::read_to_string("deliveries.json").await.ok();
+            let revocations_json = tokio::fs::read_to_string("revocations.json").await.ok();
             
             // Iterate through peers and send JSON files
             for &addr in peers {
@@ -1241,6 +4317,81 @@ async fn send_json_files_to_peers(
                         }
                         stream.write_all(b"\n").await?;
 
+                        // Send audit.json, replicas merge it by appending unseen entries
+                        if let Some(audit_json) = &audit_json {
+                            let audit_message = json!({
+                                "file_name": AUDIT_LOG_PATH,
+                                "data": audit_json
+                            })
+                            .to_string();
+                            println!("Sending {} to {}...", AUDIT_LOG_PATH, addr);
+                            if let Err(err) = stream.write_all(audit_message.as_bytes()).await {
+                                eprintln!("Failed to send {} to {}: {}", AUDIT_LOG_PATH, addr, err);
+                                continue;
+                            }
+                            stream.write_all(b"\n").await?;
+                        }
+
+                        // Send groups.json, replicas replace their copy so removed members stay removed
+                        if let Some(groups_json) = &groups_json {
+                            let groups_message = json!({
+                                "file_name": "groups.json",
+                                "data": groups_json
+                            })
+                            .to_string();
+                            println!("Sending groups.json to {}...", addr);
+                            if let Err(err) = stream.write_all(groups_message.as_bytes()).await {
+                                eprintln!("Failed to send groups.json to {}: {}", addr, err);
+                                continue;
+                            }
+                            stream.write_all(b"\n").await?;
+                        }
+
+                        // Send albums.json, replaced wholesale like groups.json
+                        if let Some(albums_json) = &albums_json {
+                            let albums_message = json!({
+                                "file_name": "albums.json",
+                                "data": albums_json
+                            })
+                            .to_string();
+                            println!("Sending albums.json to {}...", addr);
+                            if let Err(err) = stream.write_all(albums_message.as_bytes()).await {
+                                eprintln!("Failed to send albums.json to {}: {}", addr, err);
+                                continue;
+                            }
+                            stream.write_all(b"\n").await?;
+                        }
+
+                        // Send deliveries.json, replaced wholesale so a new leader still has the stored images
+                        if let Some(deliveries_json) = &deliveries_json {
+                            let deliveries_message = json!({
+                                "file_name": "deliveries.json",
+                                "data": deliveries_json
+                            })
+                            .to_string();
+                            println!("Sending deliveries.json to {}...", addr);
+                            if let Err(err) = stream.write_all(deliveries_message.as_bytes()).await {
+                                eprintln!("Failed to send deliveries.json to {}: {}", addr, err);
+                                continue;
+                            }
+                            stream.write_all(b"\n").await?;
+                        }
+
+                        // Send revocations.json, replaced wholesale so wiped copies stay acknowledged
+                        if let Some(revocations_json) = &revocations_json {
+                            let revocations_message = json!({
+                                "file_name": "revocations.json",
+                                "data": revocations_json
+                            })
+                            .to_string();
+                            println!("Sending revocations.json to {}...", addr);
+                            if let Err(err) = stream.write_all(revocations_message.as_bytes()).await {
+                                eprintln!("Failed to send revocations.json to {}: {}", addr, err);
+                                continue;
+                            }
+                            stream.write_all(b"\n").await?;
+                        }
+
                         println!("JSON files sent to {}", addr);
                     }
                     Err(err) => {
@@ -1276,7 +4427,15 @@ pub async fn listen_and_save_json(address: &str) -> Result<(), Box<dyn Error + S
                             if let Some(data) = json.get("data").and_then(|v| v.as_str()) {
                                 println!("Received JSON for file: {}", file_name);
 
-                                if let Err(err) = append_json_to_file(file_name, data).await {
+                                // These are only ever changed on the leader, so its copy wins outright
+                                let result = match file_name {
+                                    "directory.json" | "groups.json" | "albums.json" | "deliveries.json" | "revocations.json" => {
+                                        save_json_to_file(file_name, data).await
+                                    }
+                                    AUDIT_LOG_PATH => merge_audit_log(data),
+                                    _ => append_json_to_file(file_name, data).await,
+                                };
+                                if let Err(err) = result {
                                     eprintln!("Failed to append JSON to {}: {}", file_name, err);
                                 }
                             } else {
@@ -1439,41 +4598,193 @@ async fn download_files(client: &Client) -> Result<(), Box<dyn Error>> {
     }
     Ok(())
 }
-async fn send_encoded_image(socket: Arc<UdpSocket>, dest: std::net::SocketAddr) -> tokio::io::Result<()> {
-    let encoded_image_path = "encrypted_image_1.png";
-    let mut file = File::open(encoded_image_path).await?;
-    let mut buffer = [0u8; CHUNK_SIZE];
-    let mut chunk_number: u32 = 0;
 
-    println!("Server: Sending encoded image to client...");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    while let Ok(bytes_read) = file.read(&mut buffer).await {
-        if bytes_read == 0 {
-            break;
+    fn groups_with(owner: &str, group: &str, members: &[&str]) -> GroupDirectory {
+        let mut groups = GroupDirectory::new();
+        groups.groups.entry(owner.to_string()).or_default().insert(
+            group.to_string(),
+            members.iter().map(|member| member.to_string()).collect(),
+        );
+        groups
+    }
+
+    fn albums_with(owner: &str, album_name: &str, image_name: &str, viewer: &str, views: u32) -> AlbumDirectory {
+        let mut albums = AlbumDirectory::new();
+        albums.albums.entry(owner.to_string()).or_default().insert(
+            album_name.to_string(),
+            Album {
+                images: vec![image_name.to_string()],
+                access_users: HashMap::from([(viewer.to_string(), views)]),
+                group_access: HashMap::new(),
+            },
+        );
+        albums
+    }
+
+    fn delegation(delegated_by: &str, views: u32) -> Delegation {
+        Delegation {
+            delegated_by: delegated_by.to_string(),
+            views,
+            delegated_at: String::new(),
         }
+    }
 
-        let packet = [&chunk_number.to_be_bytes(), &buffer[..bytes_read]].concat();
-        socket.send_to(&packet, dest).await?;
-        println!("Server: Sent chunk {}", chunk_number);
+    #[test]
+    fn resolve_access_prefers_direct_then_group_then_album() {
+        let groups = groups_with("owner", "friends", &["bob"]);
+        let albums = albums_with("owner", "holiday", "beach.png", "bob", 3);
+        let mut image_data = json!({
+            "name": "beach.png",
+            "access_users": { "bob": 2 },
+            "group_access": { "friends": 5 }
+        });
 
-        // Wait for acknowledgment
-        let mut ack_buf = [0; 3];
-        match timeout(Duration::from_secs(5), socket.recv_from(&mut ack_buf)).await {
-            Ok(Ok((_, _))) if &ack_buf == ACK => {
-                println!("Server: Received ACK for chunk {}", chunk_number);
-            }
-            _ => {
-                println!("Server: No ACK received for chunk {}, retrying...", chunk_number);
-                continue; // Retry sending this chunk
-            }
+        let access = resolve_access(&image_data, "owner", "bob", &groups, &albums);
+        assert_eq!(access, Some((2, GrantSource::Direct)));
+
+        image_data["access_users"]["bob"] = json!(0);
+        let access = resolve_access(&image_data, "owner", "bob", &groups, &albums);
+        assert_eq!(access, Some((5, GrantSource::Group("friends".to_string()))));
+
+        image_data["group_views"]["friends"]["bob"] = json!(5);
+        let access = resolve_access(&image_data, "owner", "bob", &groups, &albums);
+        assert_eq!(access, Some((3, GrantSource::Album("holiday".to_string()))));
+
+        image_data["album_views"]["holiday"]["bob"] = json!(3);
+        assert_eq!(resolve_access(&image_data, "owner", "bob", &groups, &albums), None);
+        assert_eq!(resolve_access(&image_data, "owner", "carol", &groups, &albums), None);
+    }
+
+    #[test]
+    fn consume_views_charges_the_source_it_is_given() {
+        let mut image_data = json!({
+            "name": "beach.png",
+            "access_users": { "bob": 4 },
+            "group_access": { "friends": 5 }
+        });
+
+        consume_views(&mut image_data, "bob", &GrantSource::Direct, 3);
+        assert_eq!(image_data["access_users"]["bob"], json!(1));
+
+        consume_views(&mut image_data, "bob", &GrantSource::Group("friends".to_string()), 2);
+        assert_eq!(image_data["group_views"]["friends"]["bob"], json!(2));
+        assert_eq!(image_data["access_users"]["bob"], json!(1));
+
+        consume_views(&mut image_data, "bob", &GrantSource::Album("holiday".to_string()), 1);
+        assert_eq!(image_data["album_views"]["holiday"]["bob"], json!(1));
+        assert!(image_data["group_views"].get("holiday").is_none());
+
+        // Never below zero, whatever the caller asks for
+        consume_views(&mut image_data, "bob", &GrantSource::Direct, 10);
+        assert_eq!(image_data["access_users"]["bob"], json!(0));
+    }
+
+    #[test]
+    fn refund_views_gives_back_what_consume_views_took() {
+        let mut image_data = json!({
+            "name": "beach.png",
+            "access_users": { "bob": 4 },
+            "group_access": { "friends": 5 }
+        });
+        let group = GrantSource::Group("friends".to_string());
+
+        consume_views(&mut image_data, "bob", &GrantSource::Direct, 1);
+        refund_views(&mut image_data, "bob", &GrantSource::Direct, 1);
+        assert_eq!(image_data["access_users"]["bob"], json!(4));
+
+        consume_views(&mut image_data, "bob", &group, 2);
+        refund_views(&mut image_data, "bob", &group, 1);
+        assert_eq!(image_data["group_views"]["friends"]["bob"], json!(1));
+
+        // A grant removed since the view was handed out stays removed
+        refund_views(&mut image_data, "carol", &GrantSource::Direct, 1);
+        refund_views(&mut image_data, "carol", &GrantSource::Album("holiday".to_string()), 1);
+        assert!(image_data["access_users"].get("carol").is_none());
+        assert!(image_data.get("album_views").is_none());
+    }
+
+    #[test]
+    fn access_window_boundaries_are_inclusive_of_now() {
+        let window = AccessWindow { not_before: Some(100), expires_at: Some(200) };
+
+        assert!(!window.is_open(99));
+        assert!(window.is_open(100));
+        assert!(window.is_open(199));
+        assert!(!window.is_expired(199));
+        assert!(window.is_expired(200));
+        assert!(!window.is_open(200));
+
+        let unlimited = AccessWindow::default();
+        assert!(unlimited.is_open(0));
+        assert!(!unlimited.is_expired(u64::MAX));
+    }
+
+    #[test]
+    fn expire_grants_removes_only_expired_viewers() {
+        let mut directory = Directory::new();
+        directory.clients.insert(
+            "owner".to_string(),
+            vec![json!({
+                "name": "beach.png",
+                "access_users": { "bob": 2, "carol": 3, "dave": 1 },
+                "access_windows": {
+                    "bob": { "expires_at": 100 },
+                    "carol": { "expires_at": 101 },
+                    "dave": { "not_before": 50 }
+                },
+                "view_shares": { "bob": { "req-1": [] }, "carol": { "req-2": [] } },
+                "view_grants": { "bob": { "req-1": {} } }
+            })
+            .to_string()],
+        );
+
+        let expired = expire_grants(&mut directory, 100);
+        assert_eq!(expired, vec![("owner".to_string(), "beach.png".to_string(), "bob".to_string())]);
+
+        let image_data: serde_json::Value = serde_json::from_str(&directory.clients["owner"][0]).unwrap();
+        for field in ["access_users", "access_windows", "view_shares", "view_grants"] {
+            assert!(image_data[field].get("bob").is_none(), "{} still has bob", field);
         }
+        assert_eq!(image_data["access_users"]["carol"], json!(3));
+        assert_eq!(image_data["access_users"]["dave"], json!(1));
+        assert!(image_data["view_shares"].get("carol").is_some());
 
-        chunk_number += 1;
+        assert!(expire_grants(&mut directory, 100).is_empty());
     }
 
-    // Send end-of-transfer signal
-    socket.send_to(b"END", dest).await?;
-    println!("Server: Encoded image transfer complete.");
+    #[test]
+    fn reshare_budget_counts_every_delegation_of_the_delegator() {
+        let delegations = HashMap::from([
+            ("carol".to_string(), delegation("bob", 3)),
+            ("dave".to_string(), delegation("bob", 2)),
+            ("erin".to_string(), delegation("carol", 4)),
+        ]);
+
+        assert_eq!(check_reshare_budget(&delegations, "bob", 5, 10), Ok(()));
+        assert_eq!(check_reshare_budget(&delegations, "bob", 6, 10), Err(5));
+        assert_eq!(check_reshare_budget(&delegations, "carol", 6, 10), Ok(()));
+        assert_eq!(check_reshare_budget(&delegations, "frank", 10, 10), Ok(()));
+        assert_eq!(check_reshare_budget(&delegations, "bob", u32::MAX, u32::MAX), Err(5));
+    }
 
-    Ok(())
+    #[test]
+    fn delegation_subtree_follows_multi_level_chains() {
+        let image_data = json!({
+            "delegations": {
+                "carol": delegation("bob", 3),
+                "dave": delegation("carol", 2),
+                "erin": delegation("dave", 1),
+                "frank": delegation("alice", 1)
+            }
+        });
+
+        let mut subtree = delegation_subtree(&image_data, "bob");
+        subtree.sort();
+        assert_eq!(subtree, vec!["bob", "carol", "dave", "erin"]);
+        assert_eq!(delegation_subtree(&image_data, "erin"), vec!["erin"]);
+    }
 }